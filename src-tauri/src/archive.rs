@@ -0,0 +1,157 @@
+use std::{io, path::{Path, PathBuf}, time::{Duration, SystemTime}};
+
+use tokio::fs;
+use tracing::{debug, info, warn};
+
+use crate::{
+	backend::RemoteBackend,
+	error::{add_error, SyncError},
+	etag::md5_file,
+	history::record_file,
+	structs::{ArchiveAction, ArchiveConfig, FileAction, SyncDirection, SyncTask, TaskData}
+};
+
+const GIB: u64 = 1024 * 1024 * 1024;
+
+/// Removes the uploaded files of an archiving task from the local disk.
+#[derive(Clone)]
+pub struct Archiver {
+	config: ArchiveConfig,
+	root: PathBuf
+}
+
+impl Archiver {
+	/// `None` unless the task archives, which only upload tasks do.
+	pub fn new(task: &TaskData) -> Option<Self> {
+		if !task.archive.enabled || task.direction != SyncDirection::Upload {
+			return None;
+		}
+		Some(Self {
+			config: task.archive.clone(),
+			root: PathBuf::from(&task.localDir)
+		})
+	}
+
+	/// Whether the thresholds of the task let the file go now.
+	fn due(&self, path: &Path) -> bool {
+		if self.config.minAgeHours > 0 {
+			let min_age = Duration::from_secs(self.config.minAgeHours * 3600);
+			let modified = std::fs::metadata(path).and_then(|m| m.modified());
+			match modified.map(|m| SystemTime::now().duration_since(m)) {
+				Ok(Ok(age)) if age >= min_age => {},
+				_ => return false
+			}
+		}
+		// archived rather than filling the disk when the free space is unknown
+		if self.config.evictBelowFreeGb > 0
+			&& free_space(&self.root).is_some_and(|free| free >= self.config.evictBelowFreeGb * GIB) {
+			return false;
+		}
+		true
+	}
+
+	/// Archive a file the server has, once the server copy is checked to hold
+	/// exactly what is on disk. Failures leave the file in place.
+	pub async fn archive(&self, uuid: &str, backend: &dyn RemoteBackend, sync_task: &SyncTask) {
+		if !self.due(&sync_task.from) {
+			debug!("Keep {:?} until the archive thresholds are met", &sync_task.from);
+			return;
+		}
+
+		// hashed again, the file may have been written to since it was uploaded
+		let local = match md5_file(&sync_task.from).await {
+			Ok(digest) => hex::encode(digest),
+			Err(e) => {
+				warn!("Not archiving {:?}: {}", &sync_task.from, e);
+				return;
+			}
+		};
+		match backend.stat(&sync_task.to).await {
+			Ok(Some(remote)) if !remote.etag.is_empty() && remote.etag == local => {},
+			Ok(_) => {
+				warn!("Not archiving {:?}, the server copy does not match it", &sync_task.from);
+				return;
+			},
+			Err(e) => {
+				warn!("Not archiving {:?}: {}", &sync_task.from, e);
+				return;
+			}
+		}
+
+		let res = match self.config.action {
+			ArchiveAction::Delete => fs::remove_file(&sync_task.from).await,
+			ArchiveAction::Trash => self.move_to_trash(&sync_task.from).await
+		};
+		match res {
+			Ok(_) => {
+				info!("Archived {:?}", &sync_task.from);
+				record_file(uuid, sync_task, FileAction::Archived, 0, None);
+			},
+			Err(e) => {
+				let action = match self.config.action {
+					ArchiveAction::Delete => "delete file",
+					ArchiveAction::Trash => "move file to the trash"
+				};
+				let err = SyncError::io(action, e).task(uuid).file(sync_task);
+				warn!("Failed archiving {:?}: {}", &sync_task.from, &err);
+				add_error(err);
+			}
+		}
+	}
+
+	async fn move_to_trash(&self, path: &Path) -> io::Result<()> {
+		if self.config.trashDir.is_empty() {
+			return Err(io::Error::new(io::ErrorKind::NotFound, "No trash folder is set for the task"));
+		}
+		let relative = path.strip_prefix(&self.root).unwrap_or(path);
+		let target = free_name(Path::new(&self.config.trashDir).join(relative));
+		if let Some(parent) = target.parent() {
+			fs::create_dir_all(parent).await?;
+		}
+		// the trash may be on another disk
+		if fs::rename(path, &target).await.is_err() {
+			fs::copy(path, &target).await?;
+			fs::remove_file(path).await?;
+		}
+		Ok(())
+	}
+}
+
+/// `path`, or the first of `name (1).ext`, `name (2).ext`, … not taken yet.
+fn free_name(path: PathBuf) -> PathBuf {
+	if !path.exists() {
+		return path;
+	}
+	let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+	let ext = path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+	(1..)
+		.map(|i| path.with_file_name(format!("{} ({}){}", stem, i, ext)))
+		.find(|p| !p.exists())
+		.unwrap()
+}
+
+/// Bytes available to this user on the disk holding `path`.
+#[cfg(unix)]
+// the field types differ between platforms
+#[allow(clippy::unnecessary_cast)]
+fn free_space(path: &Path) -> Option<u64> {
+	use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+	let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+	let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+	if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+		return None;
+	}
+	Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(windows)]
+fn free_space(path: &Path) -> Option<u64> {
+	use std::os::windows::ffi::OsStrExt;
+	use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+	let path: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+	let mut free = 0u64;
+	let ok = unsafe { GetDiskFreeSpaceExW(path.as_ptr(), &mut free, std::ptr::null_mut(), std::ptr::null_mut()) };
+	if ok == 0 { None } else { Some(free) }
+}
@@ -0,0 +1,113 @@
+use std::collections::{HashMap, HashSet};
+
+use async_trait::async_trait;
+use aws_sdk_s3::primitives::ByteStream;
+use serde_json::json;
+use tracing::info;
+
+use crate::{error::{ErrorKind, SyncError}, net::{post, s3_client}, structs::{parse_json, BulkMetaData}};
+
+use super::{RemoteBackend, RemoteFile, RemoteObject, RemoteStat, S3Backend};
+
+/// Cells exposes all workspaces as this bucket of its S3 gateway.
+const BUCKET_NAME: &str = "io";
+/// Cells keeps this hidden file in every folder, so empty folders still show up in S3 listings.
+const FOLDER_MARKER: &str = ".pydio";
+/// Paths per request when checking which remote folders exist.
+const FOLDER_BATCH_SIZE: usize = 100;
+
+/// The Cells server of the current session. Files go through its S3
+/// gateway, folders through the REST API.
+pub struct CellsBackend;
+
+impl CellsBackend {
+	// looked up on every call, the session may have been refreshed in between
+	fn s3(&self) -> S3Backend {
+		S3Backend::new(s3_client(), BUCKET_NAME)
+	}
+}
+
+#[async_trait]
+impl RemoteBackend for CellsBackend {
+	/// Folder markers are listed as the folder they stand for.
+	async fn list(&self, prefix: &str) -> Result<Vec<RemoteFile>, SyncError> {
+		let files = self.s3().list(prefix).await?;
+		Ok(files
+			.into_iter()
+			.map(|f| match f.key.strip_suffix(FOLDER_MARKER) {
+				Some(folder) if folder.is_empty() || folder.ends_with('/') => RemoteFile { key: folder.to_string(), size: 0 },
+				_ => f
+			})
+			.collect())
+	}
+
+	async fn stat(&self, key: &str) -> Result<Option<RemoteStat>, SyncError> {
+		self.s3().stat(key).await
+	}
+
+	async fn put(
+		&self, key: &str, body: ByteStream, content_md5: Option<String>, metadata: HashMap<String, String>
+	) -> Result<(), SyncError> {
+		self.s3().put(key, body, content_md5, metadata).await
+	}
+
+	async fn get(&self, key: &str) -> Result<RemoteObject, SyncError> {
+		self.s3().get(key).await
+	}
+
+	async fn delete(&self, key: &str) -> Result<(), SyncError> {
+		self.s3().delete(key).await
+	}
+
+	async fn rename(&self, from: &str, to: &str) -> Result<(), SyncError> {
+		self.s3().rename(from, to).await
+	}
+
+	async fn copy(&self, from: &str, to: &str) -> Result<(), SyncError> {
+		self.s3().copy(from, to).await
+	}
+
+	/// Folders that already exist are left alone, the rest are created as
+	/// folder nodes.
+	async fn create_folders(&self, paths: &[String]) -> Result<(), SyncError> {
+		let mut missing = vec![];
+		for batch in paths.chunks(FOLDER_BATCH_SIZE) {
+			let mut res = post(
+				"/a/meta/bulk/get",
+				json!({
+					"NodePaths": batch
+				}).to_string()
+			).await.map_err(|e| SyncError::new(ErrorKind::Network, e))?;
+			let t = res.body_string().await.map_err(|e| SyncError::new(ErrorKind::Network, e))?;
+			let node_data: BulkMetaData = parse_json(&t);
+
+			let existing: HashSet<String> = node_data.Nodes
+				.iter()
+				.map(|n| n.Path.trim_matches('/').to_string())
+				.collect();
+			missing.extend(batch.iter().filter(|p| !existing.contains(p.trim_matches('/'))).cloned());
+		}
+		if missing.is_empty() {
+			return Ok(());
+		}
+
+		info!("Create {} remote folder(s)", missing.len());
+		let nodes: Vec<_> = missing
+			.iter()
+			.map(|p| json!({ "Path": p, "Type": "COLLECTION" }))
+			.collect();
+		let res = post(
+			"/a/tree/create",
+			json!({
+				"Nodes": nodes,
+				"Recursive": true
+			}).to_string()
+		).await.map_err(|e| SyncError::new(ErrorKind::Network, e))?;
+
+		if res.status().is_success() {
+			Ok(())
+		} else {
+			Err(SyncError::new(ErrorKind::RemoteRejected, format!("Creating folders failed with status {}", res.status())))
+		}
+	}
+}
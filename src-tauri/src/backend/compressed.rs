@@ -0,0 +1,230 @@
+use std::{collections::HashMap, io::{self, Write}, sync::Arc};
+
+use async_trait::async_trait;
+use aws_sdk_s3::primitives::ByteStream;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use md5::{Digest, Md5};
+use tokio::io::AsyncWriteExt;
+use tracing::warn;
+use zstd::stream::write::{Decoder, Encoder};
+
+use crate::{
+	error::{ErrorKind, SyncError},
+	etag::content_md5,
+	state::{get_compressed_etag, set_compressed_etag, CompressedEtag}
+};
+
+use super::{spool, spooled_body, RemoteBackend, RemoteFile, RemoteObject, RemoteStat};
+
+/// Metadata marking a compressed object, downloads decompress what carries it.
+const COMPRESSION_KEY: &str = "compression";
+const ZSTD: &str = "zstd";
+/// Hex MD5 and size of the content before compression, unless the task keeps
+/// them in its local state.
+const ORIGINAL_MD5_KEY: &str = "original-md5";
+const ORIGINAL_SIZE_KEY: &str = "original-size";
+const LEVEL: i32 = 3;
+
+/// Stores files whose name matches one of the task's patterns zstd
+/// compressed. Stat reports the size and MD5 of the original content, so a
+/// compressed file is skipped like any other when it did not change.
+pub struct CompressedBackend {
+	inner: Arc<dyn RemoteBackend>,
+	patterns: GlobSet,
+	/// Task whose state keeps the originals, rather than the object metadata
+	state_uuid: Option<String>
+}
+
+impl CompressedBackend {
+	pub fn new(inner: Arc<dyn RemoteBackend>, patterns: &[String], state_uuid: Option<&str>) -> Self {
+		let mut builder = GlobSetBuilder::new();
+		for pattern in patterns {
+			match GlobBuilder::new(pattern).case_insensitive(true).build() {
+				Ok(glob) => {
+					builder.add(glob);
+				},
+				Err(e) => warn!("Ignore compression pattern {}: {}", pattern, e)
+			}
+		}
+		Self {
+			inner,
+			patterns: builder.build().unwrap_or_else(|_| GlobSet::empty()),
+			state_uuid: state_uuid.map(|u| u.to_string())
+		}
+	}
+
+	/// MD5 and size of the original of a compressed object, from its metadata
+	/// or from the local state while the object is the one last seen.
+	fn original(&self, key: &str, stat: &RemoteStat) -> Option<(String, u64)> {
+		let from_metadata = stat.metadata.get(ORIGINAL_MD5_KEY).zip(stat.metadata.get(ORIGINAL_SIZE_KEY));
+		if let Some((md5, size)) = from_metadata {
+			return Some((md5.clone(), size.parse().ok()?));
+		}
+		let known = get_compressed_etag(self.state_uuid.as_ref()?, key)?;
+		(known.stored == stat.etag).then_some((known.original, known.size))
+	}
+
+	fn remember(&self, key: &str, etag: Option<CompressedEtag>) {
+		if let Some(uuid) = &self.state_uuid {
+			set_compressed_etag(uuid, key, etag);
+		}
+	}
+
+	/// Patterns match the file name, not the folders above it.
+	fn compresses(&self, key: &str) -> bool {
+		let name = key.rsplit('/').next().unwrap_or(key);
+		!key.ends_with('/') && self.patterns.is_match(name)
+	}
+}
+
+fn is_compressed(metadata: &HashMap<String, String>) -> bool {
+	metadata.get(COMPRESSION_KEY).is_some_and(|c| c == ZSTD)
+}
+
+/// Run a step of `coder` on a blocking thread, (de)compressing is CPU bound.
+async fn blocking<C, F>(mut coder: C, step: F) -> io::Result<(C, Vec<u8>)>
+where C: Send + 'static, F: FnOnce(&mut C) -> io::Result<Vec<u8>> + Send + 'static {
+	tokio::task::spawn_blocking(move || {
+		let out = step(&mut coder)?;
+		Ok((coder, out))
+	}).await.unwrap_or_else(|e| Err(io::Error::other(e)))
+}
+
+#[async_trait]
+impl RemoteBackend for CompressedBackend {
+	/// Sizes are the stored ones, the original size is only known to `stat`.
+	async fn list(&self, prefix: &str) -> Result<Vec<RemoteFile>, SyncError> {
+		self.inner.list(prefix).await
+	}
+
+	async fn stat(&self, key: &str) -> Result<Option<RemoteStat>, SyncError> {
+		let stat = match self.inner.stat(key).await? {
+			Some(s) => s,
+			None => return Ok(None)
+		};
+		if !is_compressed(&stat.metadata) {
+			return Ok(Some(stat));
+		}
+		match self.original(key, &stat) {
+			Some((etag, size)) => Ok(Some(RemoteStat { size, etag, ..stat })),
+			None => Ok(Some(stat))
+		}
+	}
+
+	/// Matching files are compressed to a temporary file first, the digest
+	/// the caller passes is the one of the original content and is not used
+	/// for them.
+	async fn put(
+		&self, key: &str, mut body: ByteStream, digest: Option<String>, mut metadata: HashMap<String, String>
+	) -> Result<(), SyncError> {
+		if !self.compresses(key) {
+			return self.inner.put(key, body, digest, metadata).await;
+		}
+
+		let mut file = spool()?;
+		let mut encoder = Encoder::new(Vec::new(), LEVEL).map_err(|e| SyncError::io("compress file", e))?;
+		let (mut original_md5, mut stored_md5) = (Md5::new(), Md5::new());
+		let mut original_size = 0u64;
+		while let Some(chunk) = body.try_next().await.map_err(|e| SyncError::io("read file", e))? {
+			original_md5.update(&chunk);
+			original_size += chunk.len() as u64;
+			let (e, compressed) = blocking(encoder, move |e| {
+				e.write_all(&chunk)?;
+				Ok(std::mem::take(e.get_mut()))
+			}).await.map_err(|e| SyncError::io("compress file", e))?;
+			encoder = e;
+			stored_md5.update(&compressed);
+			file.write_all(&compressed).await.map_err(|e| SyncError::io("write temporary file", e))?;
+		}
+		let (_, compressed) = blocking(encoder, |e| e.do_finish().map(|_| std::mem::take(e.get_mut())))
+			.await
+			.map_err(|e| SyncError::io("compress file", e))?;
+		stored_md5.update(&compressed);
+		file.write_all(&compressed).await.map_err(|e| SyncError::io("write temporary file", e))?;
+
+		let (original_md5, stored_md5) = (original_md5.finalize(), stored_md5.finalize());
+		metadata.insert(COMPRESSION_KEY.to_string(), ZSTD.to_string());
+		if self.state_uuid.is_none() {
+			metadata.insert(ORIGINAL_MD5_KEY.to_string(), hex::encode(original_md5));
+			metadata.insert(ORIGINAL_SIZE_KEY.to_string(), original_size.to_string());
+		}
+		self.inner.put(key, spooled_body(file).await?, Some(content_md5(&stored_md5)), metadata).await?;
+		self.remember(key, Some(CompressedEtag {
+			stored: hex::encode(stored_md5),
+			original: hex::encode(original_md5),
+			size: original_size
+		}));
+		Ok(())
+	}
+
+	/// Compressed objects are decompressed to a temporary file, whatever the
+	/// current patterns are.
+	async fn get(&self, key: &str) -> Result<RemoteObject, SyncError> {
+		let object = self.inner.get(key).await?;
+		if !is_compressed(&object.metadata) {
+			return Ok(object);
+		}
+		let mut body = object.body;
+
+		let mut file = spool()?;
+		let mut decoder = Decoder::new(Vec::new()).map_err(|e| SyncError::io("decompress file", e))?;
+		let (mut original_md5, mut stored_md5) = (Md5::new(), Md5::new());
+		let mut original_size = 0u64;
+		while let Some(chunk) = body.try_next().await.map_err(|e| SyncError::new(ErrorKind::Network, e))? {
+			stored_md5.update(&chunk);
+			let (d, plain) = blocking(decoder, move |d| {
+				d.write_all(&chunk)?;
+				Ok(std::mem::take(d.get_mut()))
+			}).await.map_err(|e| SyncError::io("decompress file", e))?;
+			decoder = d;
+			original_md5.update(&plain);
+			original_size += plain.len() as u64;
+			file.write_all(&plain).await.map_err(|e| SyncError::io("write temporary file", e))?;
+		}
+		let (_, plain) = blocking(decoder, |d| d.flush().map(|_| std::mem::take(d.get_mut())))
+			.await
+			.map_err(|e| SyncError::io("decompress file", e))?;
+		original_md5.update(&plain);
+		original_size += plain.len() as u64;
+		file.write_all(&plain).await.map_err(|e| SyncError::io("write temporary file", e))?;
+
+		self.remember(key, Some(CompressedEtag {
+			stored: hex::encode(stored_md5.finalize()),
+			original: hex::encode(original_md5.finalize()),
+			size: original_size
+		}));
+
+		Ok(RemoteObject {
+			body: spooled_body(file).await?,
+			metadata: object.metadata
+		})
+	}
+
+	async fn delete(&self, key: &str) -> Result<(), SyncError> {
+		self.inner.delete(key).await?;
+		self.remember(key, None);
+		Ok(())
+	}
+
+	async fn rename(&self, from: &str, to: &str) -> Result<(), SyncError> {
+		self.inner.rename(from, to).await?;
+		if let Some(uuid) = &self.state_uuid {
+			let etag = get_compressed_etag(uuid, from);
+			set_compressed_etag(uuid, from, None);
+			set_compressed_etag(uuid, to, etag);
+		}
+		Ok(())
+	}
+
+	async fn copy(&self, from: &str, to: &str) -> Result<(), SyncError> {
+		self.inner.copy(from, to).await?;
+		if let Some(uuid) = &self.state_uuid {
+			set_compressed_etag(uuid, to, get_compressed_etag(uuid, from));
+		}
+		Ok(())
+	}
+
+	async fn create_folders(&self, paths: &[String]) -> Result<(), SyncError> {
+		self.inner.create_folders(paths).await
+	}
+}
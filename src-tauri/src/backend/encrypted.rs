@@ -0,0 +1,271 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use aws_sdk_s3::primitives::ByteStream;
+use md5::{Digest, Md5};
+use tokio::{io::AsyncWriteExt, sync::OnceCell};
+use tracing::{info, warn};
+
+use crate::{
+	crypto::{plaintext_size, random_bytes, CryptoError, TaskKey},
+	error::{ErrorKind, SyncError},
+	etag::content_md5,
+	state::{get_cipher_etag, set_cipher_etag, CipherEtag}
+};
+
+use super::{spool, spooled_body, RemoteBackend, RemoteFile, RemoteObject, RemoteStat};
+
+/// Salt of the task key and a check of the passphrase, kept in the task folder.
+const KEY_FILE_NAME: &str = ".cells-sync-key";
+const KEY_SALT_LEN: usize = 16;
+
+/// Encrypts the files of one task before they reach the storage below it.
+/// With `names`, keys below `root` are encrypted one name at a time, so the
+/// folders stay browsable. Object metadata, such as modification times, is
+/// stored unencrypted.
+pub struct EncryptedBackend {
+	inner: Arc<dyn RemoteBackend>,
+	uuid: String,
+	root: String,
+	names: bool,
+	passphrase: String,
+	key: OnceCell<TaskKey>
+}
+
+fn crypto_error(e: CryptoError) -> SyncError {
+	SyncError::new(ErrorKind::Encryption, e)
+}
+
+impl EncryptedBackend {
+	pub fn new(inner: Arc<dyn RemoteBackend>, uuid: &str, root: &str, names: bool, passphrase: String) -> Self {
+		Self {
+			inner,
+			uuid: uuid.to_string(),
+			root: root.trim_end_matches('/').to_string(),
+			names,
+			passphrase,
+			key: OnceCell::new()
+		}
+	}
+
+	fn key_file(&self) -> String {
+		format!("{}/{}", &self.root, KEY_FILE_NAME)
+	}
+
+	async fn key(&self) -> Result<&TaskKey, SyncError> {
+		self.key.get_or_try_init(|| self.load_key()).await
+	}
+
+	/// Read the salt of an encrypted folder, or set one up for a new one.
+	async fn load_key(&self) -> Result<TaskKey, SyncError> {
+		if self.passphrase.is_empty() {
+			return Err(SyncError::new(ErrorKind::Encryption, "No encryption passphrase is set for this task"));
+		}
+
+		let stored = match self.inner.stat(&self.key_file()).await? {
+			Some(_) => Some(read_all(self.inner.get(&self.key_file()).await?.body).await?),
+			None => None
+		};
+		let salt = match &stored {
+			Some(content) if content.len() > KEY_SALT_LEN => content[..KEY_SALT_LEN].to_vec(),
+			Some(_) => return Err(SyncError::new(ErrorKind::Encryption, "The key file of the folder is damaged")),
+			None => random_bytes(KEY_SALT_LEN)
+		};
+
+		let passphrase = self.passphrase.clone();
+		let key_salt = salt.clone();
+		let key = tokio::task::spawn_blocking(move || TaskKey::derive(&passphrase, &key_salt))
+			.await
+			.map_err(|e| SyncError::new(ErrorKind::Unknown, e))?;
+
+		match stored {
+			Some(content) => {
+				if !key.verify(&content[KEY_SALT_LEN..]) {
+					return Err(SyncError::new(ErrorKind::Encryption, "The passphrase does not match the one the folder was encrypted with"));
+				}
+			},
+			None => {
+				info!("Set up encryption in {}", &self.root);
+				let mut content = salt;
+				content.extend(key.check());
+				self.inner.put(&self.key_file(), ByteStream::from(content), None, HashMap::new()).await?;
+			}
+		}
+		Ok(key)
+	}
+
+	fn remote_key(&self, key: &TaskKey, plain: &str) -> String {
+		if !self.names {
+			return plain.to_string();
+		}
+		match plain.strip_prefix(&format!("{}/", &self.root)) {
+			Some(rest) => {
+				let names: Vec<String> = rest
+					.split('/')
+					.map(|n| if n.is_empty() { String::new() } else { key.encrypt_name(n) })
+					.collect();
+				format!("{}/{}", &self.root, names.join("/"))
+			},
+			None => plain.to_string()
+		}
+	}
+
+	fn plain_key(&self, key: &TaskKey, remote: &str) -> Result<String, CryptoError> {
+		if !self.names {
+			return Ok(remote.to_string());
+		}
+		match remote.strip_prefix(&format!("{}/", &self.root)) {
+			Some(rest) => {
+				let names = rest
+					.split('/')
+					.map(|n| if n.is_empty() { Ok(String::new()) } else { key.decrypt_name(n) })
+					.collect::<Result<Vec<String>, CryptoError>>()?;
+				Ok(format!("{}/{}", &self.root, names.join("/")))
+			},
+			None => Ok(remote.to_string())
+		}
+	}
+}
+
+async fn read_all(mut body: ByteStream) -> Result<Vec<u8>, SyncError> {
+	let mut content = vec![];
+	while let Some(chunk) = body.try_next().await.map_err(|e| SyncError::new(ErrorKind::Network, e))? {
+		content.extend_from_slice(&chunk);
+	}
+	Ok(content)
+}
+
+#[async_trait]
+impl RemoteBackend for EncryptedBackend {
+	async fn list(&self, prefix: &str) -> Result<Vec<RemoteFile>, SyncError> {
+		let key = self.key().await?;
+		let key_file = self.key_file();
+		let files = self.inner.list(&self.remote_key(key, prefix)).await?;
+		Ok(files
+			.into_iter()
+			.filter(|f| f.key != key_file)
+			.filter_map(|f| match self.plain_key(key, &f.key) {
+				Ok(plain) => Some(RemoteFile {
+					size: if plain.ends_with('/') { 0 } else { plaintext_size(f.size) },
+					key: plain
+				}),
+				Err(e) => {
+					warn!("Skip {}: {}", &f.key, e);
+					None
+				}
+			})
+			.collect())
+	}
+
+	/// Reports the plaintext size, and the plaintext ETag when the object is
+	/// still the one this device last uploaded or downloaded.
+	async fn stat(&self, key: &str) -> Result<Option<RemoteStat>, SyncError> {
+		let task_key = self.key().await?;
+		let stat = match self.inner.stat(&self.remote_key(task_key, key)).await? {
+			Some(s) => s,
+			None => return Ok(None)
+		};
+		let etag = match get_cipher_etag(&self.uuid, key) {
+			Some(known) if known.ciphertext == stat.etag => known.plaintext,
+			_ => stat.etag
+		};
+		Ok(Some(RemoteStat {
+			size: plaintext_size(stat.size),
+			etag,
+			..stat
+		}))
+	}
+
+	/// The content is encrypted to a temporary file first, the digest the
+	/// caller passes is the one of the plaintext and is not used.
+	async fn put(
+		&self, key: &str, mut body: ByteStream, _content_md5: Option<String>, metadata: HashMap<String, String>
+	) -> Result<(), SyncError> {
+		let task_key = self.key().await?;
+		let mut file = spool()?;
+		let mut encryptor = task_key.encryptor();
+		let (mut plain_md5, mut cipher_md5) = (Md5::new(), Md5::new());
+		while let Some(chunk) = body.try_next().await.map_err(|e| SyncError::io("read file", e))? {
+			plain_md5.update(&chunk);
+			let sealed = encryptor.update(&chunk);
+			cipher_md5.update(&sealed);
+			file.write_all(&sealed).await.map_err(|e| SyncError::io("write temporary file", e))?;
+		}
+		let sealed = encryptor.finish();
+		cipher_md5.update(&sealed);
+		file.write_all(&sealed).await.map_err(|e| SyncError::io("write temporary file", e))?;
+
+		let cipher_md5 = cipher_md5.finalize().to_vec();
+		self.inner.put(
+			&self.remote_key(task_key, key),
+			spooled_body(file).await?,
+			Some(content_md5(&cipher_md5)),
+			metadata
+		).await?;
+
+		set_cipher_etag(&self.uuid, key, Some(CipherEtag {
+			ciphertext: hex::encode(cipher_md5),
+			plaintext: hex::encode(plain_md5.finalize())
+		}));
+		Ok(())
+	}
+
+	/// The content is decrypted to a temporary file, so nothing is written
+	/// to the destination before the whole file checked out.
+	async fn get(&self, key: &str) -> Result<RemoteObject, SyncError> {
+		let task_key = self.key().await?;
+		let object = self.inner.get(&self.remote_key(task_key, key)).await?;
+		let mut body = object.body;
+
+		let mut file = spool()?;
+		let mut decryptor = task_key.decryptor();
+		let (mut plain_md5, mut cipher_md5) = (Md5::new(), Md5::new());
+		while let Some(chunk) = body.try_next().await.map_err(|e| SyncError::new(ErrorKind::Network, e))? {
+			cipher_md5.update(&chunk);
+			let plain = decryptor.update(&chunk).map_err(crypto_error)?;
+			plain_md5.update(&plain);
+			file.write_all(&plain).await.map_err(|e| SyncError::io("write temporary file", e))?;
+		}
+		let plain = decryptor.finish().map_err(crypto_error)?;
+		plain_md5.update(&plain);
+		file.write_all(&plain).await.map_err(|e| SyncError::io("write temporary file", e))?;
+
+		set_cipher_etag(&self.uuid, key, Some(CipherEtag {
+			ciphertext: hex::encode(cipher_md5.finalize()),
+			plaintext: hex::encode(plain_md5.finalize())
+		}));
+		Ok(RemoteObject {
+			body: spooled_body(file).await?,
+			metadata: object.metadata
+		})
+	}
+
+	async fn delete(&self, key: &str) -> Result<(), SyncError> {
+		let task_key = self.key().await?;
+		self.inner.delete(&self.remote_key(task_key, key)).await?;
+		set_cipher_etag(&self.uuid, key, None);
+		Ok(())
+	}
+
+	async fn rename(&self, from: &str, to: &str) -> Result<(), SyncError> {
+		let task_key = self.key().await?;
+		self.inner.rename(&self.remote_key(task_key, from), &self.remote_key(task_key, to)).await?;
+		let etag = get_cipher_etag(&self.uuid, from);
+		set_cipher_etag(&self.uuid, from, None);
+		set_cipher_etag(&self.uuid, to, etag);
+		Ok(())
+	}
+
+	async fn copy(&self, from: &str, to: &str) -> Result<(), SyncError> {
+		let task_key = self.key().await?;
+		self.inner.copy(&self.remote_key(task_key, from), &self.remote_key(task_key, to)).await?;
+		set_cipher_etag(&self.uuid, to, get_cipher_etag(&self.uuid, from));
+		Ok(())
+	}
+
+	async fn create_folders(&self, paths: &[String]) -> Result<(), SyncError> {
+		let task_key = self.key().await?;
+		let paths: Vec<String> = paths.iter().map(|p| self.remote_key(task_key, p)).collect();
+		self.inner.create_folders(&paths).await
+	}
+}
@@ -0,0 +1,200 @@
+use std::{collections::HashMap, io::ErrorKind as IoErrorKind, path::PathBuf};
+
+use async_trait::async_trait;
+use aws_sdk_s3::primitives::ByteStream;
+use tokio::{fs, io::AsyncWriteExt};
+use walkdir::WalkDir;
+
+use crate::{error::{ErrorKind, SyncError}, etag::{content_md5, md5_file}, meta::parse_mtime};
+
+use super::{RemoteBackend, RemoteFile, RemoteObject, RemoteStat};
+
+/// Object metadata is kept next to the files, below this folder of the root.
+const META_DIR_NAME: &str = ".cellsmeta";
+const PART_SUFFIX: &str = ".cellspart";
+
+/// Files in a folder, such as a mounted NAS share. Keys are paths relative
+/// to the root.
+pub struct LocalBackend {
+	root: PathBuf
+}
+
+impl LocalBackend {
+	pub fn new(root: PathBuf) -> Self {
+		Self { root }
+	}
+
+	fn path(&self, key: &str) -> PathBuf {
+		let mut p = self.root.clone();
+		p.extend(key.split('/').filter(|c| !c.is_empty()));
+		p
+	}
+
+	fn meta_path(&self, key: &str) -> PathBuf {
+		let mut p = self.root.join(META_DIR_NAME);
+		p.extend(key.split('/').filter(|c| !c.is_empty()));
+		let mut p = p.into_os_string();
+		p.push(".json");
+		PathBuf::from(p)
+	}
+
+	async fn read_metadata(&self, key: &str) -> HashMap<String, String> {
+		match fs::read_to_string(self.meta_path(key)).await {
+			Ok(content) => serde_json::from_str(&content).unwrap_or_default(),
+			Err(_) => HashMap::new()
+		}
+	}
+
+	async fn write_metadata(&self, key: &str, metadata: &HashMap<String, String>) -> Result<(), SyncError> {
+		let path = self.meta_path(key);
+		if metadata.is_empty() {
+			let _ = fs::remove_file(&path).await;
+			return Ok(());
+		}
+		if let Some(parent) = path.parent() {
+			fs::create_dir_all(parent).await.map_err(io_error)?;
+		}
+		let content = serde_json::to_string(metadata).map_err(|e| SyncError::new(ErrorKind::Unknown, e))?;
+		fs::write(&path, content).await.map_err(io_error)
+	}
+}
+
+fn io_error(e: std::io::Error) -> SyncError {
+	SyncError::new(ErrorKind::Io, e)
+}
+
+#[async_trait]
+impl RemoteBackend for LocalBackend {
+	async fn list(&self, prefix: &str) -> Result<Vec<RemoteFile>, SyncError> {
+		let dir = self.path(prefix);
+		if !dir.exists() {
+			return Ok(vec![]);
+		}
+
+		let mut files = vec![];
+		let walk = WalkDir::new(&dir)
+			.min_depth(1)
+			.into_iter()
+			.filter_entry(|e| e.file_name() != META_DIR_NAME);
+		for entry in walk {
+			let entry = entry.map_err(|e| SyncError::new(ErrorKind::Io, e))?;
+			let relative = match entry.path().strip_prefix(&self.root).ok().and_then(|p| p.to_str()) {
+				Some(r) => r.replace('\\', "/"),
+				None => continue
+			};
+
+			if entry.file_type().is_dir() {
+				let empty = std::fs::read_dir(entry.path()).map(|mut d| d.next().is_none()).unwrap_or(false);
+				if empty {
+					files.push(RemoteFile { key: format!("{}/", relative), size: 0 });
+				}
+			} else if !relative.ends_with(PART_SUFFIX) {
+				let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+				files.push(RemoteFile { key: relative, size });
+			}
+		}
+		Ok(files)
+	}
+
+	async fn stat(&self, key: &str) -> Result<Option<RemoteStat>, SyncError> {
+		let path = self.path(key);
+		let m = match fs::metadata(&path).await {
+			Ok(m) if m.is_file() => m,
+			Ok(_) => return Ok(None),
+			Err(e) if e.kind() == IoErrorKind::NotFound => return Ok(None),
+			Err(e) => return Err(io_error(e))
+		};
+
+		let metadata = self.read_metadata(key).await;
+		let etag = md5_file(&path).await.map(hex::encode).map_err(io_error)?;
+		let mtime = parse_mtime(&metadata).or_else(|| m.modified().ok());
+		Ok(Some(RemoteStat {
+			size: m.len(),
+			etag,
+			mtime,
+			metadata
+		}))
+	}
+
+	async fn put(
+		&self, key: &str, mut body: ByteStream, content_md5_b64: Option<String>, metadata: HashMap<String, String>
+	) -> Result<(), SyncError> {
+		let path = self.path(key);
+		if let Some(parent) = path.parent() {
+			fs::create_dir_all(parent).await.map_err(io_error)?;
+		}
+		let mut part = path.clone().into_os_string();
+		part.push(PART_SUFFIX);
+		let part = PathBuf::from(part);
+
+		let written: Result<(), SyncError> = async {
+			let mut file = fs::File::create(&part).await.map_err(io_error)?;
+			while let Some(chunk) = body.try_next().await.map_err(|e| SyncError::new(ErrorKind::Io, e))? {
+				file.write_all(&chunk).await.map_err(io_error)?;
+			}
+			file.flush().await.map_err(io_error)?;
+
+			if let Some(expected) = &content_md5_b64 {
+				let actual = md5_file(&part).await.map_err(io_error)?;
+				if &content_md5(&actual) != expected {
+					return Err(SyncError::new(ErrorKind::RemoteRejected, "BadDigest: the content does not match its MD5"));
+				}
+			}
+			Ok(())
+		}.await;
+		if let Err(err) = written {
+			let _ = fs::remove_file(&part).await;
+			return Err(err);
+		}
+
+		fs::rename(&part, &path).await.map_err(io_error)?;
+		self.write_metadata(key, &metadata).await
+	}
+
+	async fn get(&self, key: &str) -> Result<RemoteObject, SyncError> {
+		let body = ByteStream::from_path(self.path(key)).await.map_err(|e| SyncError::new(ErrorKind::Io, e))?;
+		Ok(RemoteObject {
+			body,
+			metadata: self.read_metadata(key).await
+		})
+	}
+
+	async fn delete(&self, key: &str) -> Result<(), SyncError> {
+		match fs::remove_file(self.path(key)).await {
+			Ok(_) => {},
+			Err(e) if e.kind() == IoErrorKind::NotFound => {},
+			Err(e) => return Err(io_error(e))
+		}
+		let _ = fs::remove_file(self.meta_path(key)).await;
+		Ok(())
+	}
+
+	async fn rename(&self, from: &str, to: &str) -> Result<(), SyncError> {
+		let target = self.path(to);
+		if let Some(parent) = target.parent() {
+			fs::create_dir_all(parent).await.map_err(io_error)?;
+		}
+		fs::rename(self.path(from), &target).await.map_err(io_error)?;
+
+		let metadata = self.read_metadata(from).await;
+		let _ = fs::remove_file(self.meta_path(from)).await;
+		self.write_metadata(to, &metadata).await
+	}
+
+	async fn copy(&self, from: &str, to: &str) -> Result<(), SyncError> {
+		let target = self.path(to);
+		if let Some(parent) = target.parent() {
+			fs::create_dir_all(parent).await.map_err(io_error)?;
+		}
+		fs::copy(self.path(from), &target).await.map_err(io_error)?;
+		self.write_metadata(to, &self.read_metadata(from).await).await
+	}
+
+	async fn create_folders(&self, paths: &[String]) -> Result<(), SyncError> {
+		for path in paths {
+			fs::create_dir_all(self.path(path)).await.map_err(io_error)?;
+		}
+		Ok(())
+	}
+}
+
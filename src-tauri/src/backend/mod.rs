@@ -0,0 +1,122 @@
+use std::{collections::HashMap, io::SeekFrom, sync::Arc, time::SystemTime};
+
+use async_trait::async_trait;
+use aws_sdk_s3::primitives::ByteStream;
+use tokio::{fs::File, io::{AsyncSeekExt, AsyncWriteExt}};
+
+use crate::{error::SyncError, net::{get_encryption_passphrase, get_s3_secret_key}, structs::{BackendConfig, EncryptionMode, TaskData}};
+
+mod cells;
+mod compressed;
+mod encrypted;
+mod local;
+mod s3;
+mod versioned;
+
+pub use cells::CellsBackend;
+pub use compressed::CompressedBackend;
+pub use encrypted::EncryptedBackend;
+pub use local::LocalBackend;
+pub use s3::S3Backend;
+pub use versioned::{prune_versions, VersionedBackend};
+
+/// An entry of a remote listing. Folders have a key ending in `/`, they are
+/// only listed when nothing else would show they exist.
+#[derive(Clone)]
+pub struct RemoteFile {
+	pub key: String,
+	pub size: u64
+}
+
+pub struct RemoteStat {
+	pub size: u64,
+	/// Hex MD5 of the content for objects uploaded in one part
+	pub etag: String,
+	/// The stored local modification time, or the upload time for objects without one
+	pub mtime: Option<SystemTime>,
+	pub metadata: HashMap<String, String>
+}
+
+pub struct RemoteObject {
+	pub body: ByteStream,
+	pub metadata: HashMap<String, String>
+}
+
+/// Storage a task syncs with. Keys are `/` separated paths without a leading slash.
+#[async_trait]
+pub trait RemoteBackend: Send + Sync {
+	/// Everything below `prefix`, recursively.
+	async fn list(&self, prefix: &str) -> Result<Vec<RemoteFile>, SyncError>;
+
+	/// `None` when there is no object at `key`.
+	async fn stat(&self, key: &str) -> Result<Option<RemoteStat>, SyncError>;
+
+	/// Store `body` at `key`. `content_md5` is the base64 digest the stored
+	/// content has to match.
+	async fn put(
+		&self, key: &str, body: ByteStream, content_md5: Option<String>, metadata: HashMap<String, String>
+	) -> Result<(), SyncError>;
+
+	async fn get(&self, key: &str) -> Result<RemoteObject, SyncError>;
+
+	async fn delete(&self, key: &str) -> Result<(), SyncError>;
+
+	async fn rename(&self, from: &str, to: &str) -> Result<(), SyncError>;
+
+	/// Copy the object at `from` to `to` without downloading it.
+	async fn copy(&self, from: &str, to: &str) -> Result<(), SyncError>;
+
+	/// Make sure the folders exist, even with nothing in them.
+	async fn create_folders(&self, paths: &[String]) -> Result<(), SyncError>;
+}
+
+/// The storage of task `uuid`, whose S3 secret key is in the keyring.
+pub fn backend_for(config: &BackendConfig, uuid: &str) -> Arc<dyn RemoteBackend> {
+	match config {
+		BackendConfig::Cells => Arc::new(CellsBackend),
+		BackendConfig::S3 { endpoint, region, bucket, accessKey, secretKey } => {
+			// tasks saved before the key moved to the keyring still carry it
+			let secret_key = if secretKey.is_empty() { get_s3_secret_key(uuid) } else { secretKey.clone() };
+			Arc::new(S3Backend::connect(endpoint, region, bucket, accessKey, &secret_key))
+		},
+		BackendConfig::Local { root } => Arc::new(LocalBackend::new(root.clone()))
+	}
+}
+
+/// The storage of a task, behind its encryption and versioning when it has
+/// them. Compressed objects are read back whether or not the task compresses
+/// its own uploads, and files are compressed before they are encrypted, as
+/// ciphertext does not compress.
+pub fn task_backend(task: &TaskData) -> Arc<dyn RemoteBackend> {
+	let backend = backend_for(&task.backend, &task.uuid);
+	let backend: Arc<dyn RemoteBackend> = match task.encryption {
+		EncryptionMode::Off => backend,
+		mode => Arc::new(EncryptedBackend::new(
+			backend,
+			&task.uuid,
+			&task.remoteDir.Path,
+			mode == EncryptionMode::ContentAndNames,
+			get_encryption_passphrase(&task.uuid)
+		))
+	};
+	let patterns: &[String] = if task.compression.enabled { &task.compression.patterns } else { &[] };
+	// object metadata is not encrypted, the originals of encrypted files are kept locally
+	let state_uuid = (task.encryption != EncryptionMode::Off).then_some(task.uuid.as_str());
+	let backend = Arc::new(CompressedBackend::new(backend, patterns, state_uuid));
+	if task.backup.enabled {
+		Arc::new(VersionedBackend::new(backend, &task.remoteDir.Path))
+	} else {
+		backend
+	}
+}
+
+/// A temporary file, removed once closed, holding the output of a transfer.
+fn spool() -> Result<File, SyncError> {
+	Ok(File::from_std(tempfile::tempfile().map_err(|e| SyncError::io("create temporary file", e))?))
+}
+
+async fn spooled_body(mut file: File) -> Result<ByteStream, SyncError> {
+	file.flush().await.map_err(|e| SyncError::io("write temporary file", e))?;
+	file.seek(SeekFrom::Start(0)).await.map_err(|e| SyncError::io("read temporary file", e))?;
+	ByteStream::read_from().file(file).build().await.map_err(|e| SyncError::io("read temporary file", e))
+}
@@ -0,0 +1,163 @@
+use std::{collections::HashMap, time::SystemTime};
+
+use async_trait::async_trait;
+use aws_config::{AppName, BehaviorVersion, Region, SdkConfig};
+use aws_sdk_s3::{config::{Credentials, SharedCredentialsProvider}, primitives::ByteStream, Client};
+
+use crate::{error::SyncError, meta::parse_mtime};
+
+use super::{RemoteBackend, RemoteFile, RemoteObject, RemoteStat};
+
+/// Objects in a bucket of any S3 compatible storage.
+pub struct S3Backend {
+	client: Client,
+	bucket: String
+}
+
+impl S3Backend {
+	pub fn new(client: Client, bucket: &str) -> Self {
+		Self { client, bucket: bucket.to_string() }
+	}
+
+	/// Path style addressing is used, which MinIO and most self hosted
+	/// storages expect.
+	pub fn connect(endpoint: &str, region: &str, bucket: &str, access_key: &str, secret_key: &str) -> Self {
+		let config = SdkConfig::builder()
+			.endpoint_url(endpoint)
+			.app_name(AppName::new("s3").unwrap())
+			.behavior_version(BehaviorVersion::latest())
+			.region(Region::new(if region.is_empty() { "us-east-1".to_string() } else { region.to_string() }))
+			.credentials_provider(
+				SharedCredentialsProvider::new(
+					Credentials::new(access_key, secret_key, None, None, "s3")
+				)
+			).build();
+		let config = aws_sdk_s3::config::Builder::from(&config)
+			.force_path_style(true)
+			.build();
+
+		Self::new(Client::from_conf(config), bucket)
+	}
+}
+
+#[async_trait]
+impl RemoteBackend for S3Backend {
+	async fn list(&self, prefix: &str) -> Result<Vec<RemoteFile>, SyncError> {
+		let mut pages = self.client
+			.list_objects_v2()
+			.bucket(&self.bucket)
+			.prefix(prefix)
+			.into_paginator()
+			.send();
+
+		let mut files = vec![];
+		while let Some(page) = pages.next().await {
+			let page = page.map_err(|e| SyncError::from_sdk(&e))?;
+			for object in page.contents() {
+				if let Some(key) = object.key() {
+					files.push(RemoteFile {
+						key: key.to_string(),
+						size: object.size().unwrap_or(0) as u64
+					});
+				}
+			}
+		}
+		Ok(files)
+	}
+
+	async fn stat(&self, key: &str) -> Result<Option<RemoteStat>, SyncError> {
+		match self.client.head_object().bucket(&self.bucket).key(key).send().await {
+			Ok(output) => {
+				let metadata = output.metadata().cloned().unwrap_or_default();
+				let mtime = parse_mtime(&metadata)
+					.or_else(|| output.last_modified().and_then(|d| SystemTime::try_from(*d).ok()));
+				Ok(Some(RemoteStat {
+					size: output.content_length().unwrap_or(0) as u64,
+					etag: output.e_tag().unwrap_or("").trim_matches('"').to_string(),
+					mtime,
+					metadata
+				}))
+			},
+			Err(e) if e.as_service_error().map(|se| se.is_not_found()).unwrap_or(false) => Ok(None),
+			Err(e) => Err(SyncError::from_sdk(&e))
+		}
+	}
+
+	async fn put(
+		&self, key: &str, body: ByteStream, content_md5: Option<String>, metadata: HashMap<String, String>
+	) -> Result<(), SyncError> {
+		self.client
+			.put_object()
+			.bucket(&self.bucket)
+			.key(key)
+			.set_content_md5(content_md5)
+			.set_metadata(Some(metadata))
+			.body(body)
+			.send()
+			.await
+			.map_err(|e| SyncError::from_sdk(&e))?;
+		Ok(())
+	}
+
+	async fn get(&self, key: &str) -> Result<RemoteObject, SyncError> {
+		let output = self.client
+			.get_object()
+			.bucket(&self.bucket)
+			.key(key)
+			.send()
+			.await
+			.map_err(|e| SyncError::from_sdk(&e))?;
+		Ok(RemoteObject {
+			metadata: output.metadata().cloned().unwrap_or_default(),
+			body: output.body
+		})
+	}
+
+	async fn delete(&self, key: &str) -> Result<(), SyncError> {
+		self.client
+			.delete_object()
+			.bucket(&self.bucket)
+			.key(key)
+			.send()
+			.await
+			.map_err(|e| SyncError::from_sdk(&e))?;
+		Ok(())
+	}
+
+	/// S3 has no rename, the object is copied and the original removed.
+	async fn rename(&self, from: &str, to: &str) -> Result<(), SyncError> {
+		self.copy(from, to).await?;
+		self.delete(from).await
+	}
+
+	async fn copy(&self, from: &str, to: &str) -> Result<(), SyncError> {
+		self.client
+			.copy_object()
+			.bucket(&self.bucket)
+			.copy_source(format!("{}/{}", &self.bucket, encode_key(from)))
+			.key(to)
+			.send()
+			.await
+			.map_err(|e| SyncError::from_sdk(&e))?;
+		Ok(())
+	}
+
+	async fn create_folders(&self, paths: &[String]) -> Result<(), SyncError> {
+		for path in paths {
+			self.put(&format!("{}/", path.trim_end_matches('/')), ByteStream::from_static(b""), None, HashMap::new()).await?;
+		}
+		Ok(())
+	}
+}
+
+/// Copy sources are sent in a header and have to be URL encoded.
+fn encode_key(key: &str) -> String {
+	let mut encoded = String::with_capacity(key.len());
+	for b in key.bytes() {
+		match b {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => encoded.push(b as char),
+			_ => encoded.push_str(&format!("%{:02X}", b))
+		}
+	}
+	encoded
+}
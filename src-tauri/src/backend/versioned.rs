@@ -0,0 +1,189 @@
+use std::{collections::{HashMap, HashSet}, sync::Arc};
+
+use async_trait::async_trait;
+use aws_sdk_s3::primitives::ByteStream;
+use chrono::{DateTime, Datelike, Duration, Local, Months, NaiveDateTime, Utc};
+use tracing::debug;
+
+use crate::{error::SyncError, structs::BackupConfig};
+
+use super::{RemoteBackend, RemoteFile, RemoteObject, RemoteStat};
+
+/// Folder of the task root replaced files are moved to.
+const VERSIONS_DIR_NAME: &str = ".versions";
+/// Versions are named after the time their content was current, followed by
+/// a counter when a version of that time exists already and the extension of
+/// the file.
+const STAMP_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
+const STAMP_LEN: usize = 20;
+
+/// Never overwrites or deletes a file of the task. The object in the way is
+/// copied to `<root>/.versions/<path>/<time><ext>` before it is overwritten,
+/// or moved there when it is deleted. The versions folder is hidden from
+/// listings of the task.
+pub struct VersionedBackend {
+	inner: Arc<dyn RemoteBackend>,
+	root: String
+}
+
+impl VersionedBackend {
+	pub fn new(inner: Arc<dyn RemoteBackend>, root: &str) -> Self {
+		Self {
+			inner,
+			root: root.trim_end_matches('/').to_string()
+		}
+	}
+
+	fn versions_dir(&self) -> String {
+		versions_dir(&self.root)
+	}
+
+	fn version_key(&self, key: &str, time: DateTime<Utc>, n: u32) -> Option<String> {
+		let path = key.strip_prefix(&format!("{}/", &self.root))?;
+		let name = path.rsplit('/').next().unwrap_or(path);
+		let ext = match name.rsplit_once('.') {
+			Some((stem, ext)) if !stem.is_empty() => format!(".{}", ext),
+			_ => String::new()
+		};
+		let suffix = if n == 0 { String::new() } else { format!("-{}", n) };
+		Some(format!("{}{}/{}{}{}", self.versions_dir(), path, time.format(STAMP_FORMAT), suffix, ext))
+	}
+
+	/// Copy the object at `key`, if there is one, to its versions. It is
+	/// moved instead when `moving`, otherwise it stays in place until the
+	/// caller replaced it, so a failed upload never leaves the path empty.
+	async fn keep_version(&self, key: &str, moving: bool) -> Result<(), SyncError> {
+		let stat = match self.inner.stat(key).await? {
+			Some(s) => s,
+			None => return Ok(())
+		};
+		let time = stat.mtime.map(DateTime::<Utc>::from).unwrap_or_else(Utc::now);
+		let mut n = 0;
+		while let Some(version) = self.version_key(key, time, n) {
+			// equal mtimes must not replace the version kept before
+			if self.inner.stat(&version).await?.is_some() {
+				n += 1;
+				continue;
+			}
+			debug!("Keep {} as {}", key, &version);
+			if moving {
+				self.inner.rename(key, &version).await?;
+			} else {
+				self.inner.copy(key, &version).await?;
+			}
+			break;
+		}
+		Ok(())
+	}
+}
+
+fn versions_dir(root: &str) -> String {
+	format!("{}/{}/", root.trim_end_matches('/'), VERSIONS_DIR_NAME)
+}
+
+#[async_trait]
+impl RemoteBackend for VersionedBackend {
+	async fn list(&self, prefix: &str) -> Result<Vec<RemoteFile>, SyncError> {
+		let versions_dir = self.versions_dir();
+		let files = self.inner.list(prefix).await?;
+		if prefix.starts_with(&versions_dir) {
+			return Ok(files);
+		}
+		Ok(files.into_iter().filter(|f| !f.key.starts_with(&versions_dir)).collect())
+	}
+
+	async fn stat(&self, key: &str) -> Result<Option<RemoteStat>, SyncError> {
+		self.inner.stat(key).await
+	}
+
+	async fn put(
+		&self, key: &str, body: ByteStream, content_md5: Option<String>, metadata: HashMap<String, String>
+	) -> Result<(), SyncError> {
+		if !key.starts_with(&self.versions_dir()) {
+			self.keep_version(key, false).await?;
+		}
+		self.inner.put(key, body, content_md5, metadata).await
+	}
+
+	async fn get(&self, key: &str) -> Result<RemoteObject, SyncError> {
+		self.inner.get(key).await
+	}
+
+	/// Files of the task are moved to their versions, only versions are deleted.
+	async fn delete(&self, key: &str) -> Result<(), SyncError> {
+		if key.starts_with(&self.versions_dir()) {
+			return self.inner.delete(key).await;
+		}
+		self.keep_version(key, true).await
+	}
+
+	async fn rename(&self, from: &str, to: &str) -> Result<(), SyncError> {
+		self.keep_version(to, false).await?;
+		self.inner.rename(from, to).await
+	}
+
+	async fn copy(&self, from: &str, to: &str) -> Result<(), SyncError> {
+		self.keep_version(to, false).await?;
+		self.inner.copy(from, to).await
+	}
+
+	async fn create_folders(&self, paths: &[String]) -> Result<(), SyncError> {
+		self.inner.create_folders(paths).await
+	}
+}
+
+fn parse_stamp(name: &str) -> Option<DateTime<Utc>> {
+	let stamp = name.get(..STAMP_LEN)?;
+	NaiveDateTime::parse_from_str(stamp, STAMP_FORMAT).ok().map(|t| t.and_utc())
+}
+
+/// Delete the versions below `root` no retention rule of `backup` keeps
+/// anymore, returns how many were deleted.
+pub async fn prune_versions(backend: &dyn RemoteBackend, root: &str, backup: &BackupConfig) -> Result<usize, SyncError> {
+	let mut files: HashMap<String, Vec<(DateTime<Utc>, String)>> = HashMap::new();
+	for f in backend.list(&versions_dir(root)).await? {
+		let (file, name) = match f.key.rsplit_once('/') {
+			Some(split) => split,
+			None => continue
+		};
+		if let Some(time) = parse_stamp(name) {
+			files.entry(file.to_string()).or_default().push((time, f.key.clone()));
+		}
+	}
+
+	let now = Utc::now();
+	let mut deleted = 0;
+	for versions in files.values_mut() {
+		for key in expired(versions, backup, now) {
+			debug!("Prune version {}", &key);
+			backend.delete(&key).await?;
+			deleted += 1;
+		}
+	}
+	Ok(deleted)
+}
+
+/// Versions of one file that are neither among the last `keepLast`, nor the
+/// newest of a recent day or month.
+fn expired(versions: &mut [(DateTime<Utc>, String)], backup: &BackupConfig, now: DateTime<Utc>) -> Vec<String> {
+	// counted versions of the same time are newer, and have longer names
+	versions.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.len().cmp(&a.1.len())).then(b.1.cmp(&a.1)));
+	let daily_since = now - Duration::days(backup.keepDailyDays as i64);
+	let monthly_since = now.checked_sub_months(Months::new(backup.keepMonthlyMonths)).unwrap_or(now);
+
+	// newest first, so the first version seen of a day or month is the one kept for it
+	let (mut days, mut months) = (HashSet::new(), HashSet::new());
+	versions
+		.iter()
+		.enumerate()
+		.filter_map(|(i, (time, key))| {
+			let local = time.with_timezone(&Local);
+			let newest_of_day = days.insert(local.date_naive());
+			let newest_of_month = months.insert((local.year(), local.month()));
+			let keep = i < backup.keepLast
+				|| (backup.keepDailyDays > 0 && newest_of_day && *time >= daily_since)
+				|| (backup.keepMonthlyMonths > 0 && newest_of_month && *time >= monthly_since);
+			if keep { None } else { Some(key.clone()) }
+		})
+		.collect()
+}
@@ -0,0 +1,240 @@
+use std::num::NonZeroU32;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::{aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN}, hkdf, hmac, pbkdf2, rand::{SecureRandom, SystemRandom}};
+
+/// Start of every encrypted file, followed by the salt of its key.
+const MAGIC: &[u8; 4] = b"CSE1";
+const SALT_LEN: usize = 16;
+const HEADER_LEN: usize = MAGIC.len() + SALT_LEN;
+/// Plaintext bytes sealed together, every chunk adds a tag.
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+const PBKDF2_ROUNDS: u32 = 600_000;
+/// Sealed by the key check, decrypting it proves the passphrase is right.
+const CHECK_PLAINTEXT: &[u8] = b"cells-sync key check";
+
+#[derive(Debug)]
+pub struct CryptoError(pub &'static str);
+
+impl std::fmt::Display for CryptoError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(self.0)
+	}
+}
+
+pub fn random_bytes(len: usize) -> Vec<u8> {
+	let mut bytes = vec![0u8; len];
+	SystemRandom::new().fill(&mut bytes).expect("no system random source");
+	bytes
+}
+
+/// Key of an encrypted task, derived from its passphrase. Every file gets its
+/// own content key from a random salt, names use a key of their own.
+pub struct TaskKey {
+	master: [u8; 32],
+	names: LessSafeKey,
+	name_nonces: hmac::Key,
+	check: LessSafeKey
+}
+
+impl TaskKey {
+	/// PBKDF2 is slow on purpose, a key is derived once per run.
+	pub fn derive(passphrase: &str, salt: &[u8]) -> Self {
+		let mut master = [0u8; 32];
+		pbkdf2::derive(
+			pbkdf2::PBKDF2_HMAC_SHA256,
+			NonZeroU32::new(PBKDF2_ROUNDS).unwrap(),
+			salt,
+			passphrase.as_bytes(),
+			&mut master
+		);
+		let prk = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &master);
+		Self {
+			master,
+			names: aead_key(&prk, b"names"),
+			name_nonces: prk.expand(&[b"name nonces".as_slice()], hmac::HMAC_SHA256).unwrap().into(),
+			check: aead_key(&prk, b"check")
+		}
+	}
+
+	fn content_key(&self, salt: &[u8]) -> LessSafeKey {
+		let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(&self.master);
+		aead_key(&prk, b"content")
+	}
+
+	/// A sealed constant stored next to the files, see `verify`.
+	pub fn check(&self) -> Vec<u8> {
+		let mut sealed = CHECK_PLAINTEXT.to_vec();
+		self.check
+			.seal_in_place_append_tag(Nonce::assume_unique_for_key([0u8; NONCE_LEN]), Aad::empty(), &mut sealed)
+			.unwrap();
+		sealed
+	}
+
+	pub fn verify(&self, check: &[u8]) -> bool {
+		let mut sealed = check.to_vec();
+		self.check
+			.open_in_place(Nonce::assume_unique_for_key([0u8; NONCE_LEN]), Aad::empty(), &mut sealed)
+			.map(|plain| plain == CHECK_PLAINTEXT)
+			.unwrap_or(false)
+	}
+
+	/// Deterministic, so a file keeps its remote name from run to run. The
+	/// nonce is derived from the name itself.
+	pub fn encrypt_name(&self, name: &str) -> String {
+		let tag = hmac::sign(&self.name_nonces, name.as_bytes());
+		let mut nonce = [0u8; NONCE_LEN];
+		nonce.copy_from_slice(&tag.as_ref()[..NONCE_LEN]);
+
+		let mut sealed = name.as_bytes().to_vec();
+		self.names
+			.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(b"name"), &mut sealed)
+			.unwrap();
+		let mut out = nonce.to_vec();
+		out.extend(sealed);
+		URL_SAFE_NO_PAD.encode(out)
+	}
+
+	pub fn decrypt_name(&self, encrypted: &str) -> Result<String, CryptoError> {
+		let bytes = URL_SAFE_NO_PAD.decode(encrypted).map_err(|_| CryptoError("Not an encrypted name"))?;
+		if bytes.len() < NONCE_LEN + TAG_LEN {
+			return Err(CryptoError("Not an encrypted name"));
+		}
+		let (nonce, sealed) = bytes.split_at(NONCE_LEN);
+		let mut sealed = sealed.to_vec();
+		let plain = self.names
+			.open_in_place(Nonce::try_assume_unique_for_key(nonce).unwrap(), Aad::from(b"name"), &mut sealed)
+			.map_err(|_| CryptoError("The name does not decrypt with this passphrase"))?;
+		String::from_utf8(plain.to_vec()).map_err(|_| CryptoError("The decrypted name is not UTF-8"))
+	}
+
+	pub fn encryptor(&self) -> Encryptor {
+		let salt = random_bytes(SALT_LEN);
+		let mut pending = MAGIC.to_vec();
+		pending.extend(&salt);
+		Encryptor {
+			key: self.content_key(&salt),
+			buffer: vec![],
+			header: Some(pending),
+			counter: 0
+		}
+	}
+
+	pub fn decryptor(&self) -> Decryptor<'_> {
+		Decryptor {
+			task_key: self,
+			key: None,
+			buffer: vec![],
+			counter: 0
+		}
+	}
+}
+
+fn aead_key(prk: &hkdf::Prk, info: &[u8]) -> LessSafeKey {
+	let info = [info];
+	LessSafeKey::new(UnboundKey::from(prk.expand(&info, &AES_256_GCM).unwrap()))
+}
+
+/// Chunks are numbered and the last one is marked, so chunks can neither be
+/// reordered nor cut off.
+fn chunk_nonce(counter: u64, last: bool) -> Nonce {
+	let mut nonce = [0u8; NONCE_LEN];
+	nonce[3..11].copy_from_slice(&counter.to_be_bytes());
+	nonce[11] = last as u8;
+	Nonce::assume_unique_for_key(nonce)
+}
+
+/// Encrypts a file as it is read, see `TaskKey::encryptor`.
+pub struct Encryptor {
+	key: LessSafeKey,
+	buffer: Vec<u8>,
+	header: Option<Vec<u8>>,
+	counter: u64
+}
+
+impl Encryptor {
+	/// Ciphertext for the chunks completed by `data`.
+	pub fn update(&mut self, data: &[u8]) -> Vec<u8> {
+		let mut out = self.header.take().unwrap_or_default();
+		self.buffer.extend_from_slice(data);
+		// the last chunk is only sealed in `finish`, it may still grow
+		while self.buffer.len() > CHUNK_SIZE {
+			let rest = self.buffer.split_off(CHUNK_SIZE);
+			let chunk = std::mem::replace(&mut self.buffer, rest);
+			out.extend(self.seal(chunk, false));
+		}
+		out
+	}
+
+	pub fn finish(mut self) -> Vec<u8> {
+		let mut out = self.header.take().unwrap_or_default();
+		let chunk = std::mem::take(&mut self.buffer);
+		out.extend(self.seal(chunk, true));
+		out
+	}
+
+	fn seal(&mut self, mut chunk: Vec<u8>, last: bool) -> Vec<u8> {
+		self.key.seal_in_place_append_tag(chunk_nonce(self.counter, last), Aad::empty(), &mut chunk).unwrap();
+		self.counter += 1;
+		chunk
+	}
+}
+
+/// Decrypts what an `Encryptor` produced, failing on any change to it.
+pub struct Decryptor<'a> {
+	task_key: &'a TaskKey,
+	key: Option<LessSafeKey>,
+	buffer: Vec<u8>,
+	counter: u64
+}
+
+impl Decryptor<'_> {
+	pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+		self.buffer.extend_from_slice(data);
+		let mut out = vec![];
+		if self.key.is_none() {
+			if self.buffer.len() < HEADER_LEN {
+				return Ok(out);
+			}
+			if &self.buffer[..MAGIC.len()] != MAGIC {
+				return Err(CryptoError("The file is not encrypted"));
+			}
+			let rest = self.buffer.split_off(HEADER_LEN);
+			self.key = Some(self.task_key.content_key(&self.buffer[MAGIC.len()..]));
+			self.buffer = rest;
+		}
+		while self.buffer.len() > CHUNK_SIZE + TAG_LEN {
+			let rest = self.buffer.split_off(CHUNK_SIZE + TAG_LEN);
+			let chunk = std::mem::replace(&mut self.buffer, rest);
+			out.extend(self.open(chunk, false)?);
+		}
+		Ok(out)
+	}
+
+	pub fn finish(mut self) -> Result<Vec<u8>, CryptoError> {
+		if self.key.is_none() {
+			return Err(CryptoError("The file is cut off"));
+		}
+		let chunk = std::mem::take(&mut self.buffer);
+		self.open(chunk, true)
+	}
+
+	fn open(&mut self, mut chunk: Vec<u8>, last: bool) -> Result<Vec<u8>, CryptoError> {
+		let key = self.key.as_ref().unwrap();
+		let len = key
+			.open_in_place(chunk_nonce(self.counter, last), Aad::empty(), &mut chunk)
+			.map_err(|_| CryptoError("The file was changed or does not decrypt with this passphrase"))?
+			.len();
+		self.counter += 1;
+		chunk.truncate(len);
+		Ok(chunk)
+	}
+}
+
+/// Size of the plaintext of an encrypted file of `size` bytes.
+pub fn plaintext_size(size: u64) -> u64 {
+	let body = size.saturating_sub(HEADER_LEN as u64);
+	let chunks = body.div_ceil((CHUNK_SIZE + TAG_LEN) as u64).max(1);
+	body.saturating_sub(chunks * TAG_LEN as u64)
+}
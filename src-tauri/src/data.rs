@@ -3,23 +3,32 @@ use std::{fs::{create_dir_all, File, OpenOptions}, io::{Read, Write}, path::Path
 use auto_launch::AutoLaunchBuilder;
 use tauri::api::path::home_dir;
 use lazy_static::lazy_static;
+use tracing::info;
 
-use crate::{structs::{parse_json, Settings}, PACKAGE_NAME};
+use crate::{logging::set_log_level, pool::configure, structs::{parse_json, Settings, TaskData}, PACKAGE_NAME};
 
 const SETTINGS_FILE_NAME: &str = "settings.json";
+/// Keeps settings, tasks and history somewhere else, used for portable setups and tests.
+pub const CONFIG_DIR_ENV: &str = "CELLS_SYNC_CONFIG_DIR";
 
 lazy_static! {
 	pub static ref SETTINGS: Mutex<Settings> = Mutex::new(Settings::default());
+	// mirror of the task list owned by the window
+	pub static ref TASKS: Mutex<Vec<TaskData>> = Mutex::new(Vec::new());
 }
 
-fn get_config_dir() -> PathBuf {
+pub fn get_config_dir() -> PathBuf {
+	if let Some(dir) = std::env::var_os(CONFIG_DIR_ENV) {
+		return PathBuf::from(dir);
+	}
+
 	let mut home = home_dir().unwrap();
 	home.push(".config");
 	home.push(PACKAGE_NAME);
 	home
 }
 
-fn get_settings_path() -> PathBuf {
+pub fn get_settings_path() -> PathBuf {
 	let mut f = get_config_dir();
 	f.push(SETTINGS_FILE_NAME);
 	f
@@ -47,16 +56,20 @@ pub fn save_settings(new_settings: Settings) {
 
 	if new_settings.startWithLogin {
 		if !auto.is_enabled().unwrap() {
-			println!("Enable start at login");
+			info!("Enable start at login");
 			auto.enable().unwrap();
 		}
 	} else {
 		if auto.is_enabled().unwrap() {
-			println!("Disable start at login");
+			info!("Disable start at login");
 			auto.disable().unwrap();
 		}
 	}
 
+	set_log_level(&new_settings.logLevel);
+
+	configure(&new_settings);
+
 	// save to static
 	let mut settings = SETTINGS.lock().unwrap();
 	*settings = new_settings.clone();
@@ -78,5 +91,27 @@ pub fn save_settings(new_settings: Settings) {
 
 	file.write_all(settings_string.as_bytes()).unwrap();
 
-	println!("Save settings to {:?}", &save_path);
+	info!("Save settings to {:?}", &save_path);
+}
+
+pub fn set_tasks(new_tasks: Vec<TaskData>) {
+	let mut tasks = TASKS.lock().unwrap();
+	*tasks = new_tasks;
+}
+
+pub fn get_tasks() -> Vec<TaskData> {
+	let tasks = TASKS.lock().unwrap();
+	tasks.clone()
+}
+
+pub fn get_task(uuid: &str) -> Option<TaskData> {
+	let tasks = TASKS.lock().unwrap();
+	tasks.iter().find(|t| t.uuid == uuid).cloned()
+}
+
+pub fn update_task(task: TaskData) {
+	let mut tasks = TASKS.lock().unwrap();
+	if let Some(t) = tasks.iter_mut().find(|t| t.uuid == task.uuid) {
+		*t = task;
+	}
 }
@@ -1,18 +1,135 @@
-use std::sync::Mutex;
+use std::{fmt::{Debug, Display}, fs::read_link, path::{Path, PathBuf}, sync::{atomic::{AtomicU64, Ordering}, Mutex}};
 
+use aws_sdk_s3::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
 use lazy_static::lazy_static;
 use notify_rust::Notification;
+use serde::{Deserialize, Serialize};
 
-use crate::{data::get_saved_settings, PACKAGE_NAME};
+use crate::{data::{get_saved_settings, get_task}, history::now_millis, net::retry_file, structs::{CommandResponse, SymlinkPolicy, SyncDirection, SyncTask}, tray::{refresh_tray, refresh_tray_throttled}, PACKAGE_NAME};
+
+/// Only the most recent errors are kept in memory.
+const MAX_ERRORS: usize = 1000;
+
+const AUTH_CODES: [&str; 5] = ["AccessDenied", "InvalidAccessKeyId", "SignatureDoesNotMatch", "ExpiredToken", "InvalidToken"];
+const INVALID_NAME_CODES: [&str; 3] = ["NotImplemented", "InvalidObjectName", "KeyTooLongError"];
+const QUOTA_CODES: [&str; 3] = ["QuotaExceeded", "EntityTooLarge", "InsufficientStorage"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub enum ErrorKind {
+	Auth,
+	Network,
+	Io,
+	RemoteRejected,
+	Quota,
+	InvalidName,
+	/// The uploaded object does not match the local file
+	Verification,
+	/// No passphrase, a wrong one, or content that does not decrypt
+	Encryption,
+	/// The workspace does not allow syncing, or not in the direction of the task
+	Permission,
+	#[default]
+	Unknown
+}
+
+impl ErrorKind {
+	pub fn retryable(&self) -> bool {
+		matches!(self, Self::Auth | Self::Network | Self::Io | Self::RemoteRejected | Self::Verification | Self::Unknown)
+	}
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SyncError {
+	pub id: u64,
+	pub kind: ErrorKind,
+	pub message: String,
+	pub taskUuid: Option<String>,
+	pub path: Option<String>,
+	pub remotePath: Option<String>,
+	#[serde(default)]
+	pub direction: SyncDirection,
+	pub time: u64,
+	pub retryable: bool
+}
+
+impl SyncError {
+	pub fn new<T: ToString>(kind: ErrorKind, message: T) -> Self {
+		Self {
+			kind,
+			message: message.to_string(),
+			time: now_millis(),
+			retryable: kind.retryable(),
+			..Default::default()
+		}
+	}
+
+	/// `action` says what failed, such as "create folder".
+	pub fn io<T: Display>(action: &str, err: T) -> Self {
+		Self::new(ErrorKind::Io, format!("Failed to {}: {}", action, err))
+	}
+
+	pub fn from_sdk<E, R>(err: &SdkError<E, R>) -> Self
+	where E: ProvideErrorMetadata + std::error::Error + 'static, R: Debug {
+		let kind = match err {
+			SdkError::ServiceError(se) => match se.err().code() {
+				Some(code) if AUTH_CODES.contains(&code) => ErrorKind::Auth,
+				Some(code) if INVALID_NAME_CODES.contains(&code) => ErrorKind::InvalidName,
+				Some(code) if QUOTA_CODES.contains(&code) => ErrorKind::Quota,
+				Some(_) => ErrorKind::RemoteRejected,
+				None => ErrorKind::Unknown
+			},
+			SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => ErrorKind::Network,
+			_ => ErrorKind::Unknown
+		};
+		Self::new(kind, DisplayErrorContext(err))
+	}
+
+	pub fn task(mut self, uuid: &str) -> Self {
+		self.taskUuid = Some(uuid.to_string());
+		self
+	}
+
+	pub fn path(mut self, path: &Path) -> Self {
+		self.path = Some(path.to_string_lossy().to_string());
+		self
+	}
+
+	pub fn file(mut self, sync_task: &SyncTask) -> Self {
+		self.path = Some(sync_task.from.to_string_lossy().to_string());
+		self.remotePath = Some(sync_task.to.clone());
+		self.direction = sync_task.direction;
+		self
+	}
+}
+
+impl Display for SyncError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match &self.path {
+			Some(path) => write!(f, "{:?} error on {}: {}", self.kind, path, self.message),
+			None => write!(f, "{:?} error: {}", self.kind, self.message)
+		}
+	}
+}
 
 lazy_static! {
-	pub static ref ERRORS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+	pub static ref ERRORS: Mutex<Vec<SyncError>> = Mutex::new(Vec::new());
 }
 
-pub fn add_error<T: ToString>(err: T) {
+static NEXT_ERROR_ID: AtomicU64 = AtomicU64::new(1);
+
+pub fn add_error(mut err: SyncError) -> u64 {
+	err.id = NEXT_ERROR_ID.fetch_add(1, Ordering::Relaxed);
+	let id = err.id;
 	let str_err = err.to_string();
+
 	let mut errors = ERRORS.lock().unwrap();
-	errors.push(str_err.clone());
+	errors.push(err);
+	if errors.len() > MAX_ERRORS {
+		let overflow = errors.len() - MAX_ERRORS;
+		errors.drain(0..overflow);
+	}
+	drop(errors);
+	refresh_tray_throttled();
 
 	let settings = get_saved_settings();
 	if settings.notificationWhenFailed {
@@ -22,24 +139,77 @@ pub fn add_error<T: ToString>(err: T) {
 			.appname(PACKAGE_NAME)
 			.show();
 	}
+
+	id
+}
+
+pub fn error_count() -> usize {
+	let errors = ERRORS.lock().unwrap();
+	errors.len()
+}
+
+fn take_error(id: u64) -> Option<SyncError> {
+	let mut errors = ERRORS.lock().unwrap();
+	let index = errors.iter().position(|e| e.id == id)?;
+	let err = errors.remove(index);
+	drop(errors);
+	refresh_tray();
+	Some(err)
 }
 
 #[tauri::command]
-pub fn get_errors() -> Vec<String> {
-	let errs_lock = ERRORS.lock().unwrap();
-	let errs = errs_lock.clone();
-	drop(errs_lock);
-	errs
+pub fn list_errors() -> String {
+	let errors = ERRORS.lock().unwrap();
+	CommandResponse::ok(errors.clone()).to_string()
 }
 
 #[tauri::command]
-pub fn pop_error() -> String {
-	let mut errors = ERRORS.lock().unwrap();
-	let err = errors.pop().unwrap_or_else(|| "".to_string());
-	err
+pub fn acknowledge_error(id: u64) -> String {
+	match take_error(id) {
+		Some(_) => CommandResponse::empty_ok().to_string(),
+		None => CommandResponse::empty_err().to_string()
+	}
 }
 
-pub fn clear_errors() {
+#[tauri::command]
+pub fn acknowledge_all_errors() -> String {
 	let mut errors = ERRORS.lock().unwrap();
 	errors.clear();
+	drop(errors);
+	refresh_tray();
+	CommandResponse::empty_ok().to_string()
+}
+
+/// Upload the file an error refers to again. The error is acknowledged
+/// whether the retry succeeds or not, a new error is recorded on failure.
+#[tauri::command]
+pub async fn retry_error(id: u64) -> String {
+	let err = match take_error(id) {
+		Some(e) => e,
+		None => return CommandResponse::<()>::fail(ErrorKind::Unknown, "Error not found").to_string()
+	};
+
+	let (task_uuid, path, remote_path) = match (err.taskUuid.clone(), err.path.clone(), err.remotePath.clone()) {
+		(Some(u), Some(p), Some(r)) if err.retryable => (u, p, r),
+		_ => {
+			let mut errors = ERRORS.lock().unwrap();
+			errors.push(err);
+			drop(errors);
+			refresh_tray();
+			return CommandResponse::<()>::fail(ErrorKind::Unknown, "This error cannot be retried").to_string();
+		}
+	};
+
+	let from = PathBuf::from(path);
+	let size = from.metadata().map(|m| m.len()).unwrap_or(0);
+	let link = match get_task(&task_uuid) {
+		Some(t) if t.symlinkPolicy == SymlinkPolicy::Descriptor => read_link(&from).ok(),
+		_ => None
+	};
+	let success = retry_file(task_uuid, SyncTask { from, to: remote_path, size, direction: err.direction, link }).await;
+	if success {
+		CommandResponse::empty_ok().to_string()
+	} else {
+		CommandResponse::empty_err().to_string()
+	}
 }
@@ -1,23 +1,45 @@
+use aws_smithy_types::base64;
 use md5::{Digest, Md5};
 use std::fs::File;
-use std::io::Read;
+use std::io::{ErrorKind, Read};
 use std::path::Path;
 
-pub fn calculate_etag(file_path: &Path) -> Result<String, std::io::Error> {
+/// Large reads keep hashing fast on network shares and spinning disks.
+const READ_BUFFER_SIZE: usize = 1024 * 1024;
+
+pub fn calculate_md5(file_path: &Path) -> Result<Vec<u8>, std::io::Error> {
     let mut file = File::open(file_path)?;
-    let mut buffer = [0u8; 1024];
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
     let mut md5 = Md5::new();
 
-    while let Ok(bytes_read) = file.read(&mut buffer) {
+    loop {
+        let bytes_read = match file.read(&mut buffer) {
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        };
         if bytes_read == 0 {
             break;
         }
         md5.update(&buffer[..bytes_read]);
     }
 
-    let result = md5.finalize();
+    Ok(md5.finalize().to_vec())
+}
 
-    let etag = format!("{:x}", result);
+/// `calculate_md5` on the blocking thread pool, for use inside async tasks.
+pub async fn md5_file(file_path: &Path) -> Result<Vec<u8>, std::io::Error> {
+    let path = file_path.to_path_buf();
+    tokio::task::spawn_blocking(move || calculate_md5(&path))
+        .await
+        .map_err(|e| std::io::Error::new(ErrorKind::Other, e))?
+}
+
+pub fn md5_of(data: &[u8]) -> Vec<u8> {
+    Md5::digest(data).to_vec()
+}
 
-    Ok(etag)
+/// Value of the `Content-MD5` header for a digest from `calculate_md5`.
+pub fn content_md5(digest: &[u8]) -> String {
+    base64::encode(digest)
 }
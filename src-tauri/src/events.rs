@@ -0,0 +1,37 @@
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+use tauri::{AppHandle, Manager};
+use tracing::warn;
+
+pub const TASK_STARTED: &str = "task-started";
+pub const FILE_STARTED: &str = "file-started";
+pub const FILE_PROGRESS: &str = "file-progress";
+pub const FILE_DONE: &str = "file-done";
+pub const FILE_FAILED: &str = "file-failed";
+pub const TASK_FINISHED: &str = "task-finished";
+pub const TASK_UPDATED: &str = "task-updated";
+
+lazy_static! {
+	static ref APP_HANDLE: Mutex<Option<AppHandle>> = Mutex::new(None);
+}
+
+pub fn set_app_handle(app_handle: AppHandle) {
+	let mut handle = APP_HANDLE.lock().unwrap();
+	*handle = Some(app_handle);
+}
+
+pub fn app_handle() -> Option<AppHandle> {
+	let handle = APP_HANDLE.lock().unwrap();
+	handle.clone()
+}
+
+pub fn emit<S: Serialize + Clone>(event: &str, payload: S) {
+	let handle = APP_HANDLE.lock().unwrap();
+	if let Some(app_handle) = handle.as_ref() {
+		if let Err(e) = app_handle.emit_all(event, payload) {
+			warn!("Failed to emit {}: {:?}", event, e);
+		}
+	}
+}
@@ -0,0 +1,399 @@
+use std::{collections::HashMap, fs::{create_dir_all, rename, File, OpenOptions}, io::{BufRead, BufReader, Write}, path::PathBuf, sync::{mpsc::{channel, Sender}, Mutex}, thread, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use lazy_static::lazy_static;
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::{info, warn};
+
+use crate::{data::{get_config_dir, get_saved_settings}, error::ErrorKind, structs::{CommandResponse, FileAction, FileRecord, HistoryQuery, RunOutcome, RunRecord, SyncTask}};
+
+const HISTORY_DIR_NAME: &str = "history";
+const RUNS_FILE_NAME: &str = "runs.jsonl";
+const FILES_FILE_NAME: &str = "files.jsonl";
+
+const DAY_MILLIS: u64 = 24 * 60 * 60 * 1000;
+/// How long reading the history waits for the appends made before.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+enum HistoryWrite {
+	Line(&'static str, String),
+	/// Answered once every line sent before is written
+	Flush(Sender<()>)
+}
+
+lazy_static! {
+	// runs that have started but not finished yet, by task uuid
+	static ref ACTIVE_RUNS: Mutex<HashMap<String, RunRecord>> = Mutex::new(HashMap::new());
+	static ref HISTORY_LOCK: Mutex<()> = Mutex::new(());
+	// records are appended by one thread, off the runtime and in the order they were made
+	static ref HISTORY_WRITER: Mutex<Sender<HistoryWrite>> = Mutex::new(start_writer());
+}
+
+fn start_writer() -> Sender<HistoryWrite> {
+	let (tx, rx) = channel();
+	thread::spawn(move || {
+		for write in rx {
+			match write {
+				HistoryWrite::Line(name, line) => write_line(name, &line),
+				HistoryWrite::Flush(done) => {
+					let _ = done.send(());
+				}
+			}
+		}
+	});
+	tx
+}
+
+/// Wait for the records appended so far to be written.
+pub fn flush_history() {
+	let (tx, rx) = channel();
+	if HISTORY_WRITER.lock().unwrap().send(HistoryWrite::Flush(tx)).is_ok() {
+		let _ = rx.recv_timeout(FLUSH_TIMEOUT);
+	}
+}
+
+pub fn now_millis() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_millis() as u64)
+		.unwrap_or(0)
+}
+
+fn get_history_path(name: &str) -> PathBuf {
+	let mut p = get_config_dir();
+	p.push(HISTORY_DIR_NAME);
+	p.push(name);
+	p
+}
+
+fn append_record<T: Serialize>(name: &'static str, record: &T) {
+	let line = match serde_json::to_string(record) {
+		Ok(l) => l,
+		Err(e) => {
+			warn!("Failed to serialize history record: {}", e);
+			return;
+		}
+	};
+	if let Err(e) = HISTORY_WRITER.lock().unwrap().send(HistoryWrite::Line(name, line)) {
+		warn!("Failed to queue history record: {}", e);
+	}
+}
+
+fn write_line(name: &str, line: &str) {
+	let _lock = HISTORY_LOCK.lock().unwrap();
+	let path = get_history_path(name);
+	if !path.exists() {
+		let _ = create_dir_all(path.parent().unwrap());
+	}
+
+	let file = OpenOptions::new()
+		.create(true)
+		.append(true)
+		.open(&path);
+	match file {
+		Ok(mut f) => {
+			if let Err(e) = writeln!(f, "{}", line) {
+				warn!("Failed to write history to {:?}: {}", &path, e);
+			}
+		},
+		Err(e) => warn!("Failed to open history file {:?}: {}", &path, e)
+	}
+}
+
+fn read_records<T: DeserializeOwned>(name: &str) -> Vec<T> {
+	flush_history();
+	let _lock = HISTORY_LOCK.lock().unwrap();
+	let path = get_history_path(name);
+	let file = match File::open(&path) {
+		Ok(f) => f,
+		Err(_) => return vec![]
+	};
+
+	BufReader::new(file)
+		.lines()
+		.map_while(Result::ok)
+		.filter_map(|line| serde_json::from_str(&line).ok())
+		.collect()
+}
+
+fn write_records<T: Serialize>(name: &str, records: &[T]) {
+	let _lock = HISTORY_LOCK.lock().unwrap();
+	let path = get_history_path(name);
+	let tmp_path = path.with_extension("jsonl.tmp");
+	if !path.exists() {
+		let _ = create_dir_all(path.parent().unwrap());
+	}
+
+	let mut content = String::new();
+	for record in records {
+		if let Ok(line) = serde_json::to_string(record) {
+			content.push_str(&line);
+			content.push('\n');
+		}
+	}
+
+	let written = File::create(&tmp_path)
+		.and_then(|mut f| f.write_all(content.as_bytes()))
+		.and_then(|_| rename(&tmp_path, &path));
+	if let Err(e) = written {
+		warn!("Failed to rewrite history file {:?}: {}", &path, e);
+	}
+}
+
+/// Runs are appended when they start and again when they finish, the last
+/// line of a run wins.
+fn read_runs() -> Vec<RunRecord> {
+	let mut runs: Vec<RunRecord> = vec![];
+	let mut index: HashMap<String, usize> = HashMap::new();
+	for run in read_records::<RunRecord>(RUNS_FILE_NAME) {
+		if let Some(i) = index.get(&run.id) {
+			runs[*i] = run;
+		} else {
+			index.insert(run.id.clone(), runs.len());
+			runs.push(run);
+		}
+	}
+	runs
+}
+
+/// Drop records older than the retention period and collapse run updates.
+pub fn compact_history() {
+	let retention = get_saved_settings().historyRetentionDays;
+	let since = now_millis().saturating_sub(retention * DAY_MILLIS);
+
+	let runs: Vec<RunRecord> = read_runs()
+		.into_iter()
+		.filter(|r| r.startedAt >= since)
+		.map(|mut r| {
+			// a run that never finished was interrupted by the app exiting
+			if r.outcome == RunOutcome::Running {
+				r.outcome = RunOutcome::Cancelled;
+			}
+			r
+		})
+		.collect();
+	write_records(RUNS_FILE_NAME, &runs);
+
+	let files: Vec<FileRecord> = read_records::<FileRecord>(FILES_FILE_NAME)
+		.into_iter()
+		.filter(|f| f.time >= since)
+		.collect();
+	write_records(FILES_FILE_NAME, &files);
+}
+
+pub fn start_run(task_uuid: &str, trigger: &str, total: usize) {
+	let started_at = now_millis();
+	let run = RunRecord {
+		id: format!("{}-{}", task_uuid, started_at),
+		taskUuid: task_uuid.to_string(),
+		trigger: trigger.to_string(),
+		startedAt: started_at,
+		total,
+		..Default::default()
+	};
+	append_record(RUNS_FILE_NAME, &run);
+
+	let mut runs = ACTIVE_RUNS.lock().unwrap();
+	runs.insert(task_uuid.to_string(), run);
+}
+
+/// Grow the file count of a run while its scan is still finding files.
+pub fn add_to_run(task_uuid: &str, files: usize) {
+	let mut runs = ACTIVE_RUNS.lock().unwrap();
+	if let Some(run) = runs.get_mut(task_uuid) {
+		run.total += files;
+	}
+}
+
+pub fn record_file(task_uuid: &str, sync_task: &SyncTask, action: FileAction, bytes: u64, error: Option<String>) {
+	let mut runs = ACTIVE_RUNS.lock().unwrap();
+	let run_id = match runs.get_mut(task_uuid) {
+		Some(run) => {
+			match action {
+				FileAction::Uploaded => {
+					run.uploaded += 1;
+					run.bytes += bytes;
+				},
+				FileAction::Downloaded => {
+					run.downloaded += 1;
+					run.bytes += bytes;
+				},
+				FileAction::Skipped | FileAction::Deferred => run.skipped += 1,
+				FileAction::Failed | FileAction::Rejected => run.failed += 1,
+				// the transfer of the file is counted on its own
+				FileAction::Archived | FileAction::Conflict => {}
+			}
+			run.id.clone()
+		},
+		None => String::new()
+	};
+	drop(runs);
+
+	append_record(FILES_FILE_NAME, &FileRecord {
+		runId: run_id,
+		taskUuid: task_uuid.to_string(),
+		time: now_millis(),
+		path: sync_task.from.to_string_lossy().to_string(),
+		remotePath: sync_task.to.clone(),
+		action,
+		bytes,
+		error
+	});
+}
+
+/// Close the active run of a task. Runs that are finished without being
+/// cancelled get their outcome from the failure count.
+pub fn finish_run(task_uuid: &str, cancelled: bool) {
+	let mut runs = ACTIVE_RUNS.lock().unwrap();
+	if let Some(mut run) = runs.remove(task_uuid) {
+		drop(runs);
+		run.finishedAt = Some(now_millis());
+		run.outcome = if cancelled {
+			RunOutcome::Cancelled
+		} else if run.failed > 0 {
+			RunOutcome::CompletedWithErrors
+		} else {
+			RunOutcome::Completed
+		};
+		append_record(RUNS_FILE_NAME, &run);
+	}
+}
+
+fn in_range(time: u64, query: &HistoryQuery) -> bool {
+	query.from.map_or(true, |from| time >= from) && query.to.map_or(true, |to| time <= to)
+}
+
+fn status_matches<T: Serialize>(status: &T, query: &HistoryQuery) -> bool {
+	match &query.status {
+		Some(s) => serde_json::to_value(status).ok().and_then(|v| v.as_str().map(|v| v == s)).unwrap_or(false),
+		None => true
+	}
+}
+
+fn query_runs(query: &HistoryQuery) -> Vec<RunRecord> {
+	let mut runs: Vec<RunRecord> = read_runs()
+		.into_iter()
+		.filter(|r| query.taskUuid.as_ref().map_or(true, |u| &r.taskUuid == u))
+		.filter(|r| query.runId.as_ref().map_or(true, |id| &r.id == id))
+		.filter(|r| in_range(r.startedAt, query))
+		.filter(|r| status_matches(&r.outcome, query))
+		.collect();
+
+	// newest first
+	runs.reverse();
+	if let Some(limit) = query.limit {
+		runs.truncate(limit);
+	}
+	runs
+}
+
+fn query_files(query: &HistoryQuery) -> Vec<FileRecord> {
+	let mut files: Vec<FileRecord> = read_records::<FileRecord>(FILES_FILE_NAME)
+		.into_iter()
+		.filter(|f| query.taskUuid.as_ref().map_or(true, |u| &f.taskUuid == u))
+		.filter(|f| query.runId.as_ref().map_or(true, |id| &f.runId == id))
+		.filter(|f| in_range(f.time, query))
+		.filter(|f| status_matches(&f.action, query))
+		.collect();
+
+	files.reverse();
+	if let Some(limit) = query.limit {
+		files.truncate(limit);
+	}
+	files
+}
+
+fn csv_field<T: ToString>(value: T) -> String {
+	let value = value.to_string();
+	if value.contains([',', '"', '\n', '\r']) {
+		format!("\"{}\"", value.replace('"', "\"\""))
+	} else {
+		value
+	}
+}
+
+fn runs_to_csv(runs: &[RunRecord]) -> String {
+	let mut csv = String::from("id,taskUuid,trigger,startedAt,finishedAt,total,uploaded,downloaded,skipped,failed,bytes,outcome\n");
+	for r in runs {
+		let row = [
+			csv_field(&r.id),
+			csv_field(&r.taskUuid),
+			csv_field(&r.trigger),
+			r.startedAt.to_string(),
+			r.finishedAt.map(|t| t.to_string()).unwrap_or_default(),
+			r.total.to_string(),
+			r.uploaded.to_string(),
+			r.downloaded.to_string(),
+			r.skipped.to_string(),
+			r.failed.to_string(),
+			r.bytes.to_string(),
+			format!("{:?}", r.outcome)
+		];
+		csv.push_str(&row.join(","));
+		csv.push('\n');
+	}
+	csv
+}
+
+fn files_to_csv(files: &[FileRecord]) -> String {
+	let mut csv = String::from("runId,taskUuid,time,path,remotePath,action,bytes,error\n");
+	for f in files {
+		let row = [
+			csv_field(&f.runId),
+			csv_field(&f.taskUuid),
+			f.time.to_string(),
+			csv_field(&f.path),
+			csv_field(&f.remotePath),
+			format!("{:?}", f.action),
+			f.bytes.to_string(),
+			csv_field(f.error.clone().unwrap_or_default())
+		];
+		csv.push_str(&row.join(","));
+		csv.push('\n');
+	}
+	csv
+}
+
+/// Run a command that reads the history on a blocking thread, the files
+/// can be large and reading waits for pending writes.
+async fn blocking<F: FnOnce() -> String + Send + 'static>(command: F) -> String {
+	tokio::task::spawn_blocking(command)
+		.await
+		.unwrap_or_else(|e| CommandResponse::<()>::fail(ErrorKind::Unknown, e).to_string())
+}
+
+#[tauri::command]
+pub async fn list_runs(query: HistoryQuery) -> String {
+	blocking(move || CommandResponse::ok(query_runs(&query)).to_string()).await
+}
+
+#[tauri::command]
+pub async fn list_file_activity(query: HistoryQuery) -> String {
+	blocking(move || CommandResponse::ok(query_files(&query)).to_string()).await
+}
+
+/// Export runs or file activity matching `query` to `path`.
+/// `kind` is `runs` or `files`, `format` is `csv` or `json`.
+#[tauri::command]
+pub async fn export_history(query: HistoryQuery, kind: String, format: String, path: String) -> String {
+	blocking(move || export(&query, &kind, &format, &path)).await
+}
+
+fn export(query: &HistoryQuery, kind: &str, format: &str, path: &str) -> String {
+	let content = match (kind, format) {
+		("runs", "csv") => runs_to_csv(&query_runs(query)),
+		("runs", "json") => serde_json::to_string_pretty(&query_runs(query)).unwrap_or_default(),
+		("files", "csv") => files_to_csv(&query_files(query)),
+		("files", "json") => serde_json::to_string_pretty(&query_files(query)).unwrap_or_default(),
+		_ => {
+			return CommandResponse::<()>::err(format!("Unsupported export {} as {}", kind, format)).to_string();
+		}
+	};
+
+	match File::create(path).and_then(|mut f| f.write_all(content.as_bytes())) {
+		Ok(_) => {
+			info!("Export history to {}", path);
+			CommandResponse::empty_ok().to_string()
+		},
+		Err(e) => CommandResponse::<()>::fail(ErrorKind::Io, format!("Failed to export history: {}", e)).to_string()
+	}
+}
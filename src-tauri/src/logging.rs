@@ -0,0 +1,264 @@
+use std::{fs::{read_dir, File}, io::{self, Read, Write}, path::{Path, PathBuf}, str::FromStr, sync::Mutex, time::{Duration, SystemTime}};
+
+use lazy_static::lazy_static;
+use tracing::{info, warn};
+use tracing_appender::{non_blocking::WorkerGuard, rolling::{Builder, Rotation}};
+use tracing_subscriber::{filter::LevelFilter, fmt::{self, MakeWriter}, layer::SubscriberExt, registry::Registry, reload::{self, Handle}, util::SubscriberInitExt};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use crate::{data::{get_config_dir, get_settings_path}, error::ErrorKind, structs::{CommandResponse, Settings}};
+
+const LOGS_DIR_NAME: &str = "logs";
+const LOG_FILE_PREFIX: &str = "synchronizer";
+const MAX_LOG_FILES: usize = 7;
+/// Logs modified within this period go into a diagnostics bundle.
+const DIAGNOSTICS_LOG_AGE: Duration = Duration::from_secs(3 * 24 * 60 * 60);
+
+const REDACTED: &str = "***";
+/// Values following these keys never reach the log files, compared case-insensitively.
+const SENSITIVE_KEYS: [&str; 8] = [
+	"password", "jwt", "accesstoken", "idtoken", "refreshtoken",
+	"authorization", "secret", "passphrase"
+];
+
+lazy_static! {
+	static ref LEVEL_HANDLE: Mutex<Option<Handle<LevelFilter, Registry>>> = Mutex::new(None);
+	static ref LOG_GUARD: Mutex<Option<WorkerGuard>> = Mutex::new(None);
+}
+
+fn get_logs_dir() -> PathBuf {
+	let mut p = get_config_dir();
+	p.push(LOGS_DIR_NAME);
+	p
+}
+
+fn parse_level(level: &str) -> LevelFilter {
+	LevelFilter::from_str(level).unwrap_or(LevelFilter::INFO)
+}
+
+/// Length of the value starting at `start`, a quoted value runs to its closing
+/// quote and a bare one to the next separator.
+fn value_len(bytes: &[u8], start: usize, quoted: bool) -> usize {
+	let mut end = start;
+	while end < bytes.len() {
+		let c = bytes[end];
+		if quoted {
+			if c == b'"' || c == b'\'' || c == b'\\' {
+				break;
+			}
+		} else if c.is_ascii_whitespace() || matches!(c, b',' | b'}' | b']' | b';' | b'&' | b'"' | b'\\') {
+			break;
+		}
+		end += 1;
+	}
+	end - start
+}
+
+/// Mask credentials in a log line. Handles `"key": "value"`, `key=value`,
+/// escaped JSON inside debug output and `Bearer <token>`.
+pub fn redact(line: &str) -> String {
+	let lower = line.to_ascii_lowercase();
+	let bytes = line.as_bytes();
+	let mut out = String::with_capacity(line.len());
+	let mut pos = 0;
+
+	while pos < line.len() {
+		let next = SENSITIVE_KEYS.iter()
+			.map(|k| (k.len(), lower[pos..].find(k)))
+			.chain(std::iter::once((6, lower[pos..].find("bearer"))))
+			.filter_map(|(len, found)| found.map(|i| (pos + i, len)))
+			.min();
+
+		let (key_start, key_len) = match next {
+			Some(n) => n,
+			None => break
+		};
+		let mut i = key_start + key_len;
+		let is_bearer = &lower[key_start..i] == "bearer";
+
+		// find the separator between the key and its value
+		if !is_bearer {
+			// the rest of a key name such as `secretKey` or `password_hash`
+			while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || matches!(bytes[i], b'_' | b'-')) {
+				i += 1;
+			}
+			while i < bytes.len() && matches!(bytes[i], b'"' | b'\'' | b'\\' | b' ') {
+				i += 1;
+			}
+			if i >= bytes.len() || !matches!(bytes[i], b':' | b'=') {
+				out.push_str(&line[pos..key_start + key_len]);
+				pos = key_start + key_len;
+				continue;
+			}
+			i += 1;
+		}
+
+		let mut quoted = false;
+		while i < bytes.len() && matches!(bytes[i], b'"' | b'\'' | b'\\' | b' ') {
+			quoted |= bytes[i] != b' ';
+			i += 1;
+		}
+		// "Authorization: Bearer xxx" is redacted through the bearer rule
+		if !is_bearer && lower[i..].starts_with("bearer") {
+			out.push_str(&line[pos..i]);
+			pos = i;
+			continue;
+		}
+
+		let len = value_len(bytes, i, quoted);
+		out.push_str(&line[pos..i]);
+		if len > 0 {
+			out.push_str(REDACTED);
+		}
+		pos = i + len;
+	}
+
+	out.push_str(&line[pos..]);
+	out
+}
+
+pub struct RedactingWriter<W: Write> {
+	inner: W
+}
+
+impl<W: Write> Write for RedactingWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let text = String::from_utf8_lossy(buf);
+		self.inner.write_all(redact(&text).as_bytes())?;
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+pub struct RedactingMakeWriter<M> {
+	inner: M
+}
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for RedactingMakeWriter<M> {
+	type Writer = RedactingWriter<M::Writer>;
+
+	fn make_writer(&'a self) -> Self::Writer {
+		RedactingWriter { inner: self.inner.make_writer() }
+	}
+}
+
+pub fn init_logging(settings: &Settings) {
+	let (level_layer, level_handle) = reload::Layer::new(parse_level(&settings.logLevel));
+
+	let appender = Builder::new()
+		.rotation(Rotation::DAILY)
+		.filename_prefix(LOG_FILE_PREFIX)
+		.filename_suffix("log")
+		.max_log_files(MAX_LOG_FILES)
+		.build(get_logs_dir());
+
+	let file_layer = match appender {
+		Ok(appender) => {
+			let (writer, guard) = tracing_appender::non_blocking(appender);
+			let mut log_guard = LOG_GUARD.lock().unwrap();
+			*log_guard = Some(guard);
+			Some(fmt::layer()
+				.with_ansi(false)
+				.with_writer(RedactingMakeWriter { inner: writer }))
+		},
+		Err(e) => {
+			eprintln!("Failed to create log file appender: {}", e);
+			None
+		}
+	};
+
+	let stdout_layer = fmt::layer()
+		.with_writer(RedactingMakeWriter { inner: io::stdout });
+
+	let _ = tracing_subscriber::registry()
+		.with(level_layer)
+		.with(file_layer)
+		.with(stdout_layer)
+		.try_init();
+
+	let mut handle = LEVEL_HANDLE.lock().unwrap();
+	*handle = Some(level_handle);
+}
+
+pub fn set_log_level(level: &str) {
+	let handle = LEVEL_HANDLE.lock().unwrap();
+	if let Some(h) = handle.as_ref() {
+		if let Err(e) = h.modify(|filter| *filter = parse_level(level)) {
+			warn!("Failed to change log level: {}", e);
+		}
+	}
+}
+
+/// Stop the background log writer, writing out everything still buffered.
+pub fn flush_logs() {
+	let mut guard = LOG_GUARD.lock().unwrap();
+	guard.take();
+}
+
+fn recent_logs() -> Vec<PathBuf> {
+	let now = SystemTime::now();
+	let entries = match read_dir(get_logs_dir()) {
+		Ok(e) => e,
+		Err(_) => return vec![]
+	};
+
+	entries
+		.filter_map(|e| e.ok())
+		.filter(|e| {
+			e.metadata()
+				.and_then(|m| m.modified())
+				.map(|t| now.duration_since(t).unwrap_or_default() <= DIAGNOSTICS_LOG_AGE)
+				.unwrap_or(false)
+		})
+		.map(|e| e.path())
+		.collect()
+}
+
+fn read_redacted(path: &Path) -> io::Result<Vec<u8>> {
+	let mut content = String::new();
+	File::open(path)?.read_to_string(&mut content)?;
+	Ok(redact(&content).into_bytes())
+}
+
+fn write_diagnostics(path: &str) -> zip::result::ZipResult<()> {
+	let mut zip = ZipWriter::new(File::create(path)?);
+	let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+	zip.start_file("info.txt", options)?;
+	writeln!(zip, "version: {}", env!("CARGO_PKG_VERSION"))?;
+	writeln!(zip, "os: {} {}", std::env::consts::OS, std::env::consts::ARCH)?;
+
+	let settings_path = get_settings_path();
+	if settings_path.exists() {
+		zip.start_file("settings.json", options)?;
+		zip.write_all(&read_redacted(&settings_path)?)?;
+	}
+
+	for log in recent_logs() {
+		if let Some(name) = log.file_name() {
+			zip.start_file(format!("logs/{}", name.to_string_lossy()), options)?;
+			zip.write_all(&read_redacted(&log)?)?;
+		}
+	}
+
+	zip.finish()?;
+	Ok(())
+}
+
+/// Zip recent logs and the settings into `path` for bug reports.
+#[tauri::command]
+pub fn export_diagnostics(path: String) -> String {
+	match write_diagnostics(&path) {
+		Ok(_) => {
+			info!("Export diagnostics to {}", &path);
+			CommandResponse::empty_ok().to_string()
+		},
+		Err(e) => {
+			warn!("Failed to export diagnostics: {}", e);
+			CommandResponse::<()>::fail(ErrorKind::Io, format!("Failed to export diagnostics: {}", e)).to_string()
+		}
+	}
+}
@@ -7,21 +7,39 @@ mod structs;
 mod etag;
 mod data;
 mod error;
-
-use data::{get_saved_settings, save_settings};
-use error::{get_errors, pop_error};
+mod events;
+mod progress;
+mod history;
+mod logging;
+mod tray;
+mod state;
+mod snapshot;
+mod meta;
+mod scan;
+mod names;
+mod backend;
+mod pool;
+mod crypto;
+mod archive;
+mod placeholder;
+
+#[cfg(test)]
+mod tests;
+
+use data::{get_saved_settings, save_settings, set_tasks};
+use error::{acknowledge_all_errors, acknowledge_error, list_errors, retry_error};
+use history::{compact_history, export_history, list_file_activity, list_runs};
+use logging::{export_diagnostics, init_logging};
+use placeholder::hydrate;
+use pool::transfer_queue;
 use net::*;
-use structs::{parse_json, Settings};
-use tauri::{
-    AppHandle, CustomMenuItem, Manager, RunEvent, SystemTray,
-    SystemTrayEvent, SystemTrayMenu, SystemTrayMenuItem, WindowEvent,
-};
-
-#[cfg(target_os="macos")]
-const ACCELERATOR_PREFIX: &str = "Command";
+use structs::{parse_json, Settings, TaskData};
+use std::time::Duration;
+use tauri::{AppHandle, Manager, RunEvent, WindowEvent};
+use tray::{build_tray, refresh_tray, tray_event};
 
-#[cfg(not(target_os="macos"))]
-const ACCELERATOR_PREFIX: &str = "Ctrl";
+/// Stopping the tasks may take up to 30s, flushing the history another 5s.
+const EXIT_TIMEOUT: Duration = Duration::from_secs(35);
 
 fn main_loop(app_handle: &AppHandle, event: RunEvent) {
     match event {
@@ -44,44 +62,25 @@ fn main_loop(app_handle: &AppHandle, event: RunEvent) {
             }
             _ => {}
         },
-        _ => {}
-    }
-}
-
-fn build_tray() -> SystemTray {
-    let tray_menu = SystemTrayMenu::new()
-        .add_item(CustomMenuItem::new("show".to_string(), "Show Window").accelerator(format!("{}+S", ACCELERATOR_PREFIX)))
-        .add_native_item(SystemTrayMenuItem::Separator)
-        .add_item(CustomMenuItem::new("quit".to_string(), "Quit").accelerator(format!("{}+Q", ACCELERATOR_PREFIX)));
-
-    SystemTray::new().with_menu(tray_menu)
-}
-
-fn tray_event(app_handle: &AppHandle, event: SystemTrayEvent) {
-    match event {
-        SystemTrayEvent::MenuItemClick { id, .. } => match id.as_str() {
-            "quit" => {
+        // quitting from the OS, stop the tasks first like the tray does
+        RunEvent::ExitRequested { api, .. } => {
+            api.prevent_exit();
+            tokio::spawn(async {
+                shutdown().await;
                 std::process::exit(0);
-            }
-            "show" => {
-                let window = app_handle.get_window("main").unwrap();
-
-                #[cfg(target_os = "macos")]
-                tauri::AppHandle::show(&window.app_handle()).unwrap();
-
-                #[cfg(not(target_os = "macos"))]
-                window.show().unwrap();
-
-                window.set_focus().unwrap();
-            }
-            _ => {}
-        },
-        #[cfg(target_os="windows")]
-        SystemTrayEvent::LeftClick { .. } => {
-            let window = app_handle.get_window("main").unwrap();
-            window.show().unwrap();
-            window.set_focus().unwrap();
-        },
+            });
+        }
+        // the event loop is going away and can not be held, so the shutdown
+        // runs on its own thread while this one waits for it
+        RunEvent::Exit => {
+            let runtime = tokio::runtime::Handle::current();
+            let (done, finished) = std::sync::mpsc::channel();
+            std::thread::spawn(move || {
+                runtime.block_on(shutdown());
+                let _ = done.send(());
+            });
+            let _ = finished.recv_timeout(EXIT_TIMEOUT);
+        }
         _ => {}
     }
 }
@@ -89,15 +88,26 @@ fn tray_event(app_handle: &AppHandle, event: SystemTrayEvent) {
 #[tokio::main]
 async fn main() {
     let settings = get_saved_settings();
+    init_logging(&settings);
+    compact_history();
 
     let mut builder = tauri::Builder::default()
         .setup(|app| {
+            events::set_app_handle(app.handle());
+
             app.listen_global("update-settings", |event| {
                 let data = event.payload().unwrap();
                 let new_settings: Settings = parse_json(data);
                 save_settings(new_settings);
             });
 
+            app.listen_global("update-tasks", |event| {
+                let data = event.payload().unwrap();
+                let tasks: Vec<TaskData> = parse_json(data);
+                set_tasks(tasks);
+                refresh_tray();
+            });
+
             #[cfg(target_os="macos")]
             app.set_activation_policy(tauri::ActivationPolicy::Accessory);
 
@@ -106,6 +116,7 @@ async fn main() {
         .invoke_handler(tauri::generate_handler![
             connect,
             list,
+            list_workspaces,
             login,
             sync,
             pause,
@@ -114,8 +125,18 @@ async fn main() {
             get_password,
             set_username,
             set_password,
-            get_errors,
-            pop_error
+            set_encryption_passphrase,
+            set_s3_secret_key,
+            list_errors,
+            acknowledge_error,
+            acknowledge_all_errors,
+            retry_error,
+            list_runs,
+            list_file_activity,
+            export_history,
+            export_diagnostics,
+            transfer_queue,
+            hydrate
         ]);
 
     if settings.showTrayIcon {
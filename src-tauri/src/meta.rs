@@ -0,0 +1,129 @@
+use std::{collections::HashMap, fs::{metadata, remove_file, symlink_metadata, Metadata}, io, path::Path, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use filetime::{set_file_mtime, FileTime};
+use tracing::warn;
+
+/// Object metadata keys, stored by S3 as `x-amz-meta-*` headers.
+const MTIME_KEY: &str = "mtime";
+const MODE_KEY: &str = "mode";
+const UID_KEY: &str = "uid";
+const GID_KEY: &str = "gid";
+/// Marks an object as a symlink descriptor, the value is the link target
+const SYMLINK_KEY: &str = "symlink";
+/// Only permissions are restored, setuid, setgid and sticky bits from the
+/// server are not to be trusted.
+#[cfg(unix)]
+const PERMISSION_BITS: u32 = 0o777;
+
+/// Object metadata describing a local file. The mtime is written as seconds
+/// since the epoch with a fractional part, the format rclone uses as well.
+pub fn local_metadata(path: &Path, posix: bool) -> HashMap<String, String> {
+	let mut meta = HashMap::new();
+	let m = match metadata(path) {
+		Ok(m) => m,
+		Err(_) => return meta
+	};
+
+	if let Ok(d) = m.modified().map(|t| t.duration_since(UNIX_EPOCH).unwrap_or_default()) {
+		meta.insert(MTIME_KEY.to_string(), format!("{}.{:09}", d.as_secs(), d.subsec_nanos()));
+	}
+	if posix {
+		insert_posix(&mut meta, &m);
+	}
+	meta
+}
+
+#[cfg(unix)]
+fn insert_posix(meta: &mut HashMap<String, String>, m: &Metadata) {
+	use std::os::unix::fs::MetadataExt;
+	meta.insert(MODE_KEY.to_string(), format!("{:o}", m.mode() & PERMISSION_BITS));
+	meta.insert(UID_KEY.to_string(), m.uid().to_string());
+	meta.insert(GID_KEY.to_string(), m.gid().to_string());
+}
+
+#[cfg(not(unix))]
+fn insert_posix(_meta: &mut HashMap<String, String>, _m: &Metadata) {}
+
+/// Modification time stored by `local_metadata`, if any.
+pub fn parse_mtime(meta: &HashMap<String, String>) -> Option<SystemTime> {
+	let value = meta.get(MTIME_KEY)?;
+	let (secs, frac) = value.split_once('.').unwrap_or((value, "0"));
+	let secs: u64 = secs.parse().ok()?;
+	let frac = &frac[..frac.len().min(9)];
+	let nanos: u32 = format!("{:0<9}", frac).parse().ok()?;
+	Some(UNIX_EPOCH + Duration::new(secs, nanos))
+}
+
+/// Restore what `local_metadata` stored on a downloaded file. The owner is
+/// only restored when `owner` is set and the process runs as root.
+pub fn apply_metadata(path: &Path, meta: &HashMap<String, String>, posix: bool, owner: bool) {
+	if let Some(mtime) = parse_mtime(meta) {
+		if let Err(e) = set_file_mtime(path, FileTime::from_system_time(mtime)) {
+			warn!("Failed to set modification time of {:?}: {}", path, e);
+		}
+	}
+	if posix {
+		apply_posix(path, meta, owner);
+	}
+}
+
+#[cfg(unix)]
+fn apply_posix(path: &Path, meta: &HashMap<String, String>, owner: bool) {
+	use std::{fs::{set_permissions, Permissions}, os::unix::fs::{chown, PermissionsExt}};
+	use tracing::debug;
+
+	if let Some(mode) = meta.get(MODE_KEY).and_then(|m| u32::from_str_radix(m, 8).ok()) {
+		if let Err(e) = set_permissions(path, Permissions::from_mode(mode & PERMISSION_BITS)) {
+			warn!("Failed to set mode of {:?}: {}", path, e);
+		}
+	}
+
+	if !owner || unsafe { libc::geteuid() } != 0 {
+		return;
+	}
+	let uid = meta.get(UID_KEY).and_then(|v| v.parse().ok());
+	let gid = meta.get(GID_KEY).and_then(|v| v.parse().ok());
+	if uid.is_some() || gid.is_some() {
+		if let Err(e) = chown(path, uid, gid) {
+			debug!("Can not change owner of {:?}: {}", path, e);
+		}
+	}
+}
+
+#[cfg(not(unix))]
+fn apply_posix(_path: &Path, _meta: &HashMap<String, String>, _owner: bool) {}
+
+pub fn link_metadata(target: &str) -> HashMap<String, String> {
+	HashMap::from([(SYMLINK_KEY.to_string(), target.to_string())])
+}
+
+/// Target of a symlink descriptor object, `None` for regular objects.
+pub fn link_target(meta: &HashMap<String, String>) -> Option<String> {
+	meta.get(SYMLINK_KEY).cloned()
+}
+
+/// Replace whatever is at `path` with a symlink to `target`.
+pub fn create_link(path: &Path, target: &Path) -> io::Result<()> {
+	if symlink_metadata(path).is_ok() {
+		remove_file(path)?;
+	}
+	if let Some(parent) = path.parent() {
+		std::fs::create_dir_all(parent)?;
+	}
+
+	symlink(target, path)
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+	std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+	if path.parent().map(|p| p.join(target).is_dir()).unwrap_or(false) {
+		std::os::windows::fs::symlink_dir(target, path)
+	} else {
+		std::os::windows::fs::symlink_file(target, path)
+	}
+}
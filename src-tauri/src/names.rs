@@ -0,0 +1,188 @@
+use std::{collections::HashMap, path::{is_separator, Component, Path, PathBuf}};
+
+use unicode_normalization::UnicodeNormalization;
+
+use crate::structs::{InvalidNamePolicy, NameNormalization};
+
+/// Characters the server refuses in a name, besides control characters.
+const INVALID_CHARS: [char; 1] = ['\\'];
+/// Longest name in bytes most file systems and the server accept.
+const MAX_NAME_BYTES: usize = 255;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NameError {
+	/// The name is not valid UTF-8, the lossy form is kept for messages
+	NotUtf8(String),
+	/// The server would reject the name, with the reason why
+	Invalid(String, &'static str)
+}
+
+impl std::fmt::Display for NameError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::NotUtf8(name) => write!(f, "{:?} is not valid UTF-8", name),
+			Self::Invalid(name, reason) => write!(f, "{:?} {}", name, reason)
+		}
+	}
+}
+
+/// Turns local paths into remote keys the same way on every platform.
+#[derive(Clone)]
+pub struct NameRules {
+	pub normalization: NameNormalization,
+	pub invalid: InvalidNamePolicy
+}
+
+impl NameRules {
+	pub fn new(normalization: NameNormalization, invalid: InvalidNamePolicy) -> Self {
+		Self { normalization, invalid }
+	}
+
+	pub fn normalize(&self, name: &str) -> String {
+		match self.normalization {
+			NameNormalization::None => name.to_string(),
+			NameNormalization::Nfc => name.nfc().collect(),
+			NameNormalization::Nfd => name.nfd().collect()
+		}
+	}
+
+	/// Remote key of `path` below `root`, joined with `/` whatever the platform.
+	pub fn relative_key(&self, root: &Path, path: &Path) -> Result<String, NameError> {
+		let relative = path.strip_prefix(root).unwrap_or(path);
+		let mut names = vec![];
+		for component in relative.components() {
+			if let Component::Normal(name) = component {
+				let name = name.to_str().ok_or_else(|| NameError::NotUtf8(name.to_string_lossy().to_string()))?;
+				names.push(self.check(&self.normalize(name))?);
+			}
+		}
+		Ok(names.join("/"))
+	}
+
+	/// The name itself if the server accepts it, refused otherwise. When
+	/// escaping, `%` is escaped in every name so that no valid name maps to
+	/// the key of an escaped one.
+	pub fn check(&self, name: &str) -> Result<String, NameError> {
+		match (invalid_reason(name), self.invalid) {
+			(_, InvalidNamePolicy::Escape) if name.len() <= MAX_NAME_BYTES => {
+				// every escaped character takes three bytes
+				let escaped = escape(name);
+				if escaped.len() > MAX_NAME_BYTES {
+					return Err(NameError::Invalid(name.to_string(), "is too long once escaped"));
+				}
+				Ok(escaped)
+			},
+			(None, _) => Ok(name.to_string()),
+			(Some(reason), _) => Err(NameError::Invalid(name.to_string(), reason))
+		}
+	}
+
+	/// Local name of a remote one, the reverse of `check`.
+	pub fn local_name(&self, name: &str) -> String {
+		match self.invalid {
+			InvalidNamePolicy::Escape => unescape(name),
+			_ => name.to_string()
+		}
+	}
+
+	/// Local path of `relative`, a remote key below the task root. Names
+	/// that would leave `root` are dropped.
+	pub fn local_path(&self, root: &Path, relative: &str) -> PathBuf {
+		let mut path = root.to_path_buf();
+		path.extend(
+			relative
+				.split('/')
+				.filter(|n| !n.is_empty() && *n != "." && *n != "..")
+				.map(|n| self.local_name(n))
+		);
+		path
+	}
+}
+
+/// Why the server would reject `name`, if it would.
+pub fn invalid_reason(name: &str) -> Option<&'static str> {
+	if name == "." || name == ".." {
+		Some("is reserved")
+	} else if name.len() > MAX_NAME_BYTES {
+		Some("is too long")
+	} else if name.chars().any(|c| c.is_control() || INVALID_CHARS.contains(&c)) {
+		Some("contains characters the server does not accept")
+	} else if name.ends_with(' ') {
+		Some("ends with a space")
+	} else {
+		None
+	}
+}
+
+fn escape(name: &str) -> String {
+	let trailing = name.len() - name.trim_end_matches(' ').len();
+	let mut escaped = String::with_capacity(name.len());
+	for c in name[..name.len() - trailing].chars() {
+		if c.is_control() || INVALID_CHARS.contains(&c) || c == '%' {
+			let mut buf = [0; 4];
+			for b in c.encode_utf8(&mut buf).bytes() {
+				escaped.push_str(&format!("%{:02X}", b));
+			}
+		} else {
+			escaped.push(c);
+		}
+	}
+	escaped.push_str(&"%20".repeat(trailing));
+	match name {
+		"." => "%2E".to_string(),
+		".." => "%2E%2E".to_string(),
+		_ => escaped
+	}
+}
+
+/// Decode what `escape` encoded. Names that do not decode to a single valid
+/// name are kept as they are.
+fn unescape(name: &str) -> String {
+	let mut bytes = Vec::with_capacity(name.len());
+	let mut rest = name.as_bytes();
+	while let Some((&b, tail)) = rest.split_first() {
+		let decoded = match tail {
+			[h, l, ..] if b == b'%' => match ((*h as char).to_digit(16), (*l as char).to_digit(16)) {
+				(Some(h), Some(l)) => Some((h * 16 + l) as u8),
+				_ => None
+			},
+			_ => None
+		};
+		match decoded {
+			Some(d) => {
+				bytes.push(d);
+				rest = &tail[2..];
+			},
+			None => {
+				bytes.push(b);
+				rest = tail;
+			}
+		}
+	}
+	match String::from_utf8(bytes) {
+		Ok(n) if n != "." && n != ".." && !n.chars().any(is_separator) => n,
+		_ => name.to_string()
+	}
+}
+
+/// Remembers keys by their lowercase form to find names that only differ in
+/// case, which end up as the same file on Windows and macOS.
+#[derive(Default)]
+pub struct CaseCollisions {
+	seen: HashMap<String, String>
+}
+
+impl CaseCollisions {
+	/// The key that was added before and only differs from `key` in case.
+	pub fn insert(&mut self, key: &str) -> Option<String> {
+		let folded = key.to_lowercase();
+		match self.seen.get(&folded) {
+			Some(existing) if existing != key => Some(existing.clone()),
+			Some(_) => None,
+			None => {
+				self.seen.insert(folded, key.to_string());
+				None
+			}
+		}
+	}
+}
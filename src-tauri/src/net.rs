@@ -1,28 +1,53 @@
-use std::{collections::{HashMap, VecDeque}, path::PathBuf, str::FromStr, sync::Mutex, thread::sleep, time::Duration};
+use std::{collections::{HashMap, HashSet, VecDeque}, fs::{create_dir_all, read_link, remove_file, rename}, path::{Path, PathBuf}, str::FromStr, sync::{Arc, Mutex}, time::Duration};
 
 use aws_config::{AppName, BehaviorVersion, Region, SdkConfig};
-use aws_sdk_s3::{config::{Credentials, SharedCredentialsProvider}, error::SdkError, primitives::ByteStream, Client};
+use aws_sdk_s3::{config::{Credentials, SharedCredentialsProvider}, primitives::ByteStream, Client};
+use chrono::{DateTime, Local};
 use keyring::Entry;
 use lazy_static::lazy_static;
 use serde_json::json;
 use surf::StatusCode;
-use tokio::{sync::Semaphore, task::JoinHandle};
-use walkdir::WalkDir;
+use tokio::{fs::File, io::AsyncWriteExt, sync::mpsc, task::{JoinHandle, JoinSet}};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
 
-use crate::{data::get_saved_settings, error::add_error, etag::calculate_etag, structs::{parse_json, BulkMetaData, BulkNode, CommandResponse, SessionData, SyncTask, TaskData, TaskProgress, UserData}};
+use crate::{archive::Archiver, backend::{backend_for, prune_versions, task_backend, RemoteBackend, RemoteFile}, data::{get_saved_settings, get_task, get_tasks}, error::{add_error, ErrorKind, SyncError}, etag::{content_md5, md5_file, md5_of}, history::{add_to_run, finish_run, flush_history, record_file, start_run}, logging::{flush_logs, redact}, meta::{apply_metadata, create_link, link_metadata, link_target, local_metadata}, scan::{EntryKind, Scanner}, snapshot::{FileStamp, Snapshot}, events::{emit, FILE_STARTED, TASK_FINISHED, TASK_STARTED}, progress::{counting_body, current_progress, file_done, file_failed, file_queued, file_received, file_skipped, new_progress, remove_progress}, names::{CaseCollisions, NameRules}, placeholder::{is_placeholder, is_selected, read_placeholder, remove_placeholder, write_placeholder}, pool::{acquire, set_limits}, structs::{parse_json, BackendConfig, BackupConfig, BulkMetaData, BulkNode, CommandResponse, FileAction, FileEvent, Placeholder, SessionData, Settings, SymlinkPolicy, SyncDirection, SyncTask, TaskData, TaskEvent, UserData, Workspace}, state::{clear_pending, flush_state, get_synced_etag, get_task_state, save_pending, set_synced_etag}, tray::refresh_tray};
 
 pub const PACKAGE_NAME: &str = "com.iewnfod.pydio.cells.synchronizer";
 const USERNAME_KEY: &str = "username";
 const PASSWORD_KEY: &str = "password";
-
-const BUCKET_NAME: &str = "io";
+const ENCRYPTION_KEY: &str = "encryption";
+const S3_SECRET_KEY: &str = "s3-secret";
+
+/// How long pausing or quitting waits for files that are already uploading.
+const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+/// Downloads are written next to their destination under this suffix and renamed when complete.
+const PART_SUFFIX: &str = ".cellspart";
+/// Files the walk may find ahead of the workers before it waits for them.
+const SCAN_QUEUE_SIZE: usize = 1024;
+/// Files of one run waiting in the transfer pool or being transferred.
+const POOL_QUEUE_SIZE: usize = 1000;
+/// Attempts at a file within one run, failed files wait twice as long as before each time.
+const MAX_ATTEMPTS: u32 = 5;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
 
 static mut ENDPOINT: String = String::new();
 
+pub struct SyncHandler {
+	handle: JoinHandle<()>,
+	token: CancellationToken,
+	/// Kept until the task has stopped, so no second run starts meanwhile
+	stopping: bool
+}
+
 lazy_static! {
 	pub static ref SESSION: Mutex<SessionData> = Mutex::new(SessionData::default());
-	pub static ref SYNC_HANDLERS: Mutex<HashMap<String, JoinHandle<()>>> = Mutex::new(HashMap::new());
-	pub static ref SYNC_PROGRESS: Mutex<HashMap<String, TaskProgress>> = Mutex::new(HashMap::new());
+	pub static ref SYNC_HANDLERS: Mutex<HashMap<String, SyncHandler>> = Mutex::new(HashMap::new());
+	static ref PAUSED_UNTIL: Mutex<Option<DateTime<Local>>> = Mutex::new(None);
+	// one connection pool for every REST call
+	static ref HTTP_CLIENT: surf::Client = surf::Client::new();
+	// the client of the current session, keyed by what it was built from
+	static ref S3_CLIENT: Mutex<Option<(String, Client)>> = Mutex::new(None);
 }
 
 fn get_endpoint() -> String {
@@ -70,28 +95,52 @@ fn _set_key(key: &str, value: &str) {
 #[tauri::command]
 pub fn get_username() -> String {
 	let u = _get_key(USERNAME_KEY);
-	println!("Get username successfully");
+	debug!("Get username successfully");
 	u
 }
 
 #[tauri::command]
 pub fn get_password() -> String {
 	let p = _get_key(PASSWORD_KEY);
-	println!("Get password successfully");
+	debug!("Get password successfully");
 	p
 }
 
 #[tauri::command]
 pub fn set_username(value: String) -> String {
 	_set_key(USERNAME_KEY, &value);
-	println!("Save username successfully");
+	debug!("Save username successfully");
 	CommandResponse::empty_ok().to_string()
 }
 
 #[tauri::command]
 pub fn set_password(value: String) -> String {
 	_set_key(PASSWORD_KEY, &value);
-	println!("Save password successfully");
+	debug!("Save password successfully");
+	CommandResponse::empty_ok().to_string()
+}
+
+pub fn get_encryption_passphrase(uuid: &str) -> String {
+	_get_key(&format!("{}-{}", ENCRYPTION_KEY, uuid))
+}
+
+/// Files already encrypted with another passphrase can no longer be read.
+#[tauri::command]
+pub fn set_encryption_passphrase(uuid: String, value: String) -> String {
+	_set_key(&format!("{}-{}", ENCRYPTION_KEY, &uuid), &value);
+	debug!("Save encryption passphrase of task {}", &uuid);
+	CommandResponse::empty_ok().to_string()
+}
+
+pub fn get_s3_secret_key(uuid: &str) -> String {
+	_get_key(&format!("{}-{}", S3_SECRET_KEY, uuid))
+}
+
+/// The secret key of an S3 task, the task itself is saved without it.
+#[tauri::command]
+pub fn set_s3_secret_key(uuid: String, value: String) -> String {
+	_set_key(&format!("{}-{}", S3_SECRET_KEY, &uuid), &value);
+	debug!("Save S3 secret key of task {}", &uuid);
 	CommandResponse::empty_ok().to_string()
 }
 
@@ -114,11 +163,11 @@ async fn solve_res(res: Result<surf::Response, surf::Error>) -> Result<surf::Res
 	}
 }
 
-async fn post<T: ToString>(api: T, data: String) -> Result<surf::Response, surf::Error> {
+pub async fn post<T: ToString>(api: T, data: String) -> Result<surf::Response, surf::Error> {
 	let endpoint = get_endpoint();
 
-	println!("Posting {}{} with body: {}", &endpoint, api.to_string(), &data);
-	let res = surf::post(format!("{}{}", &endpoint, api.to_string()))
+	debug!("Posting {}{} with body: {}", &endpoint, api.to_string(), redact(&data));
+	let res = HTTP_CLIENT.post(format!("{}{}", &endpoint, api.to_string()))
 		.body(data)
 		.header("Authorization", format!("Bearer {}", get_jwt()))
 		.send().await;
@@ -127,9 +176,9 @@ async fn post<T: ToString>(api: T, data: String) -> Result<surf::Response, surf:
 }
 
 async fn post_without_bearer<T: ToString>(api: T, data: String) -> Result<surf::Response, surf::Error> {
-	println!("Posting {}{} without bearer with body: {}", get_endpoint(), api.to_string(), &data);
+	debug!("Posting {}{} anonymously with body: {}", get_endpoint(), api.to_string(), redact(&data));
 
-	surf::post(format!("{}{}", &get_endpoint(), api.to_string()))
+	HTTP_CLIENT.post(format!("{}{}", &get_endpoint(), api.to_string()))
 		.body(data)
 		.send().await
 }
@@ -137,8 +186,8 @@ async fn post_without_bearer<T: ToString>(api: T, data: String) -> Result<surf::
 async fn get<T: ToString>(api: T) -> Result<surf::Response, surf::Error> {
 	let endpoint = get_endpoint();
 
-	println!("Getting {}{}", &endpoint, api.to_string());
-	let res = surf::get(format!("{}{}", &endpoint, api.to_string()))
+	debug!("Getting {}{}", &endpoint, api.to_string());
+	let res = HTTP_CLIENT.get(format!("{}{}", &endpoint, api.to_string()))
 		.header("Authorization", format!("Bearer {}", get_jwt()))
 		.send().await;
 
@@ -160,7 +209,8 @@ pub async fn connect(endpoint: String, username: String) -> String {
 
 		CommandResponse::ok(data).to_string()
 	} else {
-		CommandResponse::<UserData>::err(
+		CommandResponse::<UserData>::fail(
+			ErrorKind::Network,
 			res.err().unwrap().to_string()
 		).to_string()
 	}
@@ -189,12 +239,69 @@ pub async fn list(p: String) -> String {
 
 		CommandResponse::ok(data).to_string()
 	} else {
-		CommandResponse::<Vec<BulkMetaData>>::err(
+		CommandResponse::<Vec<BulkMetaData>>::fail(
+			ErrorKind::Network,
 			res.err().unwrap().to_string()
 		).to_string()
 	}
 }
 
+async fn workspaces() -> Result<Vec<Workspace>, SyncError> {
+	let mut res = post(
+		"/a/meta/bulk/get",
+		json!({
+			"NodePaths": ["/*"]
+		}).to_string()
+	).await.map_err(|e| SyncError::new(ErrorKind::Network, e))?;
+	let t = res.body_string().await.map_err(|e| SyncError::new(ErrorKind::Network, e))?;
+	let data: BulkMetaData = parse_json(&t);
+	Ok(data.Nodes.iter().map(|n| n.workspace()).collect())
+}
+
+/// Workspaces of the logged in user, with what they let the user do.
+#[tauri::command]
+pub async fn list_workspaces() -> String {
+	match workspaces().await {
+		Ok(w) => CommandResponse::ok(w).to_string(),
+		Err(e) => CommandResponse::<Vec<Workspace>>::fail(e.kind, e.message).to_string()
+	}
+}
+
+/// Refuse tasks the workspace of `remote_path` does not let sync in
+/// `direction`. Tasks still run when the workspaces cannot be listed.
+async fn check_workspace(remote_path: &str, direction: SyncDirection) -> Result<(), SyncError> {
+	let slug = remote_path.trim_start_matches('/').split('/').next().unwrap_or_default();
+	let workspace = match workspaces().await {
+		Ok(w) => match w.into_iter().find(|w| w.slug == slug) {
+			Some(w) => w,
+			None => return Ok(())
+		},
+		Err(e) => {
+			warn!("Could not check the workspace of {}: {}", remote_path, e);
+			return Ok(());
+		}
+	};
+	if !workspace.syncable {
+		return Err(SyncError::new(
+			ErrorKind::Permission,
+			format!("Workspace {} does not allow syncing", &workspace.label)
+		));
+	}
+	let allowed = match direction {
+		SyncDirection::Upload => workspace.writable,
+		SyncDirection::Download => workspace.readable,
+		SyncDirection::Both => workspace.readable && workspace.writable
+	};
+	if !allowed {
+		return Err(SyncError::new(
+			ErrorKind::Permission,
+			format!("Workspace {} is {}, it cannot be synced in direction {:?}", &workspace.label,
+				if workspace.writable { "write-only" } else { "read-only" }, direction)
+		));
+	}
+	Ok(())
+}
+
 #[tauri::command]
 pub async fn login(endpoint: String, username: String, password: String) -> String {
 	unsafe {
@@ -218,7 +325,8 @@ pub async fn login(endpoint: String, username: String, password: String) -> Stri
 	if res.is_ok() {
 		let t = res.unwrap().body_string().await.unwrap();
 		if t.contains("Login failed") {
-			return CommandResponse::<SessionData>::err(
+			return CommandResponse::<SessionData>::fail(
+				ErrorKind::Auth,
 				"Login failed"
 			).to_string();
 		}
@@ -227,294 +335,1147 @@ pub async fn login(endpoint: String, username: String, password: String) -> Stri
 		let mut session = SESSION.lock().unwrap();
 		*session = data.clone();
 
-		println!("Login Successfully");
+		info!("Login Successfully");
 		CommandResponse::ok(data).to_string()
 	} else {
-		CommandResponse::<SessionData>::err(
+		CommandResponse::<SessionData>::fail(
+			ErrorKind::Network,
 			res.err().unwrap().to_string()
 		).to_string()
 	}
 }
 
 #[tauri::command]
-pub async fn sync(task: TaskData, ignores: Vec<String>) -> String {
-	let handlers = SYNC_HANDLERS.lock().unwrap();
-	for (uuid, handler) in handlers.iter() {
-		if uuid == &task.uuid {
-			if !handler.is_finished() {
-				println!("Task {} is already running", uuid);
-				return CommandResponse::empty_err().to_string();
-			} else {
-				pause(uuid.clone());
-				break;
-			}
+pub async fn sync(task: TaskData, ignores: Vec<String>, trigger: Option<String>) -> String {
+	let mut handlers = SYNC_HANDLERS.lock().unwrap();
+	if let Some(handler) = handlers.get(&task.uuid) {
+		if handler.stopping {
+			info!("Task {} is still stopping", &task.uuid);
+			return CommandResponse::empty_err().to_string();
+		}
+		if !handler.handle.is_finished() {
+			info!("Task {} is already running", &task.uuid);
+			return CommandResponse::empty_err().to_string();
 		}
+		handlers.remove(&task.uuid);
 	}
 	drop(handlers);
 
+	if let Some(until) = paused_until() {
+		info!("Skip task {}, all tasks are paused until {}", &task.uuid, until);
+		return CommandResponse::empty_ok().to_string();
+	}
+
+	if !task.paused && task.backend == BackendConfig::Cells {
+		if let Err(err) = check_workspace(&task.remoteDir.Path, task.direction).await {
+			let err = err.task(&task.uuid);
+			warn!("Skip task {}: {}", &task.uuid, &err);
+			let res = CommandResponse::<()>::fail(err.kind, &err.message).to_string();
+			add_error(err);
+			return res;
+		}
+	}
+
 	if !task.paused {
 		let all_ignores = [&ignores.clone()[..], &task.ignores[..]].concat();
 		let uuid = task.uuid.clone();
-		println!("Sync Task {:?}", &task);
-		let handler = tokio::spawn(async move {
+		let trigger = trigger.unwrap_or_else(|| "manual".to_string());
+		info!("Sync Task {:?}", &task);
+		let token = CancellationToken::new();
+		let child_token = token.clone();
+		let backend = task_backend(&task);
+		let archiver = Archiver::new(&task);
+		set_limits(&task.uuid, task.priority, task.maxTransfers);
+		let handle = tokio::spawn(async move {
 			_sync(
+				backend,
 				task.localDir,
 				task.remoteDir,
 				all_ignores,
-				uuid
+				uuid,
+				trigger,
+				task.direction,
+				task.symlinkPolicy,
+				task.backup,
+				archiver,
+				task.selectedFolders,
+				child_token
 			).await;
 		});
 		let mut handlers = SYNC_HANDLERS.lock().unwrap();
-		handlers.insert(task.uuid.clone(), handler);
+		handlers.insert(task.uuid.clone(), SyncHandler { handle, token, stopping: false });
+		drop(handlers);
+		refresh_tray();
 	}
 
 	CommandResponse::empty_ok().to_string()
 }
 
 async fn _sync(
-	local: String, remote: BulkNode, ignores: Vec<String>, uuid: String
+	backend: Arc<dyn RemoteBackend>, local: String, remote: BulkNode, ignores: Vec<String>, uuid: String, trigger: String,
+	direction: SyncDirection, symlink_policy: SymlinkPolicy, backup: BackupConfig, archiver: Option<Archiver>,
+	selected: Vec<String>, token: CancellationToken
 ) {
 	let remote_path = remote.Path;
 	let local_path = PathBuf::from_str(&local).unwrap();
 	if !local_path.exists() {
-		return ;
+		if direction == SyncDirection::Upload || create_dir_all(&local_path).is_err() {
+			finish_task(&uuid);
+			return ;
+		}
 	}
 
-	let settings = get_saved_settings();
+	// read once, every file of the run needs them
+	let settings = Arc::new(get_saved_settings());
+	let rules = NameRules::new(settings.nameNormalization, settings.invalidNames);
+
+	// listed first, so local files can be matched as soon as the walk finds them
+	let mut remote_files = if direction != SyncDirection::Upload {
+		match backend.list(&format!("{}/", &remote_path)).await {
+			Ok(f) => f,
+			Err(err) => {
+				let err = err.task(&uuid);
+				warn!("Failed listing {}: {}", &remote_path, &err);
+				add_error(err);
+				finish_task(&uuid);
+				return;
+			}
+		}
+	} else {
+		vec![]
+	};
+	// matched by normalized name, so a file uploaded from a system using
+	// the other unicode form is not duplicated
+	let remote_by_name: HashMap<String, RemoteFile> = remote_files.iter().map(|f| (rules.normalize(&f.key), f.clone())).collect();
+
+	// files a stopped run did not get to go first
+	let mut sync_tasks: VecDeque<SyncTask> = get_task_state(&uuid)
+		.pending
+		.into_iter()
+		.filter(|t| match t.direction {
+			SyncDirection::Download => remote_by_name.contains_key(&rules.normalize(&t.to)),
+			_ => t.from.symlink_metadata().is_ok()
+		})
+		.collect();
+	if !sync_tasks.is_empty() {
+		info!("Resume {} unfinished files of task {}", sync_tasks.len(), &uuid);
+	}
+	let resumed: HashSet<PathBuf> = sync_tasks.iter().map(|t| t.from.clone()).collect();
+	let resumed_keys: Vec<String> = sync_tasks.iter().map(|t| t.to.clone()).collect();
+
+	let progress = new_progress(&uuid, 0, 0);
+	start_run(&uuid, &trigger, 0);
+	emit(TASK_STARTED, TaskEvent { uuid: uuid.clone(), progress });
+	for t in &sync_tasks {
+		queue_file(&uuid, t);
+	}
+
+	// the walk runs on the blocking pool and hands files over while it goes
+	let (tx, mut rx) = mpsc::channel::<SyncTask>(SCAN_QUEUE_SIZE);
+	let scan = {
+		let uuid = uuid.clone();
+		let local_path = local_path.clone();
+		let remote_path = remote_path.clone();
+		let ignores = ignores.clone();
+		let rules = rules.clone();
+		let token = token.clone();
+		tokio::task::spawn_blocking(move || {
+			let mut scanner = Scanner::new(symlink_policy);
+			let walk = scanner.walk(&local_path);
+			let mut collisions = CaseCollisions::default();
+			let mut matched: HashSet<String> = HashSet::new();
+			let mut empty_dirs: Vec<String> = vec![];
+
+			info!("Start to collect files for task {}", &uuid);
+			'walk_loop: for p in walk {
+				if token.is_cancelled() {
+					break;
+				}
+				let p = match p {
+					Ok(p) => p,
+					Err(e) => {
+						let path = e.path().unwrap_or(&local_path).to_path_buf();
+						match e.loop_ancestor() {
+							Some(ancestor) => warn!("Skip {:?}, it links back to {:?}", &path, ancestor),
+							None => warn!("Failed to scan {:?}: {}", &path, e)
+						}
+						continue;
+					}
+				};
+				let path = p.path();
+				if is_placeholder(path) {
+					// never uploaded, one whose file is gone from the server goes too
+					let gone = read_placeholder(path).is_some_and(|h| !remote_by_name.contains_key(&rules.normalize(&h.remotePath)));
+					if direction != SyncDirection::Upload && gone {
+						debug!("Remove the placeholder {:?}", path);
+						let _ = remove_file(path);
+					}
+					continue;
+				}
+				for component in path.components() {
+					let name = component.as_os_str().to_string_lossy();
+					if ignores.iter().any(|i| *i == name) {
+						continue 'walk_loop;
+					}
+				}
+
+				let kind = scanner.classify(&p);
+				if let EntryKind::Skip = kind {
+					continue;
+				}
+
+				let to_path = match rules.relative_key(&local_path, path) {
+					Ok(k) => k,
+					Err(e) => {
+						warn!("Skip {:?}: {}", path, &e);
+						add_error(SyncError::new(ErrorKind::InvalidName, e).task(&uuid).path(path));
+						continue;
+					}
+				};
+				let s3_path = format!("{}/{}", &remote_path, &to_path);
+				if let Some(existing) = collisions.insert(&s3_path) {
+					warn!("{} and {} only differ in case", &existing, &s3_path);
+					add_error(
+						SyncError::new(ErrorKind::InvalidName, format!("Only differs in case from {}, the two collide on case-insensitive file systems", existing))
+							.task(&uuid)
+							.path(path)
+					);
+				}
+
+				let link = match kind {
+					EntryKind::Link(target) => Some(target),
+					EntryKind::EmptyDir => {
+						empty_dirs.push(s3_path);
+						continue;
+					},
+					_ => None
+				};
+
+				let size = match (&link, p.metadata()) {
+					(Some(target), _) => target.to_string_lossy().len() as u64,
+					(None, Ok(m)) => m.len(),
+					(None, Err(_)) => 0
+				};
 
-	let walk = WalkDir::new(&local_path);
-	let mut sync_tasks: VecDeque<SyncTask> = VecDeque::new();
+				let mut sync_task = SyncTask { from: path.to_path_buf(), to: s3_path, size, direction, link };
+				if direction != SyncDirection::Upload {
+					match remote_by_name.get(&sync_task.to) {
+						Some(file) => {
+							sync_task.to = file.key.clone();
+							if direction == SyncDirection::Download {
+								sync_task.size = file.size;
+							}
+							matched.insert(file.key.clone());
+						},
+						// local files missing remotely are left alone when
+						// downloading, two-way mode decides once it gets there
+						None if direction == SyncDirection::Download => continue,
+						None => {}
+					}
+				}
 
-	println!("Start to collect files for task {}", &uuid);
-	'walk_loop: for p in walk {
-		let p = p.unwrap();
-		if p.file_type().is_file() {
-			let path = p.path();
-			for component in path.components() {
-				let name = component.as_os_str().to_str().unwrap();
-				let str_name = name.to_string();
-				if ignores.contains(&str_name) {
-					continue 'walk_loop;
+				if resumed.contains(&sync_task.from) {
+					continue;
+				}
+				if tx.blocking_send(sync_task).is_err() {
+					break;
+				}
+			}
+			(matched, empty_dirs)
+		})
+	};
+	let mut scan = Some(scan);
+
+	let failed: Arc<Mutex<Vec<SyncTask>>> = Arc::new(Mutex::new(vec![]));
+	let mut attempts: HashMap<PathBuf, u32> = HashMap::new();
+	let in_flight: Arc<Mutex<HashMap<PathBuf, SyncTask>>> = Arc::new(Mutex::new(HashMap::new()));
+	let mut files = JoinSet::new();
+
+	while !token.is_cancelled() {
+		let sync_task = if let Some(t) = sync_tasks.pop_front() {
+			t
+		} else if let Some(handle) = scan.as_mut() {
+			let next = tokio::select! {
+				t = rx.recv() => t,
+				_ = token.cancelled() => break
+			};
+			match next {
+				Some(t) => {
+					queue_file(&uuid, &t);
+					t
+				},
+				None => {
+					let (mut local_keys, empty_dirs) = handle.await.unwrap_or_default();
+					scan = None;
+					local_keys.extend(resumed_keys.iter().cloned());
+
+					if direction != SyncDirection::Download && !empty_dirs.is_empty() {
+						if let Err(err) = backend.create_folders(&empty_dirs).await {
+							let err = err.task(&uuid);
+							warn!("Failed creating remote folders: {}", &err);
+							add_error(err);
+						}
+					}
+					// writes placeholders and creates folders, so it runs on the blocking pool as well
+					let remote_files = std::mem::take(&mut remote_files);
+					let (remote_path, local_path, ignores, selected, rules) =
+						(remote_path.clone(), local_path.clone(), ignores.clone(), selected.clone(), rules.clone());
+					let downloads = tokio::task::spawn_blocking(move || {
+						remote_only(&remote_files, &local_keys, &remote_path, &local_path, &ignores, &selected, &rules, direction)
+					}).await.unwrap_or_default();
+					for t in downloads {
+						queue_file(&uuid, &t);
+						sync_tasks.push_back(t);
+					}
+					continue;
+				}
+			}
+		} else if !files.is_empty() || !failed.lock().unwrap().is_empty() {
+			// 等待这一轮全部完成
+			while files.join_next().await.is_some() {}
+			let failed_tasks: Vec<SyncTask> = failed.lock().unwrap().drain(..).collect();
+			let mut fewest = MAX_ATTEMPTS;
+			for t in failed_tasks {
+				let tries = attempts.entry(t.from.clone()).or_insert(0);
+				*tries += 1;
+				if *tries >= MAX_ATTEMPTS {
+					// its failure is recorded already, the next run tries again
+					warn!("Give up on {:?} after {} attempts", &t.from, tries);
+					file_skipped(&uuid, &t);
+					continue;
+				}
+				fewest = fewest.min(*tries);
+				sync_tasks.push_back(t);
+			}
+			if !sync_tasks.is_empty() {
+				let delay = RETRY_DELAY * 2u32.pow(fewest - 1);
+				debug!("Retry {} file(s) of task {} in {:?}", sync_tasks.len(), &uuid, delay);
+				tokio::select! {
+					_ = tokio::time::sleep(delay) => {},
+					_ = token.cancelled() => {}
 				}
 			}
+			continue;
+		} else {
+			break;
+		};
+
+		// a run keeps a bounded number of files in the pool, the rest stay here
+		while files.len() >= POOL_QUEUE_SIZE {
+			files.join_next().await;
+		}
+		if token.is_cancelled() {
+			sync_tasks.push_front(sync_task);
+			break;
+		}
 
-			let partial_path = path.strip_prefix(&local_path).unwrap();
-			let partial_path_str = partial_path.as_os_str().to_str();
-			let mut to_path = partial_path_str.unwrap().to_string();
-			if cfg!(target_os="windows") {
-				to_path = to_path.replace("\\", "/");
+		let uuid_clone = uuid.clone();
+		let backend_clone = backend.clone();
+		let token_clone = token.clone();
+		let failed_clone = failed.clone();
+		let in_flight_clone = in_flight.clone();
+		let archiver_clone = archiver.clone();
+		let settings_clone = settings.clone();
+		in_flight.lock().unwrap().insert(sync_task.from.clone(), sync_task.clone());
+
+		files.spawn(async move {
+			// 获取许可
+			let mut slot = tokio::select! {
+				slot = acquire(&uuid_clone, &sync_task) => slot,
+				// still in flight, so it is saved as pending
+				_ = token_clone.cancelled() => return
+			};
+			// 运行同步
+			let success = _sync_single(
+				&uuid_clone, backend_clone.as_ref(), sync_task.clone(), archiver_clone.as_ref(), &settings_clone, &token_clone
+			).await;
+			if success {
+				slot.succeeded();
+				let p = current_progress(&uuid_clone).unwrap_or_default();
+				debug!("{}/{}", p.current, p.total);
+			} else {
+				failed_clone.lock().unwrap().push(sync_task.clone());
 			}
-			let s3_path = format!("{}/{}", &remote_path, &to_path);
+			in_flight_clone.lock().unwrap().remove(&sync_task.from);
+			// 撤销许可
+			drop(slot);
+		});
+		// finished files are reaped as the run goes, a large scan would keep them all otherwise
+		while files.try_join_next().is_some() {}
+	}
+	// lets the walk give up on a full queue
+	drop(rx);
+
+	if token.is_cancelled() {
+		// checkpoint right away in case the stop times out, and again once
+		// the files in flight are done
+		save_pending(&uuid, unfinished(&sync_tasks, &failed, &in_flight));
+		flush_state();
+		info!("Task {} stopped, waiting for {} file(s) in flight", &uuid, files.len());
+		while files.join_next().await.is_some() {}
+		save_pending(&uuid, unfinished(&sync_tasks, &failed, &in_flight));
+		flush_state();
+		return;
+	}
 
-			sync_tasks.push_back(SyncTask { from: path.to_path_buf(), to: s3_path.clone() });
+	if backup.enabled {
+		match prune_versions(backend.as_ref(), &remote_path, &backup).await {
+			Ok(0) => {},
+			Ok(n) => info!("Pruned {} old version(s) of task {}", n, &uuid),
+			Err(err) => {
+				let err = err.task(&uuid);
+				warn!("Failed pruning versions in {}: {}", &remote_path, &err);
+				add_error(err);
+			}
 		}
 	}
 
-	let total = sync_tasks.len();
+	finish_task(&uuid);
+}
 
-	// 创建线程池
-	static mut TASK_SEMA: Semaphore = Semaphore::const_new(8);
-	unsafe {
-		TASK_SEMA = Semaphore::new(settings.uploadThreadNumber);
-	}
-
-	while sync_tasks.len() > 0 {
-		static NEW_TASKS: Mutex<Vec<SyncTask>> = Mutex::new(vec![]);
-
-		for ti in 0..sync_tasks.len() {
-			unsafe {
-				// 获取许可
-				let permit = TASK_SEMA.acquire().await.unwrap();
-
-				let uuid_clone = uuid.clone();
-				let task_clone = sync_tasks[ti].clone();
-				let total_clone = total.clone();
-
-				let handler = tokio::spawn(async move {
-					// 运行同步
-					let success = _sync_single(task_clone.clone()).await;
-					if success {
-						let mut p = get_progress(uuid_clone.clone(), total_clone);
-						p.increase();
-						println!("{}/{}", p.current, p.total);
-						update_progress(&uuid_clone, p);
-					} else {
-						let mut tasks = NEW_TASKS.lock().unwrap();
-						tasks.push(task_clone.clone());
-					}
-					// 等待
-					sleep(Duration::from_millis(1000));
-					// 撤销许可
-					drop(permit);
-				});
-
-				// 如果是最后一个，就等待他完成
-				if ti == sync_tasks.len() - 1 {
-					handler.await.unwrap();
-				}
-			}
+/// Files of a stopped run that still have to be uploaded.
+fn unfinished(
+	remaining: &VecDeque<SyncTask>, failed: &Mutex<Vec<SyncTask>>, in_flight: &Mutex<HashMap<PathBuf, SyncTask>>
+) -> Vec<SyncTask> {
+	let mut pending: Vec<SyncTask> = failed.lock().unwrap().clone();
+	pending.extend(in_flight.lock().unwrap().values().cloned());
+	pending.extend(remaining.iter().cloned());
+
+	let mut seen = HashSet::new();
+	pending.retain(|t| seen.insert(t.from.clone()));
+	pending
+}
+
+/// Files and folders only present remotely, queued for download once the
+/// walk is done. Files outside the selected folders get a placeholder instead.
+#[allow(clippy::too_many_arguments)]
+fn remote_only(
+	remote_files: &[RemoteFile], local_keys: &HashSet<String>, remote_path: &str, local_path: &Path, ignores: &[String],
+	selected: &[String], rules: &NameRules, direction: SyncDirection
+) -> Vec<SyncTask> {
+	let prefix = format!("{}/", remote_path);
+	let mut downloads = vec![];
+	for file in remote_files {
+		if local_keys.contains(&file.key) {
+			continue;
+		}
+		let relative = match file.key.strip_prefix(&prefix) {
+			Some(r) if !r.is_empty() => r,
+			_ => continue
+		};
+		if relative.split('/').any(|name| ignores.iter().any(|i| i == name)) {
+			continue;
+		}
+		if relative.ends_with('/') {
+			create_local_folder(&rules.local_path(local_path, relative));
+			continue;
 		}
 
-		sync_tasks.clear();
-		let tasks = NEW_TASKS.lock().unwrap();
-		for task in tasks.iter() {
-			sync_tasks.push_back(task.clone());
+		let from = rules.local_path(local_path, relative);
+		if !is_selected(selected, relative) {
+			let placeholder = Placeholder { remotePath: file.key.clone(), size: file.size };
+			if let Err(e) = write_placeholder(&from, &placeholder) {
+				warn!("Failed to write the placeholder of {}: {}", &file.key, e);
+			}
+			continue;
 		}
+		downloads.push(SyncTask {
+			from,
+			to: file.key.clone(),
+			size: file.size,
+			direction,
+			link: None
+		});
 	}
+	downloads
+}
 
-	pause(uuid.clone());
+/// Add a file found by the scan to the progress and history of the run.
+fn queue_file(uuid: &str, sync_task: &SyncTask) {
+	file_queued(uuid, sync_task);
+	add_to_run(uuid, 1);
 }
 
-async fn _sync_single(sync_task: SyncTask) -> bool {
-	let body = ByteStream::from_path(&sync_task.from).await;
-	if body.is_ok() {
-		let this_node = post(
-			"/a/meta/bulk/get",
-			json!({
-				"NodePaths": [
-					&sync_task.to
-				]
-			}).to_string()
-		).await;
+async fn _sync_single(
+	uuid: &str, backend: &dyn RemoteBackend, sync_task: SyncTask, archiver: Option<&Archiver>, settings: &Settings,
+	token: &CancellationToken
+) -> bool {
+	emit(FILE_STARTED, FileEvent {
+		uuid: uuid.to_string(),
+		path: sync_task.from.to_string_lossy().to_string(),
+		remotePath: sync_task.to.clone(),
+		totalBytes: sync_task.size,
+		progress: current_progress(uuid).unwrap_or_default(),
+		..Default::default()
+	});
+
+	if let Some(target) = sync_task.link.clone() {
+		if sync_task.direction != SyncDirection::Download {
+			return upload_link(uuid, backend, sync_task, &target).await;
+		}
+	}
 
-		if let Ok(mut node) = this_node {
-			let t = node.body_string().await.unwrap();
-			let node_data: BulkMetaData = parse_json(&t);
-			if node_data.Nodes.len() > 0 {
-				let etag = calculate_etag(&sync_task.from);
-				if let Ok(tag) = etag {
-					if node_data.Nodes[0].Etag == tag {
-						println!("Skip {:?}", &sync_task.from);
-						sleep(Duration::from_millis(100));
-						return true;
+	match sync_task.direction {
+		SyncDirection::Upload => upload_file(uuid, backend, sync_task, archiver, settings, token).await,
+		SyncDirection::Download => download_file(uuid, backend, sync_task, settings, token).await,
+		SyncDirection::Both => match changed_side(uuid, backend, &sync_task).await {
+			Ok(Side::Same(etag)) => {
+				debug!("Skip {:?}", &sync_task.from);
+				if etag.is_some() {
+					set_synced_etag(uuid, &sync_task.to, etag);
+				}
+				record_file(uuid, &sync_task, FileAction::Skipped, 0, None);
+				file_done(uuid, &sync_task, 0);
+				true
+			},
+			Ok(Side::Upload(etag)) => {
+				let to = sync_task.to.clone();
+				let sync_task = SyncTask { direction: SyncDirection::Upload, ..sync_task };
+				if !upload_file(uuid, backend, sync_task, archiver, settings, token).await {
+					return false;
+				}
+				// a deferred file is not on the server yet
+				if let (Some(etag), Ok(Some(remote))) = (etag, backend.stat(&to).await) {
+					if remote.etag == etag {
+						set_synced_etag(uuid, &to, Some(etag));
 					}
 				}
-			}
+				true
+			},
+			Ok(Side::Download(etag)) => {
+				let to = sync_task.to.clone();
+				let sync_task = SyncTask { direction: SyncDirection::Download, ..sync_task };
+				let success = download_file(uuid, backend, sync_task, settings, token).await;
+				if success {
+					set_synced_etag(uuid, &to, Some(etag));
+				}
+				success
+			},
+			Ok(Side::Conflict(etag)) => {
+				let to = sync_task.to.clone();
+				let success = keep_conflict(uuid, backend, sync_task, settings, token).await;
+				if success {
+					set_synced_etag(uuid, &to, Some(etag));
+				}
+				success
+			},
+			Err(err) => transfer_failed(uuid, &sync_task, err).await
 		}
+	}
+}
 
-		println!("Putting {} to {}", &sync_task.from.to_str().unwrap(), &sync_task.to);
+/// Where a file of a two-way task has to go, with the ETag both sides have
+/// once it got there.
+enum Side {
+	/// Both sides have the same content, or neither has the file
+	Same(Option<String>),
+	Upload(Option<String>),
+	Download(String),
+	/// Changed on both sides since they last had the same content
+	Conflict(String)
+}
 
-		let session = get_session();
+/// Compare both sides of a file with the content they last had in common.
+/// Without that, differing files count as a conflict rather than guessing
+/// from modification times which edit to drop.
+async fn changed_side(uuid: &str, backend: &dyn RemoteBackend, sync_task: &SyncTask) -> Result<Side, SyncError> {
+	let local = FileStamp::of(&sync_task.from).ok();
+	let remote = backend.stat(&sync_task.to).await?;
+
+	let (local, remote) = match (local, remote) {
+		(None, None) => return Ok(Side::Same(None)),
+		(Some(_), None) => return Ok(Side::Upload(local_etag(&sync_task.from).await)),
+		(None, Some(remote)) => return Ok(Side::Download(remote.etag)),
+		(Some(local), Some(remote)) => (local, remote)
+	};
+	let etag = local_etag(&sync_task.from).await;
+	if local.size == remote.size && etag.as_deref() == Some(remote.etag.as_str()) {
+		return Ok(Side::Same(etag));
+	}
+	Ok(match get_synced_etag(uuid, &sync_task.to) {
+		Some(synced) if etag.as_deref() == Some(synced.as_str()) => Side::Download(remote.etag),
+		Some(synced) if remote.etag == synced => Side::Upload(etag),
+		_ => Side::Conflict(remote.etag)
+	})
+}
+
+/// Keep both edits of a file changed on both sides. The local file moves to
+/// a copy next to it, which the next run uploads, and the remote file is
+/// downloaded in its place.
+async fn keep_conflict(
+	uuid: &str, backend: &dyn RemoteBackend, sync_task: SyncTask, settings: &Settings, token: &CancellationToken
+) -> bool {
+	let copy = conflict_path(&sync_task.from);
+	if let Err(e) = rename(&sync_task.from, &copy) {
+		return transfer_failed(uuid, &sync_task, SyncError::io("keep conflicting file", e)).await;
+	}
+	warn!("{:?} changed on both sides, the local changes are kept in {:?}", &sync_task.from, &copy);
+	record_file(uuid, &sync_task, FileAction::Conflict, 0, Some(format!("Local changes kept in {}", copy.display())));
+	download_file(uuid, backend, SyncTask { direction: SyncDirection::Download, ..sync_task }, settings, token).await
+}
+
+/// `<name> (conflict <time>)<ext>` next to `path`, numbered if taken.
+fn conflict_path(path: &Path) -> PathBuf {
+	let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+	let ext = path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+	let time = Local::now().format("%Y-%m-%d %H%M%S");
+	let mut n = 0;
+	loop {
+		let number = if n == 0 { String::new() } else { format!(" {}", n) };
+		let copy = path.with_file_name(format!("{} (conflict {}{}){}", stem, time, number, ext));
+		if !copy.exists() {
+			return copy;
+		}
+		n += 1;
+	}
+}
+
+fn create_local_folder(dir: &Path) {
+	if !dir.exists() {
+		match create_dir_all(dir) {
+			Ok(_) => info!("Create folder {:?}", dir),
+			Err(e) => warn!("Failed to create folder {:?}: {}", dir, e)
+		}
+	}
+}
+
+async fn local_etag(path: &Path) -> Option<String> {
+	md5_file(path).await.ok().map(hex::encode)
+}
+
+/// The S3 client of the current session. Clients share their connections,
+/// so one is kept until the endpoint or the credentials change.
+pub fn s3_client() -> Client {
+	let session = get_session();
+	let endpoint = get_endpoint();
+	let key = format!("{}\n{}\n{}", &endpoint, &session.Token.AccessToken, &session.Token.IDToken);
+
+	let mut cached = S3_CLIENT.lock().unwrap();
+	if let Some((k, client)) = cached.as_ref() {
+		if *k == key {
+			return client.clone();
+		}
+	}
 
-		let config = SdkConfig::builder()
-			.endpoint_url(get_endpoint())
-			.app_name(AppName::new("s3").unwrap())
-			.behavior_version(BehaviorVersion::latest())
-			.region(Region::new("auto"))
-			.credentials_provider(
-				SharedCredentialsProvider::new(
-					Credentials::new(
-						session.Token.AccessToken,
-						session.Token.IDToken,
-						None, None,
-						"cells"
-					)
+	debug!("Create S3 client for {}", &endpoint);
+	let config = SdkConfig::builder()
+		.endpoint_url(endpoint)
+		.app_name(AppName::new("s3").unwrap())
+		.behavior_version(BehaviorVersion::latest())
+		.region(Region::new("auto"))
+		.credentials_provider(
+			SharedCredentialsProvider::new(
+				Credentials::new(
+					session.Token.AccessToken,
+					session.Token.IDToken,
+					None, None,
+					"cells"
 				)
-			).build();
+			)
+		).build();
+
+	let client = Client::new(&config);
+	*cached = Some((key, client.clone()));
+	client
+}
+
+/// Record a failed transfer, returns whether the file counts as handled.
+async fn transfer_failed(uuid: &str, sync_task: &SyncTask, err: SyncError) -> bool {
+	let err = err.task(uuid).file(sync_task);
+	warn!("Failed transferring {:?}: {}", &sync_task.from, &err);
+	file_failed(uuid, sync_task, &err.message);
+	match err.kind {
+		ErrorKind::Auth => {
+			record_file(uuid, sync_task, FileAction::Failed, 0, Some(err.message));
+			refresh_login().await;
+		},
+		ErrorKind::Network => {
+			record_file(uuid, sync_task, FileAction::Failed, 0, Some(err.message));
+		},
+		_ if !err.retryable => {
+			warn!("Invalid file {:?}", &sync_task.from);
+			record_file(uuid, sync_task, FileAction::Rejected, 0, Some(err.message.clone()));
+			add_error(err);
+			file_skipped(uuid, sync_task);
+			return true;
+		},
+		_ => {
+			record_file(uuid, sync_task, FileAction::Failed, 0, Some(err.message.clone()));
+			add_error(err);
+		}
+	};
+	false
+}
+
+async fn download_file(
+	uuid: &str, backend: &dyn RemoteBackend, sync_task: SyncTask, settings: &Settings, token: &CancellationToken
+) -> bool {
+
+	let remote = match backend.stat(&sync_task.to).await {
+		Ok(Some(r)) => r,
+		Ok(None) => {
+			debug!("{} is gone from the server", &sync_task.to);
+			record_file(uuid, &sync_task, FileAction::Skipped, 0, None);
+			file_skipped(uuid, &sync_task);
+			return true;
+		},
+		Err(err) => return transfer_failed(uuid, &sync_task, err).await
+	};
+
+	if let Some(target) = link_target(&remote.metadata) {
+		return restore_link(uuid, &sync_task, Path::new(&target));
+	}
+
+	if let Ok(stamp) = FileStamp::of(&sync_task.from) {
+		if stamp.size == remote.size && local_etag(&sync_task.from).await.as_deref() == Some(remote.etag.as_str()) {
+			// same content, only bring the metadata in line
+			debug!("Skip {:?}", &sync_task.from);
+			apply_metadata(&sync_task.from, &remote.metadata, settings.preservePosixMetadata, settings.restoreOwner);
+			record_file(uuid, &sync_task, FileAction::Skipped, 0, None);
+			file_done(uuid, &sync_task, 0);
+			return true;
+		}
+	}
+
+	if token.is_cancelled() {
+		debug!("Task {} stopped before downloading {}", uuid, &sync_task.to);
+		return false;
+	}
+
+	info!("Getting {} to {:?}", &sync_task.to, &sync_task.from);
+
+	let object = match backend.get(&sync_task.to).await {
+		Ok(o) => o,
+		Err(err) => return transfer_failed(uuid, &sync_task, err).await
+	};
+
+	let mut part = sync_task.from.clone().into_os_string();
+	part.push(PART_SUFFIX);
+	let part = PathBuf::from(part);
+
+	let written = match write_body(uuid, &sync_task, object.body, &part).await {
+		Ok(w) => w,
+		Err(err) => {
+			let _ = remove_file(&part);
+			return transfer_failed(uuid, &sync_task, err).await;
+		}
+	};
+	if let Err(e) = rename(&part, &sync_task.from) {
+		let _ = remove_file(&part);
+		return transfer_failed(uuid, &sync_task, SyncError::io("rename downloaded file", e)).await;
+	}
+	apply_metadata(&sync_task.from, &object.metadata, settings.preservePosixMetadata, settings.restoreOwner);
+	remove_placeholder(&sync_task.from);
+
+	info!("Successfully download {}", &sync_task.to);
+	record_file(uuid, &sync_task, FileAction::Downloaded, written, None);
+	file_done(uuid, &sync_task, written);
+	true
+}
+
+fn restore_link(uuid: &str, sync_task: &SyncTask, target: &Path) -> bool {
+	if read_link(&sync_task.from).map(|t| t == target).unwrap_or(false) {
+		debug!("Skip {:?}", &sync_task.from);
+		record_file(uuid, sync_task, FileAction::Skipped, 0, None);
+		file_done(uuid, sync_task, 0);
+		return true;
+	}
+
+	match create_link(&sync_task.from, target) {
+		Ok(_) => {
+			info!("Link {:?} to {:?}", &sync_task.from, target);
+			record_file(uuid, sync_task, FileAction::Downloaded, 0, None);
+			file_done(uuid, sync_task, 0);
+			true
+		},
+		Err(e) => {
+			let err = SyncError::io("create link", e).task(uuid).file(sync_task);
+			warn!("{}", &err);
+			file_failed(uuid, sync_task, &err.message);
+			record_file(uuid, sync_task, FileAction::Failed, 0, Some(err.message.clone()));
+			add_error(err);
+			false
+		}
+	}
+}
+
+/// Upload a symlink as a small object holding its target.
+async fn upload_link(uuid: &str, backend: &dyn RemoteBackend, sync_task: SyncTask, target: &Path) -> bool {
+	let content = target.to_string_lossy().to_string();
+
+	match backend.stat(&sync_task.to).await {
+		Ok(Some(remote)) if link_target(&remote.metadata).as_deref() == Some(content.as_str()) => {
+			debug!("Skip {:?}", &sync_task.from);
+			record_file(uuid, &sync_task, FileAction::Skipped, 0, None);
+			file_done(uuid, &sync_task, 0);
+			return true;
+		},
+		Err(err) => return transfer_failed(uuid, &sync_task, err).await,
+		_ => {}
+	}
+
+	info!("Putting link {:?} to {}", &sync_task.from, &sync_task.to);
+	let res = backend.put(
+		&sync_task.to,
+		ByteStream::from(content.clone().into_bytes()),
+		Some(content_md5(&md5_of(content.as_bytes()))),
+		link_metadata(&content)
+	).await;
+
+	match res {
+		Ok(_) => {
+			record_file(uuid, &sync_task, FileAction::Uploaded, sync_task.size, None);
+			file_done(uuid, &sync_task, sync_task.size);
+			true
+		},
+		Err(err) => transfer_failed(uuid, &sync_task, err).await
+	}
+}
+
+async fn write_body(uuid: &str, sync_task: &SyncTask, mut body: ByteStream, path: &Path) -> Result<u64, SyncError> {
+	if let Some(parent) = path.parent() {
+		create_dir_all(parent).map_err(|e| SyncError::io("create folder", e))?;
+	}
+
+	let mut file = File::create(path).await.map_err(|e| SyncError::io("create file", e))?;
+	let mut written = 0;
+	while let Some(chunk) = body.try_next().await.map_err(|e| SyncError::new(ErrorKind::Network, e))? {
+		file.write_all(&chunk).await.map_err(|e| SyncError::io("write file", e))?;
+		written += chunk.len() as u64;
+		file_received(uuid, sync_task, chunk.len() as u64);
+	}
+	file.flush().await.map_err(|e| SyncError::io("write file", e))?;
+	Ok(written)
+}
+
+async fn upload_file(
+	uuid: &str, backend: &dyn RemoteBackend, sync_task: SyncTask, archiver: Option<&Archiver>, settings: &Settings,
+	token: &CancellationToken
+) -> bool {
+	let stamp = match FileStamp::of(&sync_task.from) {
+		Ok(s) => s,
+		Err(e) => {
+			let err = SyncError::io("read file", e).task(uuid).file(&sync_task);
+			warn!("{}", &err);
+			file_failed(uuid, &sync_task, &err.message);
+			record_file(uuid, &sync_task, FileAction::Failed, 0, Some(err.message.clone()));
+			add_error(err);
+			return false;
+		}
+	};
+
+	// a file still being written would be uploaded half done
+	if !stamp.settled(Duration::from_secs(settings.settleSeconds)) {
+		debug!("Defer {:?}, modified less than {}s ago", &sync_task.from, settings.settleSeconds);
+		record_file(uuid, &sync_task, FileAction::Deferred, 0, None);
+		file_skipped(uuid, &sync_task);
+		return true;
+	}
+
+	let snapshot = if settings.snapshotSizeLimit > 0 && stamp.size <= settings.snapshotSizeLimit * 1024 * 1024 {
+		match Snapshot::take(&sync_task.from).await {
+			Ok(s) if stamp.unchanged(&sync_task.from) => Some(s),
+			Ok(_) => return defer_changed(uuid, &sync_task),
+			Err(e) => {
+				warn!("Failed to copy {:?}, uploading it in place: {}", &sync_task.from, e);
+				None
+			}
+		}
+	} else {
+		None
+	};
+	let source = match &snapshot {
+		Some(s) => s.path().to_path_buf(),
+		None => sync_task.from.clone()
+	};
+
+	let body = ByteStream::from_path(&source).await;
+	if body.is_ok() {
+		let etag = md5_file(&source).await;
+
+		if let (Ok(Some(remote)), Ok(digest)) = (backend.stat(&sync_task.to).await, &etag) {
+			if remote.etag == hex::encode(digest) {
+				debug!("Skip {:?}", &sync_task.from);
+				record_file(uuid, &sync_task, FileAction::Skipped, 0, None);
+				file_done(uuid, &sync_task, 0);
+				// a file kept back by the archive thresholds goes once they are met
+				if let Some(archiver) = archiver {
+					archiver.archive(uuid, backend, &sync_task).await;
+				}
+				return true;
+			}
+		}
 
-		let s3_client = Client::new(&config);
+		// a file that has not started uploading is left for the next run
+		if token.is_cancelled() {
+			debug!("Task {} stopped before uploading {:?}", uuid, &sync_task.from);
+			return false;
+		}
 
-		let res = s3_client
-			.put_object()
-			.bucket(BUCKET_NAME)
-			.key(&sync_task.to)
-			.body(body.unwrap())
-			.send()
-			.await;
+		info!("Putting {:?} to {}", &sync_task.from, &sync_task.to);
+
+		let body = counting_body(uuid, &sync_task, body.unwrap());
+
+		let res = backend.put(
+			&sync_task.to,
+			body,
+			etag.as_ref().ok().map(|d| content_md5(d)),
+			local_metadata(&sync_task.from, settings.preservePosixMetadata)
+		).await;
 
 		match res {
 			Ok(_) => {
-				println!("Successfully upload {:?}", &sync_task.from);
+				if snapshot.is_none() && !stamp.unchanged(&sync_task.from) {
+					return defer_changed(uuid, &sync_task);
+				}
+
+				if settings.verifyUploads {
+					let local_etag = etag.as_ref().ok().map(hex::encode);
+					if let Err(err) = verify_upload(backend, &sync_task, stamp.size, local_etag.as_deref()).await {
+						let err = err.task(uuid).file(&sync_task);
+						warn!("Failed verifying {:?}: {}", &sync_task.from, &err);
+						file_failed(uuid, &sync_task, &err.message);
+						record_file(uuid, &sync_task, FileAction::Failed, sync_task.size, Some(err.message.clone()));
+						add_error(err);
+						return false;
+					}
+				}
+
+				info!("Successfully upload {:?}", &sync_task.from);
+				record_file(uuid, &sync_task, FileAction::Uploaded, sync_task.size, None);
+				file_done(uuid, &sync_task, sync_task.size);
+				if let Some(archiver) = archiver {
+					archiver.archive(uuid, backend, &sync_task).await;
+				}
 				return true;
 			},
-			Err(e) => {
-				println!("Failed uploading {:?}: \n{:?}", &sync_task.from, &e);
-				match e {
-					SdkError::ServiceError(se) => {
-						if let Some(code) = se.err().meta().code() {
-							match code {
-								"AccessDenied" => {
-									refresh_login().await;
-								},
-								"NotImplemented" => {
-									println!("Invalid file {:?}", &sync_task.from);
-									add_error(format!("Invalid file {:?}", &sync_task.from));
-									return true;
-								},
-								_ => {}
-							}
-						} else {
-							println!("Unknown error: {:?}", &se);
-							add_error(format!("Unknown error: {:?}", &se));
-						}
-					},
-					_ => {}
-				};
-				return false;
+			Err(err) => {
+				return transfer_failed(uuid, &sync_task, err).await;
 			}
 		}
 	} else {
-		let err = body.err();
-		println!("Failed to read file: {:?}", &err);
-		add_error(format!("Failed to read file: {:?}", &err));
+		let err = SyncError::io("read file", body.err().unwrap()).task(uuid).file(&sync_task);
+		warn!("{}", &err);
+		file_failed(uuid, &sync_task, &err.message);
+		record_file(uuid, &sync_task, FileAction::Failed, 0, Some(err.message.clone()));
+		add_error(err);
 		return false;
 	}
 }
 
-fn update_progress(uuid: &str, new_progress: TaskProgress) {
-	let mut progresses = SYNC_PROGRESS.lock().unwrap();
-	if let Some(progress) = progresses.get_mut(uuid) {
-		progress.current = new_progress.current;
-		progress.total = new_progress.total;
+/// The file was written to while it was uploaded. It is deferred to the next
+/// run like an unsettled file, retrying it within the run would never end for
+/// a file that is written to all the time.
+fn defer_changed(uuid: &str, sync_task: &SyncTask) -> bool {
+	info!("{:?} changed during upload, defer it to the next run", &sync_task.from);
+	record_file(uuid, sync_task, FileAction::Deferred, 0, Some("File changed during upload".to_string()));
+	file_skipped(uuid, sync_task);
+	true
+}
+
+/// Fetch the object that was just uploaded and compare it with the local file.
+/// The server may not know the ETag of an object yet, only the size is checked then.
+async fn verify_upload(backend: &dyn RemoteBackend, sync_task: &SyncTask, size: u64, etag: Option<&str>) -> Result<(), SyncError> {
+	let remote = backend.stat(&sync_task.to).await?
+		.ok_or_else(|| SyncError::new(ErrorKind::Verification, "Uploaded file not found on the server"))?;
+
+	if remote.size != size {
+		return Err(SyncError::new(
+			ErrorKind::Verification,
+			format!("Size mismatch, {} bytes locally but {} bytes on the server", size, remote.size)
+		));
+	}
+	if let Some(etag) = etag {
+		if !remote.etag.is_empty() && remote.etag != etag {
+			return Err(SyncError::new(
+				ErrorKind::Verification,
+				format!("ETag mismatch, {} locally but {} on the server", etag, remote.etag)
+			));
+		}
 	}
+
+	debug!("Verified {:?}", &sync_task.from);
+	Ok(())
 }
 
-fn new_progress(uuid: &str, total: usize) -> TaskProgress {
-	let mut progress = TaskProgress::default();
-	progress.total = total;
-	let mut progresses = SYNC_PROGRESS.lock().unwrap();
-	progresses.insert(uuid.to_string(), progress);
-	progress
+/// Transfer a single file outside of a task run, used to retry failed files.
+/// It waits for a slot of the transfer pool like the files of a run.
+pub async fn retry_file(uuid: String, sync_task: SyncTask) -> bool {
+	let (backend, archiver) = match get_task(&uuid) {
+		Some(task) => {
+			set_limits(&uuid, task.priority, task.maxTransfers);
+			(task_backend(&task), Archiver::new(&task))
+		},
+		None => (backend_for(&BackendConfig::Cells, &uuid), None)
+	};
+	let mut slot = acquire(&uuid, &sync_task).await;
+	let success = _sync_single(
+		&uuid, backend.as_ref(), sync_task, archiver.as_ref(), &get_saved_settings(), &CancellationToken::new()
+	).await;
+	if success {
+		slot.succeeded();
+	}
+	success
 }
 
-#[tauri::command]
-pub fn pause(uuid: String) -> String {
+/// Called by the sync task itself once it has run out of files.
+fn finish_task(uuid: &str) {
+	let progress = current_progress(uuid).unwrap_or_default();
+	let mut handlers = SYNC_HANDLERS.lock().unwrap();
+	// a task being stopped is removed by `stop_task`
+	if handlers.get(uuid).is_some_and(|h| !h.stopping) {
+		handlers.remove(uuid);
+	}
+	drop(handlers);
+	remove_progress(uuid);
+	finish_run(uuid, false);
+	clear_pending(uuid);
+	flush_state();
+
+	info!("Finish task {}", uuid);
+	emit(TASK_FINISHED, TaskEvent { uuid: uuid.to_string(), progress });
+	refresh_tray();
+}
+
+/// Ask a running task to stop and give the files it is uploading up to
+/// `STOP_TIMEOUT` to finish before they are aborted.
+async fn stop_task(uuid: &str) -> bool {
 	let mut handlers = SYNC_HANDLERS.lock().unwrap();
-	let mut task_progress = SYNC_PROGRESS.lock().unwrap();
-	if let Some(handler) = handlers.get(&uuid) {
-		handler.abort();
-		println!("Pause task {}", &uuid);
-		handlers.remove(&uuid);
-		task_progress.remove(&uuid);
+	match handlers.get_mut(uuid) {
+		Some(h) if !h.stopping => {
+			h.stopping = true;
+			h.token.cancel();
+		},
+		_ => return false
+	}
+	drop(handlers);
+
+	let running = || SYNC_HANDLERS.lock().unwrap().get(uuid).is_some_and(|h| !h.handle.is_finished());
+	let stopped = tokio::time::timeout(STOP_TIMEOUT, async {
+		while running() {
+			tokio::time::sleep(Duration::from_millis(50)).await;
+		}
+	}).await;
+	if stopped.is_err() {
+		warn!("Task {} did not stop within {:?}, aborting it", uuid, STOP_TIMEOUT);
+		if let Some(h) = SYNC_HANDLERS.lock().unwrap().get(uuid) {
+			h.handle.abort();
+		}
+		while running() {
+			tokio::time::sleep(Duration::from_millis(50)).await;
+		}
+	}
+
+	remove_progress(uuid);
+	finish_run(uuid, true);
+	SYNC_HANDLERS.lock().unwrap().remove(uuid);
+	refresh_tray();
+	true
+}
+
+async fn stop_all() {
+	let uuids: Vec<String> = SYNC_HANDLERS.lock().unwrap().keys().cloned().collect();
+	let mut stops = JoinSet::new();
+	for uuid in uuids {
+		stops.spawn(async move {
+			stop_task(&uuid).await;
+		});
+	}
+	while stops.join_next().await.is_some() {}
+}
+
+/// Stop all tasks, then write out the sync state and the logs before exiting.
+pub async fn shutdown() {
+	info!("Shutting down");
+	stop_all().await;
+	flush_state();
+	flush_history();
+	flush_logs();
+}
+
+#[tauri::command]
+pub async fn pause(uuid: String) -> String {
+	if stop_task(&uuid).await {
+		info!("Pause task {}", &uuid);
 	}
 	CommandResponse::empty_ok().to_string()
 }
 
-fn get_progress(uuid: String, total: usize) -> TaskProgress {
-	let progresses = SYNC_PROGRESS.lock().unwrap();
-	if let Some(progress) = progresses.get(&uuid) {
-		return progress.clone();
-	} else {
-		drop(progresses);
-		return new_progress(&uuid, total);
+pub fn is_running(uuid: &str) -> bool {
+	let handlers = SYNC_HANDLERS.lock().unwrap();
+	handlers.get(uuid).map(|h| h.stopping || !h.handle.is_finished()).unwrap_or(false)
+}
+
+pub fn running_count() -> usize {
+	let handlers = SYNC_HANDLERS.lock().unwrap();
+	handlers.values().filter(|h| h.stopping || !h.handle.is_finished()).count()
+}
+
+/// End of the current global pause, `None` when syncing is allowed.
+pub fn paused_until() -> Option<DateTime<Local>> {
+	let paused = PAUSED_UNTIL.lock().unwrap();
+	paused.filter(|until| *until > Local::now())
+}
+
+/// Stop every running task and hold off all syncs until `until`.
+pub fn pause_all_until(until: DateTime<Local>) {
+	let mut paused = PAUSED_UNTIL.lock().unwrap();
+	*paused = Some(until);
+	drop(paused);
+	info!("Pause all tasks until {}", until);
+
+	refresh_tray();
+	tokio::spawn(stop_all());
+
+	tokio::spawn(async move {
+		tokio::time::sleep((until - Local::now()).to_std().unwrap_or_default()).await;
+		let paused = PAUSED_UNTIL.lock().unwrap();
+		// a newer pause or a manual resume replaced this one
+		if *paused != Some(until) {
+			return;
+		}
+		drop(paused);
+		resume_all();
+	});
+}
+
+pub fn resume_all() {
+	let mut paused = PAUSED_UNTIL.lock().unwrap();
+	*paused = None;
+	drop(paused);
+	info!("Resume all tasks");
+	sync_all("resume");
+	refresh_tray();
+}
+
+/// Start a task from the backend, using the global ignores from the settings.
+pub fn sync_now(task: TaskData, trigger: &str) {
+	let ignores = get_saved_settings().globalIgnores;
+	let trigger = trigger.to_string();
+	tokio::spawn(async move {
+		sync(task, ignores, Some(trigger)).await;
+	});
+}
+
+pub fn sync_all(trigger: &str) {
+	for task in get_tasks() {
+		if !task.paused && !is_running(&task.uuid) {
+			sync_now(task, trigger);
+		}
 	}
 }
 
 #[tauri::command]
 pub fn progress(uuid: String) -> String {
-	let progresses = SYNC_PROGRESS.lock().unwrap();
-	if let Some(progress) = progresses.get(&uuid) {
-		if progress.current == progress.total && progress.total != 0 {
-			pause(uuid.clone());
-		}
-		CommandResponse::ok(progress.clone()).to_string()
+	if let Some(progress) = current_progress(&uuid) {
+		CommandResponse::ok(progress).to_string()
 	} else {
 		CommandResponse::empty_err().to_string()
 	}
 }
 
 async fn refresh_login() {
-	println!("Refresh Login");
+	info!("Refresh Login");
 	login(
 		unsafe {
 			ENDPOINT.clone()
@@ -0,0 +1,148 @@
+use std::{fs, io, path::{Path, PathBuf}};
+
+use tracing::warn;
+use walkdir::WalkDir;
+
+use crate::{
+	data::get_task,
+	net::retry_file,
+	structs::{CommandResponse, HydrateResult, Placeholder, SyncDirection, SyncTask}
+};
+
+/// Appended to the name of the file a placeholder stands for.
+pub const PLACEHOLDER_SUFFIX: &str = ".cellsplaceholder";
+
+pub fn is_placeholder(path: &Path) -> bool {
+	path.as_os_str().to_string_lossy().ends_with(PLACEHOLDER_SUFFIX)
+}
+
+pub fn placeholder_path(path: &Path) -> PathBuf {
+	let mut p = path.as_os_str().to_owned();
+	p.push(PLACEHOLDER_SUFFIX);
+	PathBuf::from(p)
+}
+
+/// The file a placeholder stands for.
+fn file_path(placeholder: &Path) -> Option<PathBuf> {
+	placeholder.to_str()?.strip_suffix(PLACEHOLDER_SUFFIX).map(PathBuf::from)
+}
+
+pub fn read_placeholder(placeholder: &Path) -> Option<Placeholder> {
+	serde_json::from_str(&fs::read_to_string(placeholder).ok()?).ok()
+}
+
+/// Stand in for the remote file that belongs at `path`. An unchanged
+/// placeholder is not written again.
+pub fn write_placeholder(path: &Path, placeholder: &Placeholder) -> io::Result<()> {
+	let target = placeholder_path(path);
+	let content = serde_json::to_string(placeholder)?;
+	if fs::read_to_string(&target).is_ok_and(|c| c == content) {
+		return Ok(());
+	}
+	if let Some(parent) = target.parent() {
+		fs::create_dir_all(parent)?;
+	}
+	fs::write(target, content)
+}
+
+/// Called once the file at `path` is downloaded.
+pub fn remove_placeholder(path: &Path) {
+	let _ = fs::remove_file(placeholder_path(path));
+}
+
+/// Whether `relative`, a path below the remote folder of a task, is in one of
+/// its selected folders.
+pub fn is_selected(selected: &[String], relative: &str) -> bool {
+	selected.is_empty() || selected.iter().map(|f| f.trim_matches('/')).any(|folder| {
+		folder.is_empty() || relative.strip_prefix(folder).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+	})
+}
+
+/// The folders of a task, the local one as configured and resolved.
+struct LocalRoot {
+	dir: PathBuf,
+	resolved: PathBuf,
+	remote: String
+}
+
+impl LocalRoot {
+	/// `path` below the configured folder, if it resolves to somewhere inside
+	/// it. Links are followed, so neither they nor `..` lead out of the task.
+	fn inside(&self, path: &Path) -> Option<PathBuf> {
+		let resolved = fs::canonicalize(path).ok()?;
+		resolved.strip_prefix(&self.resolved).ok().map(|relative| self.dir.join(relative))
+	}
+
+	/// Whether `key` is a file below the remote folder, placeholders could
+	/// name any object the session can read otherwise.
+	fn holds(&self, key: &str) -> bool {
+		key.strip_prefix(&self.remote)
+			.and_then(|k| k.strip_prefix('/'))
+			.is_some_and(|relative| relative.split('/').all(|name| !matches!(name, "" | "." | "..")))
+	}
+}
+
+/// Download the files behind placeholders. `paths` are placeholders or
+/// folders to hydrate everything below, all inside the local folder of the
+/// task, and placeholders only stand for files inside its remote folder.
+#[tauri::command]
+pub async fn hydrate(uuid: String, paths: Vec<String>) -> String {
+	let root = match get_task(&uuid).and_then(|t| {
+		let resolved = fs::canonicalize(&t.localDir).ok()?;
+		let remote = t.remoteDir.Path.trim_end_matches('/').to_string();
+		Some(LocalRoot { dir: PathBuf::from(t.localDir), resolved, remote })
+	}) {
+		Some(r) => r,
+		None => return CommandResponse::<HydrateResult>::err("Task not found").to_string()
+	};
+
+	let mut result = HydrateResult::default();
+	let mut placeholders = vec![];
+	for path in paths {
+		match root.inside(Path::new(&path)) {
+			Some(dir) if dir.is_dir() => placeholders.extend(
+				WalkDir::new(&dir)
+					.into_iter()
+					.filter_map(|e| e.ok())
+					.map(|e| e.into_path())
+					.filter(|p| is_placeholder(p))
+					.filter_map(|p| root.inside(&p))
+			),
+			Some(placeholder) if is_placeholder(&placeholder) => placeholders.push(placeholder),
+			_ => {
+				warn!("Refuse to hydrate {:?}", &path);
+				result.failed.push(path);
+			}
+		}
+	}
+
+	for placeholder in placeholders {
+		let (from, content) = match (file_path(&placeholder), read_placeholder(&placeholder)) {
+			(Some(f), Some(c)) if root.holds(&c.remotePath) => (f, c),
+			(Some(_), Some(c)) => {
+				warn!("{:?} stands for {}, outside of the task", &placeholder, &c.remotePath);
+				result.failed.push(placeholder.to_string_lossy().to_string());
+				continue;
+			},
+			_ => {
+				warn!("{:?} is not a placeholder", &placeholder);
+				result.failed.push(placeholder.to_string_lossy().to_string());
+				continue;
+			}
+		};
+		let sync_task = SyncTask {
+			from,
+			to: content.remotePath,
+			size: content.size,
+			direction: SyncDirection::Download,
+			link: None
+		};
+		// a downloaded file takes the place of its placeholder
+		if retry_file(uuid.clone(), sync_task).await {
+			result.hydrated += 1;
+		} else {
+			result.failed.push(placeholder.to_string_lossy().to_string());
+		}
+	}
+	CommandResponse::ok(result).to_string()
+}
@@ -0,0 +1,252 @@
+use std::{collections::{HashMap, VecDeque}, sync::Mutex};
+
+use lazy_static::lazy_static;
+use tokio::sync::oneshot;
+use tracing::debug;
+
+use crate::{
+	data::get_saved_settings,
+	history::now_millis,
+	structs::{CommandResponse, Settings, SyncTask, TaskPriority, Transfer, TransferQueue, TransferState}
+};
+
+/// Finished transfers kept for the queue view.
+const FINISHED_KEPT: usize = 100;
+/// Queued transfers returned by the queue view, the total is always returned.
+const QUEUED_SHOWN: usize = 200;
+
+/// Files waiting for a transfer slot, shared by every running task. Free
+/// slots go to the highest priority task waiting for one, tasks of the same
+/// priority take turns.
+struct Pool {
+	size: usize,
+	small_files_first: bool,
+	tasks: HashMap<String, TaskSlots>,
+	// least recently served first
+	turns: VecDeque<String>,
+	active: HashMap<u64, Transfer>,
+	next_id: u64,
+	// newest first
+	finished: VecDeque<Transfer>
+}
+
+struct Waiter {
+	transfer: Transfer,
+	tx: oneshot::Sender<Slot>
+}
+
+#[derive(Default)]
+struct TaskSlots {
+	priority: TaskPriority,
+	/// Most slots the task may hold at once, 0 for no limit
+	max: usize,
+	active: usize,
+	waiting: VecDeque<Waiter>
+}
+
+impl TaskSlots {
+	fn ready(&self) -> bool {
+		!self.waiting.is_empty() && (self.max == 0 || self.active < self.max)
+	}
+
+	/// Index of the file to start next.
+	fn next_file(&self, small_files_first: bool) -> usize {
+		if !small_files_first {
+			return 0;
+		}
+		self.waiting
+			.iter()
+			.enumerate()
+			.min_by_key(|(_, w)| w.transfer.size)
+			.map(|(i, _)| i)
+			.unwrap_or(0)
+	}
+}
+
+impl Pool {
+	fn task(&mut self, uuid: &str) -> &mut TaskSlots {
+		if !self.tasks.contains_key(uuid) {
+			self.turns.push_back(uuid.to_string());
+		}
+		self.tasks.entry(uuid.to_string()).or_default()
+	}
+
+	/// Highest priority first, tasks of the same priority in turn.
+	fn ordered_tasks(&self) -> Vec<&String> {
+		let mut uuids: Vec<&String> = self.turns.iter().collect();
+		// stable, so tasks of the same priority keep their turn
+		uuids.sort_by_key(|u| std::cmp::Reverse(self.tasks[*u].priority));
+		uuids
+	}
+
+	fn dispatch(&mut self) {
+		// waits given up by a stopped task
+		for task in self.tasks.values_mut() {
+			task.waiting.retain(|w| !w.tx.is_closed());
+		}
+
+		while self.active.len() < self.size {
+			let uuid = match self.ordered_tasks().into_iter().find(|u| self.tasks[*u].ready()) {
+				Some(u) => u.clone(),
+				None => break
+			};
+			let small_files_first = self.small_files_first;
+			let task = self.tasks.get_mut(&uuid).unwrap();
+			let index = task.next_file(small_files_first);
+			let mut waiter = task.waiting.remove(index).unwrap();
+
+			let id = self.next_id;
+			self.next_id += 1;
+			waiter.transfer.state = TransferState::Active;
+			waiter.transfer.startedAt = Some(now_millis());
+
+			// the wait may have been given up since the check above
+			if let Err(mut slot) = waiter.tx.send(Slot { id: Some(id), succeeded: false }) {
+				slot.id = None;
+				continue;
+			}
+			task.active += 1;
+			self.active.insert(id, waiter.transfer);
+
+			self.turns.retain(|u| *u != uuid);
+			self.turns.push_back(uuid);
+		}
+	}
+
+	fn release(&mut self, id: u64, succeeded: bool) {
+		if let Some(mut transfer) = self.active.remove(&id) {
+			if let Some(task) = self.tasks.get_mut(&transfer.taskUuid) {
+				task.active = task.active.saturating_sub(1);
+			}
+			transfer.state = if succeeded { TransferState::Done } else { TransferState::Failed };
+			transfer.finishedAt = Some(now_millis());
+			self.finished.push_front(transfer);
+			self.finished.truncate(FINISHED_KEPT);
+		}
+		self.dispatch();
+	}
+
+	fn view(&self) -> TransferQueue {
+		let mut queued = vec![];
+		let mut queued_total = 0;
+		for uuid in self.ordered_tasks() {
+			let mut waiting: Vec<&Transfer> = self.tasks[uuid]
+				.waiting
+				.iter()
+				.filter(|w| !w.tx.is_closed())
+				.map(|w| &w.transfer)
+				.collect();
+			if self.small_files_first {
+				waiting.sort_by_key(|t| t.size);
+			}
+			queued_total += waiting.len();
+			queued.extend(waiting.into_iter().cloned());
+		}
+		queued.truncate(QUEUED_SHOWN);
+
+		let mut active: Vec<Transfer> = self.active.values().cloned().collect();
+		active.sort_by_key(|t| t.startedAt);
+
+		TransferQueue {
+			queued,
+			queuedTotal: queued_total,
+			active,
+			finished: self.finished.iter().cloned().collect()
+		}
+	}
+}
+
+lazy_static! {
+	static ref POOL: Mutex<Pool> = {
+		let settings = get_saved_settings();
+		Mutex::new(Pool {
+			size: settings.uploadThreadNumber.max(1),
+			small_files_first: settings.smallFilesFirst,
+			tasks: HashMap::new(),
+			turns: VecDeque::new(),
+			active: HashMap::new(),
+			next_id: 0,
+			finished: VecDeque::new()
+		})
+	};
+}
+
+/// A transfer slot, handed back to the pool when dropped.
+pub struct Slot {
+	// None for a slot that was never handed out
+	id: Option<u64>,
+	succeeded: bool
+}
+
+impl Slot {
+	/// Show the transfer as done rather than failed once the slot is dropped.
+	pub fn succeeded(&mut self) {
+		self.succeeded = true;
+	}
+}
+
+impl Drop for Slot {
+	fn drop(&mut self) {
+		if let Some(id) = self.id.take() {
+			POOL.lock().unwrap().release(id, self.succeeded);
+		}
+	}
+}
+
+/// Queue a file of the task and wait until it may be transferred. A wait
+/// that is cancelled before the slot arrives gives it back right away.
+pub async fn acquire(uuid: &str, sync_task: &SyncTask) -> Slot {
+	let (tx, rx) = oneshot::channel();
+	{
+		let mut pool = POOL.lock().unwrap();
+		let task = pool.task(uuid);
+		let transfer = Transfer {
+			taskUuid: uuid.to_string(),
+			path: sync_task.from.to_string_lossy().to_string(),
+			remotePath: sync_task.to.clone(),
+			size: sync_task.size,
+			direction: sync_task.direction,
+			priority: task.priority,
+			state: TransferState::Queued,
+			queuedAt: now_millis(),
+			startedAt: None,
+			finishedAt: None
+		};
+		task.waiting.push_back(Waiter { transfer, tx });
+		pool.dispatch();
+	}
+
+	// senders are only dropped after sending, or once this wait is gone
+	rx.await.unwrap()
+}
+
+/// Called when a task run starts, so changes to the task apply.
+pub fn set_limits(uuid: &str, priority: TaskPriority, max: usize) {
+	let mut pool = POOL.lock().unwrap();
+	let task = pool.task(uuid);
+	task.priority = priority;
+	task.max = max;
+	for waiter in task.waiting.iter_mut() {
+		waiter.transfer.priority = priority;
+	}
+	pool.dispatch();
+}
+
+/// Follow changed settings. Slots above a smaller size are not taken back,
+/// they just are not handed out again.
+pub fn configure(settings: &Settings) {
+	let mut pool = POOL.lock().unwrap();
+	let size = settings.uploadThreadNumber.max(1);
+	if pool.size != size {
+		debug!("Resize the transfer pool from {} to {}", pool.size, size);
+		pool.size = size;
+	}
+	pool.small_files_first = settings.smallFilesFirst;
+	pool.dispatch();
+}
+
+/// Files waiting for a slot, being transferred and recently finished, over all tasks.
+#[tauri::command]
+pub fn transfer_queue() -> String {
+	CommandResponse::ok(POOL.lock().unwrap().view()).to_string()
+}
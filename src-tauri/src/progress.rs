@@ -0,0 +1,295 @@
+use std::{collections::{HashMap, VecDeque}, pin::Pin, sync::Mutex, task::{Context, Poll}, time::{Duration, Instant}};
+
+use aws_sdk_s3::primitives::ByteStream;
+use aws_smithy_types::body::SdkBody;
+use bytes::Bytes;
+use http_body::{Body, SizeHint};
+use lazy_static::lazy_static;
+
+use crate::{events::{emit, FILE_DONE, FILE_FAILED, FILE_PROGRESS}, structs::{FileEvent, SyncTask, TaskProgress}, tray::refresh_tooltip};
+
+/// Throughput is averaged over the bytes sent during this window.
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(10);
+const FILE_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Default)]
+struct ProgressTracker {
+	progress: TaskProgress,
+	// bytes sent for the current attempt of every file being uploaded
+	files: HashMap<String, u64>,
+	samples: VecDeque<(Instant, u64)>,
+	// bytes of all samples, kept up to date so the rate is not summed per chunk
+	window_bytes: u64,
+	// last FILE_PROGRESS event of every file being transferred
+	last_file_events: HashMap<String, Instant>
+}
+
+impl ProgressTracker {
+	fn record(&mut self, path: &str, bytes: u64) {
+		*self.files.entry(path.to_string()).or_insert(0) += bytes;
+		self.progress.transferredBytes += bytes;
+
+		let now = Instant::now();
+		self.samples.push_back((now, bytes));
+		self.window_bytes += bytes;
+		while let Some((time, sent)) = self.samples.front() {
+			if now.duration_since(*time) > THROUGHPUT_WINDOW {
+				self.window_bytes -= *sent;
+				self.samples.pop_front();
+			} else {
+				break;
+			}
+		}
+		self.update_rate(now);
+	}
+
+	fn update_rate(&mut self, now: Instant) {
+		let sent = self.window_bytes;
+		let elapsed = match self.samples.front() {
+			Some((time, _)) => now.duration_since(*time).as_secs_f64().max(1.0),
+			None => THROUGHPUT_WINDOW.as_secs_f64()
+		};
+		self.progress.throughput = sent as f64 / elapsed;
+
+		let remaining = self.progress.totalBytes.saturating_sub(self.progress.transferredBytes);
+		self.progress.eta = if self.progress.throughput > 0.0 {
+			Some((remaining as f64 / self.progress.throughput).ceil() as u64)
+		} else {
+			None
+		};
+	}
+
+	/// Forget the partial bytes of a file, used when an attempt restarts or fails.
+	fn reset_file(&mut self, path: &str) {
+		self.last_file_events.remove(path);
+		if let Some(sent) = self.files.remove(path) {
+			self.progress.transferredBytes = self.progress.transferredBytes.saturating_sub(sent);
+		}
+	}
+}
+
+lazy_static! {
+	static ref SYNC_PROGRESS: Mutex<HashMap<String, ProgressTracker>> = Mutex::new(HashMap::new());
+}
+
+pub fn new_progress(uuid: &str, total: usize, total_bytes: u64) -> TaskProgress {
+	let mut tracker = ProgressTracker::default();
+	tracker.progress.total = total;
+	tracker.progress.totalBytes = total_bytes;
+	let progress = tracker.progress;
+	let mut progresses = SYNC_PROGRESS.lock().unwrap();
+	progresses.insert(uuid.to_string(), tracker);
+	progress
+}
+
+/// Count a file found after the run started, the scan keeps queueing files
+/// while the first ones transfer.
+pub fn file_queued(uuid: &str, sync_task: &SyncTask) {
+	let mut progresses = SYNC_PROGRESS.lock().unwrap();
+	if let Some(tracker) = progresses.get_mut(uuid) {
+		tracker.progress.total += 1;
+		tracker.progress.totalBytes += sync_task.size;
+	}
+}
+
+pub fn current_progress(uuid: &str) -> Option<TaskProgress> {
+	let progresses = SYNC_PROGRESS.lock().unwrap();
+	progresses.get(uuid).map(|t| t.progress)
+}
+
+pub fn remove_progress(uuid: &str) {
+	let mut progresses = SYNC_PROGRESS.lock().unwrap();
+	progresses.remove(uuid);
+	drop(progresses);
+	refresh_tooltip(true);
+}
+
+fn file_event(uuid: &str, sync_task: &SyncTask, bytes: u64, error: Option<String>) -> FileEvent {
+	FileEvent {
+		uuid: uuid.to_string(),
+		path: sync_task.from.to_string_lossy().to_string(),
+		remotePath: sync_task.to.clone(),
+		bytes,
+		totalBytes: sync_task.size,
+		error,
+		progress: current_progress(uuid).unwrap_or_default()
+	}
+}
+
+/// Mark a file as finished, `sent` is the number of bytes actually uploaded
+/// (zero for skipped files). Every finished file counts its whole size as
+/// transferred, so the bytes add up to `totalBytes` once the run is done.
+pub fn file_done(uuid: &str, sync_task: &SyncTask, sent: u64) {
+	let path = sync_task.from.to_string_lossy().to_string();
+	let mut progresses = SYNC_PROGRESS.lock().unwrap();
+	if let Some(tracker) = progresses.get_mut(uuid) {
+		tracker.reset_file(&path);
+		tracker.progress.transferredBytes += sync_task.size;
+		tracker.progress.increase();
+		tracker.update_rate(Instant::now());
+	}
+	drop(progresses);
+
+	emit(FILE_DONE, file_event(uuid, sync_task, sent, None));
+	refresh_tooltip(false);
+}
+
+pub fn file_failed<T: ToString>(uuid: &str, sync_task: &SyncTask, err: T) {
+	let path = sync_task.from.to_string_lossy().to_string();
+	let mut progresses = SYNC_PROGRESS.lock().unwrap();
+	if let Some(tracker) = progresses.get_mut(uuid) {
+		tracker.reset_file(&path);
+	}
+	drop(progresses);
+
+	emit(FILE_FAILED, file_event(uuid, sync_task, 0, Some(err.to_string())));
+}
+
+/// Count a file as handled without transferring it, e.g. when the remote rejects it.
+pub fn file_skipped(uuid: &str, sync_task: &SyncTask) {
+	file_done(uuid, sync_task, 0);
+}
+
+fn file_sent(uuid: &str, sync_task: &SyncTask, path: &str, bytes: u64) {
+	let mut progresses = SYNC_PROGRESS.lock().unwrap();
+	let sent = match progresses.get_mut(uuid) {
+		Some(tracker) => {
+			tracker.record(path, bytes);
+			let now = Instant::now();
+			let due = match tracker.last_file_events.get(path) {
+				Some(last) => now.duration_since(*last) >= FILE_PROGRESS_INTERVAL,
+				None => true
+			};
+			if due {
+				tracker.last_file_events.insert(path.to_string(), now);
+				tracker.files.get(path).copied()
+			} else {
+				None
+			}
+		},
+		None => None
+	};
+	drop(progresses);
+
+	if let Some(sent) = sent {
+		emit(FILE_PROGRESS, file_event(uuid, sync_task, sent, None));
+		refresh_tooltip(false);
+	}
+}
+
+/// Count bytes written by a download, the counterpart of `counting_body`.
+pub fn file_received(uuid: &str, sync_task: &SyncTask, bytes: u64) {
+	let path = sync_task.from.to_string_lossy().to_string();
+	file_sent(uuid, sync_task, &path, bytes);
+}
+
+fn file_restarted(uuid: &str, path: &str) {
+	let mut progresses = SYNC_PROGRESS.lock().unwrap();
+	if let Some(tracker) = progresses.get_mut(uuid) {
+		tracker.reset_file(path);
+	}
+}
+
+/// Wraps an upload body and reports every chunk handed to the HTTP client.
+struct CountingBody {
+	inner: SdkBody,
+	uuid: String,
+	sync_task: SyncTask,
+	path: String
+}
+
+impl Body for CountingBody {
+	type Data = Bytes;
+	type Error = aws_smithy_types::body::Error;
+
+	fn poll_data(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+		let poll = Pin::new(&mut self.inner).poll_data(cx);
+		if let Poll::Ready(Some(Ok(data))) = &poll {
+			file_sent(&self.uuid, &self.sync_task, &self.path, data.len() as u64);
+		}
+		poll
+	}
+
+	fn poll_trailers(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+		Pin::new(&mut self.inner).poll_trailers(cx)
+	}
+
+	fn is_end_stream(&self) -> bool {
+		Body::is_end_stream(&self.inner)
+	}
+
+	fn size_hint(&self) -> SizeHint {
+		Body::size_hint(&self.inner)
+	}
+}
+
+/// Attach byte counting to an upload body. The SDK rebuilds the body on
+/// retries, so every rebuild starts the file from zero again.
+pub fn counting_body(uuid: &str, sync_task: &SyncTask, body: ByteStream) -> ByteStream {
+	let uuid = uuid.to_string();
+	let sync_task = sync_task.clone();
+	let path = sync_task.from.to_string_lossy().to_string();
+
+	ByteStream::new(body.into_inner().map(move |inner| {
+		file_restarted(&uuid, &path);
+		SdkBody::from_body_0_4(CountingBody {
+			inner,
+			uuid: uuid.clone(),
+			sync_task: sync_task.clone(),
+			path: path.clone()
+		})
+	}))
+}
+
+pub fn format_bytes(bytes: f64) -> String {
+	let units = ["B", "KB", "MB", "GB", "TB"];
+	let mut value = bytes;
+	let mut unit = 0;
+	while value >= 1024.0 && unit < units.len() - 1 {
+		value /= 1024.0;
+		unit += 1;
+	}
+	format!("{:.1} {}", value, units[unit])
+}
+
+pub fn format_duration(seconds: u64) -> String {
+	if seconds >= 3600 {
+		format!("{}h {}m", seconds / 3600, seconds % 3600 / 60)
+	} else if seconds >= 60 {
+		format!("{}m {}s", seconds / 60, seconds % 60)
+	} else {
+		format!("{}s", seconds)
+	}
+}
+
+/// One line describing all running tasks, `None` when nothing is syncing.
+pub fn progress_summary() -> Option<String> {
+	let progresses = SYNC_PROGRESS.lock().unwrap();
+	if progresses.is_empty() {
+		return None;
+	}
+
+	let mut total = TaskProgress::default();
+	for tracker in progresses.values() {
+		total.total += tracker.progress.total;
+		total.current += tracker.progress.current;
+		total.totalBytes += tracker.progress.totalBytes;
+		total.transferredBytes += tracker.progress.transferredBytes;
+		total.throughput += tracker.progress.throughput;
+	}
+
+	let mut text = format!(
+		"Syncing {} task(s): {}/{} files, {} of {}, {}/s",
+		progresses.len(),
+		total.current, total.total,
+		format_bytes(total.transferredBytes as f64),
+		format_bytes(total.totalBytes as f64),
+		format_bytes(total.throughput)
+	);
+	if total.throughput > 0.0 {
+		let remaining = total.totalBytes.saturating_sub(total.transferredBytes);
+		let eta = (remaining as f64 / total.throughput).ceil() as u64;
+		text.push_str(&format!(", {} left", format_duration(eta)));
+	}
+	Some(text)
+}
@@ -0,0 +1,114 @@
+use std::{collections::HashSet, fs::{read_dir, read_link, FileType}, path::{Path, PathBuf}};
+
+use tracing::{debug, info, warn};
+use walkdir::{DirEntry, WalkDir};
+
+use crate::structs::SymlinkPolicy;
+
+/// What the scan does with a walked entry.
+pub enum EntryKind {
+	File,
+	/// A symlink uploaded as a descriptor object holding its target
+	Link(PathBuf),
+	/// A folder with nothing in it, created remotely as a folder node
+	EmptyDir,
+	Skip
+}
+
+/// Walks a task folder and applies its symlink policy. Files reachable
+/// through several hardlinks or followed symlinks are only queued once.
+pub struct Scanner {
+	policy: SymlinkPolicy,
+	seen: HashSet<(u64, u64)>
+}
+
+impl Scanner {
+	pub fn new(policy: SymlinkPolicy) -> Self {
+		Self {
+			policy,
+			seen: HashSet::new()
+		}
+	}
+
+	/// Following links makes walkdir report loops as errors instead of descending forever.
+	pub fn walk(&self, root: &Path) -> WalkDir {
+		WalkDir::new(root).follow_links(self.policy == SymlinkPolicy::Follow)
+	}
+
+	pub fn classify(&mut self, entry: &DirEntry) -> EntryKind {
+		let file_type = entry.file_type();
+
+		if file_type.is_symlink() {
+			return match self.policy {
+				SymlinkPolicy::Descriptor => match read_link(entry.path()) {
+					Ok(target) => EntryKind::Link(target),
+					Err(e) => {
+						warn!("Failed to read symlink {:?}: {}", entry.path(), e);
+						EntryKind::Skip
+					}
+				},
+				// a symlink still reported while following links is dangling
+				_ => {
+					debug!("Skip symlink {:?}", entry.path());
+					EntryKind::Skip
+				}
+			};
+		}
+
+		if file_type.is_dir() {
+			let empty = entry.depth() > 0 && read_dir(entry.path())
+				.map(|mut d| d.next().is_none())
+				.unwrap_or(false);
+			return if empty { EntryKind::EmptyDir } else { EntryKind::Skip };
+		}
+
+		if file_type.is_file() {
+			if let Some(id) = file_id(entry) {
+				if !self.seen.insert(id) {
+					info!("Skip {:?}, the same file is already queued through another link", entry.path());
+					return EntryKind::Skip;
+				}
+			}
+			return EntryKind::File;
+		}
+
+		info!("Skip {:?}, {} are not synced", entry.path(), special_kind(&file_type));
+		EntryKind::Skip
+	}
+}
+
+/// Device and inode of files that may be reached through more than one path.
+#[cfg(unix)]
+fn file_id(entry: &DirEntry) -> Option<(u64, u64)> {
+	use std::os::unix::fs::MetadataExt;
+	let m = entry.metadata().ok()?;
+	if m.nlink() > 1 || entry.path_is_symlink() {
+		Some((m.dev(), m.ino()))
+	} else {
+		None
+	}
+}
+
+#[cfg(not(unix))]
+fn file_id(_entry: &DirEntry) -> Option<(u64, u64)> {
+	None
+}
+
+#[cfg(unix)]
+fn special_kind(file_type: &FileType) -> &'static str {
+	use std::os::unix::fs::FileTypeExt;
+	if file_type.is_socket() {
+		"sockets"
+	} else if file_type.is_fifo() {
+		"FIFOs"
+	} else if file_type.is_block_device() || file_type.is_char_device() {
+		"devices"
+	} else {
+		"special files"
+	}
+}
+
+#[cfg(not(unix))]
+fn special_kind(_file_type: &FileType) -> &'static str {
+	"special files"
+}
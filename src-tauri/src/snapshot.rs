@@ -0,0 +1,94 @@
+use std::{fs::{copy, create_dir_all, metadata, remove_file}, io, path::{Path, PathBuf}, sync::atomic::{AtomicU64, Ordering}, time::{Duration, SystemTime}};
+
+use tracing::{debug, warn};
+
+use crate::PACKAGE_NAME;
+
+const SNAPSHOTS_DIR_NAME: &str = "snapshots";
+
+static NEXT_SNAPSHOT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Size and modification time of a file, compared before and after an
+/// upload to notice writes that happened in between.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStamp {
+	pub size: u64,
+	pub modified: Option<SystemTime>
+}
+
+impl FileStamp {
+	pub fn of(path: &Path) -> io::Result<Self> {
+		let m = metadata(path)?;
+		Ok(Self {
+			size: m.len(),
+			modified: m.modified().ok()
+		})
+	}
+
+	/// Whether the file has not been written to for at least `settle`.
+	/// Files with an unknown or future modification time count as settled.
+	pub fn settled(&self, settle: Duration) -> bool {
+		match self.modified {
+			Some(modified) => match SystemTime::now().duration_since(modified) {
+				Ok(age) => age >= settle,
+				Err(_) => true
+			},
+			None => true
+		}
+	}
+
+	/// Whether the file at `path` still looks the same as when it was stamped.
+	pub fn unchanged(&self, path: &Path) -> bool {
+		match Self::of(path) {
+			Ok(now) => now == *self,
+			Err(_) => false
+		}
+	}
+}
+
+fn get_snapshots_dir() -> PathBuf {
+	let mut p = std::env::temp_dir();
+	p.push(PACKAGE_NAME);
+	p.push(SNAPSHOTS_DIR_NAME);
+	p
+}
+
+/// A private copy of a file, so the upload and its ETag see the same
+/// content even if the original is written to meanwhile. The copy is
+/// removed when the snapshot is dropped.
+pub struct Snapshot {
+	path: PathBuf
+}
+
+impl Snapshot {
+	/// Copied on a blocking thread, the file may be large.
+	pub async fn take(source: &Path) -> io::Result<Self> {
+		let source = source.to_path_buf();
+		tokio::task::spawn_blocking(move || Self::copy(&source))
+			.await
+			.unwrap_or_else(|e| Err(io::Error::other(e)))
+	}
+
+	fn copy(source: &Path) -> io::Result<Self> {
+		let dir = get_snapshots_dir();
+		create_dir_all(&dir)?;
+
+		let mut path = dir;
+		path.push(format!("{}-{}", std::process::id(), NEXT_SNAPSHOT_ID.fetch_add(1, Ordering::Relaxed)));
+		copy(source, &path)?;
+		debug!("Snapshot {:?} to {:?}", source, &path);
+		Ok(Self { path })
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+}
+
+impl Drop for Snapshot {
+	fn drop(&mut self) {
+		if let Err(e) = remove_file(&self.path) {
+			warn!("Failed to remove snapshot {:?}: {}", &self.path, e);
+		}
+	}
+}
@@ -0,0 +1,209 @@
+use std::{collections::HashMap, fs::{create_dir_all, rename, File, OpenOptions}, io::{Read, Write}, path::PathBuf, sync::Mutex};
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{data::get_config_dir, history::now_millis, structs::SyncTask};
+
+const STATE_FILE_NAME: &str = "state.json";
+
+/// What the engine remembers about a task between runs.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TaskState {
+	/// Files that were queued or uploading when the last run was stopped
+	pub pending: Vec<SyncTask>,
+	pub interruptedAt: Option<u64>,
+	/// ETags of encrypted files by remote key, see `CipherEtag`
+	pub cipherEtags: HashMap<String, CipherEtag>,
+	/// Originals of compressed files of encrypted tasks by key, see `CompressedEtag`
+	pub compressedEtags: HashMap<String, CompressedEtag>,
+	/// ETag both sides of a two-way task last had, by remote key
+	pub syncedEtags: HashMap<String, String>
+}
+
+/// The server only knows the ETag of the ciphertext of an encrypted file,
+/// it is mapped back to the plaintext one through this record.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct CipherEtag {
+	pub ciphertext: String,
+	pub plaintext: String
+}
+
+/// MD5 and size of a file before it was compressed. Encrypted tasks keep
+/// them here rather than in the object metadata, which is not encrypted.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct CompressedEtag {
+	/// ETag of the compressed content
+	pub stored: String,
+	pub original: String,
+	pub size: u64
+}
+
+#[derive(Debug, Default)]
+struct StateStore {
+	tasks: HashMap<String, TaskState>,
+	loaded: bool,
+	dirty: bool
+}
+
+lazy_static! {
+	static ref STATE: Mutex<StateStore> = Mutex::new(StateStore::default());
+}
+
+fn get_state_path() -> PathBuf {
+	let mut p = get_config_dir();
+	p.push(STATE_FILE_NAME);
+	p
+}
+
+fn load(store: &mut StateStore) {
+	if store.loaded {
+		return;
+	}
+	store.loaded = true;
+
+	let mut content = String::new();
+	match File::open(get_state_path()) {
+		Ok(mut f) => {
+			if let Err(e) = f.read_to_string(&mut content) {
+				warn!("Failed to read sync state: {}", e);
+				return;
+			}
+		},
+		Err(_) => return
+	}
+
+	match serde_json::from_str(&content) {
+		Ok(tasks) => store.tasks = tasks,
+		Err(e) => warn!("Failed to parse sync state, starting over: {}", e)
+	}
+}
+
+pub fn get_task_state(uuid: &str) -> TaskState {
+	let mut store = STATE.lock().unwrap();
+	load(&mut store);
+	store.tasks.get(uuid).cloned().unwrap_or_default()
+}
+
+/// Remember the files a stopped run did not get to, they go first on the next run.
+pub fn save_pending(uuid: &str, pending: Vec<SyncTask>) {
+	let mut store = STATE.lock().unwrap();
+	load(&mut store);
+	let state = store.tasks.entry(uuid.to_string()).or_default();
+	state.pending = pending;
+	state.interruptedAt = Some(now_millis());
+	store.dirty = true;
+}
+
+/// Forget the checkpoint of a task after a run went through all of its files.
+pub fn clear_pending(uuid: &str) {
+	let mut store = STATE.lock().unwrap();
+	load(&mut store);
+	if let Some(state) = store.tasks.get_mut(uuid) {
+		if !state.pending.is_empty() || state.interruptedAt.is_some() {
+			state.pending.clear();
+			state.interruptedAt = None;
+			store.dirty = true;
+		}
+	}
+}
+
+pub fn get_cipher_etag(uuid: &str, key: &str) -> Option<CipherEtag> {
+	let mut store = STATE.lock().unwrap();
+	load(&mut store);
+	store.tasks.get(uuid).and_then(|s| s.cipherEtags.get(key).cloned())
+}
+
+/// Remember the ETags of an encrypted file that was uploaded or downloaded,
+/// `None` forgets them.
+pub fn set_cipher_etag(uuid: &str, key: &str, etag: Option<CipherEtag>) {
+	let mut store = STATE.lock().unwrap();
+	load(&mut store);
+	let state = store.tasks.entry(uuid.to_string()).or_default();
+	match etag {
+		Some(e) => state.cipherEtags.insert(key.to_string(), e),
+		None => state.cipherEtags.remove(key)
+	};
+	store.dirty = true;
+}
+
+pub fn get_synced_etag(uuid: &str, key: &str) -> Option<String> {
+	let mut store = STATE.lock().unwrap();
+	load(&mut store);
+	store.tasks.get(uuid).and_then(|s| s.syncedEtags.get(key).cloned())
+}
+
+/// Remember the content a file had on both sides after a two-way transfer,
+/// changes since then tell which side has to go where.
+pub fn set_synced_etag(uuid: &str, key: &str, etag: Option<String>) {
+	let mut store = STATE.lock().unwrap();
+	load(&mut store);
+	let state = store.tasks.entry(uuid.to_string()).or_default();
+	match etag {
+		Some(e) => state.syncedEtags.insert(key.to_string(), e),
+		None => state.syncedEtags.remove(key)
+	};
+	store.dirty = true;
+}
+
+pub fn get_compressed_etag(uuid: &str, key: &str) -> Option<CompressedEtag> {
+	let mut store = STATE.lock().unwrap();
+	load(&mut store);
+	store.tasks.get(uuid).and_then(|s| s.compressedEtags.get(key).cloned())
+}
+
+/// Remember the original of a compressed file that was uploaded or
+/// downloaded, `None` forgets it.
+pub fn set_compressed_etag(uuid: &str, key: &str, etag: Option<CompressedEtag>) {
+	let mut store = STATE.lock().unwrap();
+	load(&mut store);
+	let state = store.tasks.entry(uuid.to_string()).or_default();
+	match etag {
+		Some(e) => state.compressedEtags.insert(key.to_string(), e),
+		None => state.compressedEtags.remove(key)
+	};
+	store.dirty = true;
+}
+
+/// Write the state to disk if it changed. The file is replaced atomically so
+/// a crash while writing never leaves a truncated state behind.
+pub fn flush_state() {
+	let mut store = STATE.lock().unwrap();
+	if !store.dirty {
+		return;
+	}
+
+	let content = match serde_json::to_string(&store.tasks) {
+		Ok(c) => c,
+		Err(e) => {
+			warn!("Failed to serialize sync state: {}", e);
+			return;
+		}
+	};
+
+	let path = get_state_path();
+	if !path.exists() {
+		let _ = create_dir_all(path.parent().unwrap());
+	}
+	let tmp_path = path.with_extension("json.tmp");
+	let written = OpenOptions::new()
+		.write(true)
+		.create(true)
+		.truncate(true)
+		.open(&tmp_path)
+		.and_then(|mut f| {
+			f.write_all(content.as_bytes())?;
+			f.sync_all()
+		})
+		.and_then(|_| rename(&tmp_path, &path));
+
+	match written {
+		Ok(_) => {
+			store.dirty = false;
+			info!("Save sync state to {:?}", &path);
+		},
+		Err(e) => warn!("Failed to save sync state to {:?}: {}", &path, e)
+	}
+}
@@ -1,30 +1,37 @@
 use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde_json::{json, Value};
+use tracing::warn;
+
+use crate::error::ErrorKind;
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct CommandResponse<T: Default+Serialize> {
 	success: bool,
 	data: T,
-	message: String
+	message: String,
+	kind: Option<ErrorKind>
 }
 
 impl<T: Default+Serialize> CommandResponse<T> {
 	pub fn ok(data: T) -> Self {
-		Self { success: true, data, message: "".to_string() }
+		Self { success: true, data, message: "".to_string(), kind: None }
 	}
 	pub fn err<S: ToString>(message: S) -> Self {
-		Self { success: false, data: T::default(), message: message.to_string() }
+		Self { success: false, data: T::default(), message: message.to_string(), kind: None }
+	}
+	pub fn fail<S: ToString>(kind: ErrorKind, message: S) -> Self {
+		Self { success: false, data: T::default(), message: message.to_string(), kind: Some(kind) }
 	}
 }
 
 impl CommandResponse<()> {
 	pub fn empty_ok() -> Self {
-		Self { success: true, data: (), message: "".to_string() }
+		Self { success: true, data: (), message: "".to_string(), kind: None }
 	}
 	pub fn empty_err() -> Self {
-		Self { success: false, data: (), message: "".to_string() }
+		Self { success: false, data: (), message: "".to_string(), kind: None }
 	}
 }
 
@@ -59,6 +66,9 @@ pub struct BulkNode {
 	Type: String,
 	#[serde(default)]
 	pub Etag: String,
+	/// Cells sends 64 bit integers as strings
+	#[serde(default)]
+	pub Size: String,
 	#[serde(default)]
 	MetaStore: BulkMetaStore
 }
@@ -70,9 +80,61 @@ pub struct BulkMetaStore {
 	#[serde(default)]
 	ws_syncable: String,
 	#[serde(default)]
+	ws_permissions: String,
+	#[serde(default)]
 	name: String
 }
 
+/// Meta values are JSON documents kept in a string.
+fn meta_value(raw: &str) -> Value {
+	serde_json::from_str(raw).unwrap_or_else(|_| Value::String(raw.to_string()))
+}
+
+impl BulkNode {
+	/// Read a node listed at the root, where every node is a workspace.
+	pub fn workspace(&self) -> Workspace {
+		let meta = &self.MetaStore;
+		let label = match meta_value(&meta.ws_label) {
+			Value::String(l) if !l.is_empty() => l,
+			_ => self.Path.trim_matches('/').to_string()
+		};
+		let permissions = match meta_value(&meta.ws_permissions) {
+			Value::String(p) => p,
+			_ => String::new()
+		};
+		// servers that do not tell are not second-guessed
+		let syncable = match meta_value(&meta.ws_syncable) {
+			Value::Bool(b) => b,
+			Value::String(s) => s != "false",
+			_ => true
+		};
+		Workspace {
+			uuid: self.Uuid.clone(),
+			slug: self.Path.trim_matches('/').to_string(),
+			label,
+			readable: permissions.is_empty() || permissions.contains('r'),
+			writable: permissions.is_empty() || permissions.contains('w'),
+			permissions,
+			syncable
+		}
+	}
+}
+
+/// A workspace of the logged in user.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Workspace {
+	pub uuid: String,
+	/// First folder of the paths in the workspace
+	pub slug: String,
+	pub label: String,
+	/// As reported by the server, such as `r` or `rw`
+	pub permissions: String,
+	pub readable: bool,
+	pub writable: bool,
+	/// Whether the workspace lets sync clients in
+	pub syncable: bool
+}
+
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct SessionData {
 	pub JWT: String,
@@ -87,10 +149,156 @@ pub struct TokenData {
 	pub ExpiresAt: String
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub enum SyncDirection {
+	#[default]
+	Upload,
+	Download,
+	/// Changes go either way, a file changed on both sides is kept twice
+	Both
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub enum SymlinkPolicy {
+	#[default]
+	Skip,
+	/// Sync what the link points to, loops are detected and skipped
+	Follow,
+	/// Upload a small object holding the link target, recreated on download
+	Descriptor
+}
+
+/// Client side encryption of a task, its passphrase is kept in the keyring.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub enum EncryptionMode {
+	#[default]
+	Off,
+	Content,
+	/// Names are encrypted one path component at a time
+	ContentAndNames
+}
+
+/// Files of a task stored zstd compressed, matched by name.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+	pub enabled: bool,
+	pub patterns: Vec<String>
+}
+
+impl Default for CompressionConfig {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			patterns: ["*.txt", "*.log", "*.csv", "*.json", "*.xml", "*.sql"].map(String::from).to_vec()
+		}
+	}
+}
+
+/// Replaced files of a task are kept as versions, pruned after every run.
+/// A version is kept while any of the rules keeps it.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BackupConfig {
+	pub enabled: bool,
+	/// Newest versions of a file always kept
+	pub keepLast: usize,
+	/// The newest version of each day is kept for this many days
+	pub keepDailyDays: u32,
+	/// The newest version of each month is kept for this many months
+	pub keepMonthlyMonths: u32
+}
+
+impl Default for BackupConfig {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			keepLast: 10,
+			keepDailyDays: 30,
+			keepMonthlyMonths: 12
+		}
+	}
+}
+
+/// What happens to a local file once it is archived.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub enum ArchiveAction {
+	#[default]
+	Delete,
+	/// Move it to `trashDir`, keeping its folders in the task
+	Trash
+}
+
+/// Files of an upload task are removed locally once the server copy is
+/// verified, freeing the disk they were on.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ArchiveConfig {
+	pub enabled: bool,
+	pub action: ArchiveAction,
+	pub trashDir: String,
+	/// Files modified more recently stay, 0 to archive them right away
+	pub minAgeHours: u64,
+	/// Files are only archived while the disk has less free space, 0 to always archive them
+	pub evictBelowFreeGb: u64
+}
+
+/// Order in which running tasks get transfer slots, higher first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Deserialize, Serialize)]
+pub enum TaskPriority {
+	Low,
+	#[default]
+	Normal,
+	High
+}
+
+/// Unicode form remote names are written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub enum NameNormalization {
+	/// Keep names exactly as the file system reports them
+	None,
+	/// Composed form, used by Linux, Windows and the Cells web interface
+	#[default]
+	Nfc,
+	/// Decomposed form, as reported by older macOS file systems
+	Nfd
+}
+
+/// What happens to files whose name the server would reject.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub enum InvalidNamePolicy {
+	#[default]
+	Skip,
+	/// Percent-encode the offending characters
+	Escape
+}
+
+/// Content of a placeholder, standing in for a remote file that was not
+/// downloaded.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Placeholder {
+	pub remotePath: String,
+	pub size: u64
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct HydrateResult {
+	pub hydrated: usize,
+	/// Placeholders whose file could not be downloaded
+	pub failed: Vec<String>
+}
+
+/// A file to transfer, `from` is always the local path and `to` the remote key.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct SyncTask {
 	pub from: PathBuf,
-	pub to: String
+	pub to: String,
+	pub size: u64,
+	#[serde(default)]
+	pub direction: SyncDirection,
+	/// Target of a symlink uploaded as a descriptor
+	#[serde(default)]
+	pub link: Option<PathBuf>
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
@@ -101,7 +309,71 @@ pub struct TaskData {
 	pub remoteDir: BulkNode,
 	pub paused: bool,
 	pub repeatInterval: f64,
-	pub repeatIntervalUnit: TimeUnit
+	pub repeatIntervalUnit: TimeUnit,
+	#[serde(default)]
+	pub direction: SyncDirection,
+	#[serde(default)]
+	pub symlinkPolicy: SymlinkPolicy,
+	#[serde(default)]
+	pub backend: BackendConfig,
+	#[serde(default)]
+	pub priority: TaskPriority,
+	/// Files of this task transferred at once, 0 to use the whole pool
+	#[serde(default)]
+	pub maxTransfers: usize,
+	#[serde(default)]
+	pub encryption: EncryptionMode,
+	#[serde(default)]
+	pub compression: CompressionConfig,
+	#[serde(default)]
+	pub backup: BackupConfig,
+	/// Only used by upload tasks
+	#[serde(default)]
+	pub archive: ArchiveConfig,
+	/// Folders below `remoteDir` downloaded by download and two-way tasks,
+	/// the rest only gets placeholders. Empty to download everything.
+	#[serde(default)]
+	pub selectedFolders: Vec<String>
+}
+
+/// Where a task stores its files. `remoteDir.Path` is the folder inside it.
+#[derive(Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "type")]
+pub enum BackendConfig {
+	/// The Cells server the app is logged in to
+	#[default]
+	Cells,
+	/// Any S3 compatible storage, such as MinIO
+	S3 {
+		endpoint: String,
+		region: String,
+		bucket: String,
+		accessKey: String,
+		/// Empty once saved, the key is kept in the keyring
+		#[serde(default)]
+		secretKey: String
+	},
+	/// A folder on this machine or a mounted network share
+	Local {
+		root: PathBuf
+	}
+}
+
+// tasks are logged, the secret key must not end up in the logs
+impl std::fmt::Debug for BackendConfig {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Cells => write!(f, "Cells"),
+			Self::S3 { endpoint, region, bucket, accessKey, .. } => f
+				.debug_struct("S3")
+				.field("endpoint", endpoint)
+				.field("region", region)
+				.field("bucket", bucket)
+				.field("accessKey", accessKey)
+				.finish_non_exhaustive(),
+			Self::Local { root } => f.debug_struct("Local").field("root", root).finish()
+		}
+	}
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
@@ -113,7 +385,13 @@ pub struct TimeUnit {
 #[derive(Debug, Copy, Clone, Default, Deserialize, Serialize)]
 pub struct TaskProgress {
 	pub total: usize,
-	pub current: usize
+	pub current: usize,
+	pub totalBytes: u64,
+	pub transferredBytes: u64,
+	/// Bytes per second, averaged over the last few seconds
+	pub throughput: f64,
+	/// Estimated seconds left
+	pub eta: Option<u64>
 }
 
 impl TaskProgress {
@@ -122,7 +400,129 @@ impl TaskProgress {
 	}
 }
 
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TaskEvent {
+	pub uuid: String,
+	pub progress: TaskProgress
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FileEvent {
+	pub uuid: String,
+	pub path: String,
+	pub remotePath: String,
+	pub bytes: u64,
+	pub totalBytes: u64,
+	pub error: Option<String>,
+	pub progress: TaskProgress
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub enum RunOutcome {
+	#[default]
+	Running,
+	Completed,
+	CompletedWithErrors,
+	Cancelled
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RunRecord {
+	pub id: String,
+	pub taskUuid: String,
+	pub trigger: String,
+	pub startedAt: u64,
+	pub finishedAt: Option<u64>,
+	pub total: usize,
+	pub uploaded: usize,
+	#[serde(default)]
+	pub downloaded: usize,
+	pub skipped: usize,
+	pub failed: usize,
+	pub bytes: u64,
+	pub outcome: RunOutcome
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub enum FileAction {
+	#[default]
+	Uploaded,
+	Downloaded,
+	Skipped,
+	Failed,
+	Rejected,
+	/// Modified too recently, left for a later run
+	Deferred,
+	/// Removed locally after it was uploaded, recorded next to the upload
+	Archived,
+	/// Changed on both sides, the local file was kept as a copy before the
+	/// download recorded next to it
+	Conflict
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct FileRecord {
+	pub runId: String,
+	pub taskUuid: String,
+	pub time: u64,
+	pub path: String,
+	pub remotePath: String,
+	pub action: FileAction,
+	pub bytes: u64,
+	pub error: Option<String>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub enum TransferState {
+	#[default]
+	Queued,
+	Active,
+	Done,
+	Failed
+}
+
+/// A file in the transfer pool, or one that recently left it.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Transfer {
+	pub taskUuid: String,
+	pub path: String,
+	pub remotePath: String,
+	pub size: u64,
+	pub direction: SyncDirection,
+	pub priority: TaskPriority,
+	pub state: TransferState,
+	/// Milliseconds since epoch, like the other times
+	pub queuedAt: u64,
+	pub startedAt: Option<u64>,
+	pub finishedAt: Option<u64>
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct TransferQueue {
+	/// In the order they are expected to start, only the first ones
+	pub queued: Vec<Transfer>,
+	pub queuedTotal: usize,
+	pub active: Vec<Transfer>,
+	/// Newest first
+	pub finished: Vec<Transfer>
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HistoryQuery {
+	pub taskUuid: Option<String>,
+	pub runId: Option<String>,
+	/// Inclusive lower bound, milliseconds since epoch
+	pub from: Option<u64>,
+	/// Inclusive upper bound, milliseconds since epoch
+	pub to: Option<u64>,
+	/// A `RunOutcome` for runs or a `FileAction` for files
+	pub status: Option<String>,
+	pub limit: Option<usize>
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct Settings {
 	pub startWithLogin: bool,
 	pub showTrayIcon: bool,
@@ -130,6 +530,23 @@ pub struct Settings {
 	pub continueWhenUsingBattery: bool,
 	pub notificationWhenFailed: bool,
 	pub uploadThreadNumber: usize,
+	/// Start the smallest waiting files of a task first
+	pub smallFilesFirst: bool,
+	/// Check size and ETag of every uploaded file against the server
+	pub verifyUploads: bool,
+	/// Files modified less than this many seconds ago are left for a later run
+	pub settleSeconds: u64,
+	/// Files up to this many megabytes are copied before uploading, 0 disables it
+	pub snapshotSizeLimit: u64,
+	/// Also keep mode and owner of files, not only the modification time
+	pub preservePosixMetadata: bool,
+	/// Give downloaded files their stored owner, only done when running as root
+	pub restoreOwner: bool,
+	pub nameNormalization: NameNormalization,
+	pub invalidNames: InvalidNamePolicy,
+	pub historyRetentionDays: u64,
+	/// One of `error`, `warn`, `info`, `debug` or `trace`
+	pub logLevel: String,
 }
 
 impl ToString for Settings {
@@ -146,7 +563,17 @@ impl Default for Settings {
 			globalIgnores: vec![],
 			continueWhenUsingBattery: true,
 			notificationWhenFailed: false,
-			uploadThreadNumber: 8
+			uploadThreadNumber: 8,
+			smallFilesFirst: false,
+			verifyUploads: false,
+			settleSeconds: 5,
+			snapshotSizeLimit: 0,
+			preservePosixMetadata: false,
+			restoreOwner: false,
+			nameNormalization: NameNormalization::Nfc,
+			invalidNames: InvalidNamePolicy::Skip,
+			historyRetentionDays: 30,
+			logLevel: "info".to_string()
 		}
 	}
 }
@@ -156,7 +583,7 @@ pub fn parse_json<'a, T: Default + Deserialize<'a>>(data: &'a str) -> T {
 	match result {
 		Ok(r) => r,
 		Err(e) => {
-			println!("Error parsing json: {}", e);
+			warn!("Error parsing json: {}", e);
 			T::default()
 		}
 	}
@@ -0,0 +1,102 @@
+//! Throughput of small uploads against `MockCells`, run with
+//! `cargo test --release -- --ignored --nocapture bench`. The sync engine
+//! uploads a fixture of small files, then the same files go through a fresh
+//! client each, like transfers did before clients were kept per session.
+//! Both rates are reported, the test fails when the engine is not faster.
+
+use std::{collections::HashMap, fs, path::Path, time::{Duration, Instant}};
+
+use aws_sdk_s3::primitives::ByteStream;
+use tokio::task::JoinSet;
+
+use crate::{
+	backend::{RemoteBackend, S3Backend},
+	net::{is_running, sync},
+	structs::SyncDirection
+};
+
+use super::{login_to, mock_cells::BUCKET, start_server, task, SERIAL};
+
+const FILES: usize = 10_000;
+/// Same as `uploadThreadNumber` in the settings of the tests.
+const IN_FLIGHT: usize = 2;
+
+/// Requests and files per second of one way to upload the fixture.
+struct Rate {
+	requests: f64,
+	files: f64
+}
+
+impl Rate {
+	fn of(requests: usize, elapsed: Duration) -> Self {
+		Self {
+			requests: requests as f64 / elapsed.as_secs_f64(),
+			files: FILES as f64 / elapsed.as_secs_f64()
+		}
+	}
+}
+
+fn write_fixture(dir: &Path) {
+	for i in 0..FILES {
+		fs::write(dir.join(format!("{}.txt", i)), format!("file {}", i)).unwrap();
+	}
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn bench_small_files() {
+	let _serial = SERIAL.lock().await;
+	let server = start_server().await;
+	login_to(&server).await;
+	let local = tempfile::tempdir().unwrap();
+	write_fixture(local.path());
+
+	let before = server.requests();
+	let started = Instant::now();
+	let upload = task("bench", local.path(), "personal/bench", SyncDirection::Upload);
+	sync(upload.clone(), vec![], Some("bench".to_string())).await;
+	tokio::time::timeout(Duration::from_secs(600), async {
+		while is_running(&upload.uuid) {
+			tokio::time::sleep(Duration::from_millis(50)).await;
+		}
+	}).await.expect("task did not finish");
+	let engine = Rate::of(server.requests() - before, started.elapsed());
+	assert_eq!(server.puts(), FILES);
+
+	let before = server.requests();
+	let started = Instant::now();
+	let mut running = JoinSet::new();
+	for i in 0..FILES {
+		if running.len() >= IN_FLIGHT {
+			running.join_next().await.unwrap().unwrap();
+		}
+		let endpoint = server.endpoint.clone();
+		running.spawn(async move {
+			// the mock does not check S3 credentials
+			let backend = S3Backend::connect(&endpoint, "auto", BUCKET, "bench", "bench");
+			let key = format!("personal/baseline/{}.txt", i);
+			backend.stat(&key).await.unwrap();
+			backend.put(&key, ByteStream::from(format!("file {}", i).into_bytes()), None, HashMap::new()).await.unwrap();
+		});
+	}
+	while let Some(res) = running.join_next().await {
+		res.unwrap();
+	}
+	let baseline = Rate::of(server.requests() - before, started.elapsed());
+	assert_eq!(server.puts(), 2 * FILES);
+
+	eprintln!(
+		"{} files: sync engine {:.0} requests/s, {:.0} files/s; fresh client per file {:.0} requests/s, {:.0} files/s",
+		FILES,
+		engine.requests,
+		engine.files,
+		baseline.requests,
+		baseline.files
+	);
+	assert!(
+		engine.files > baseline.files,
+		"the sync engine uploaded {:.0} files/s, slower than {:.0} files/s with a fresh client per file",
+		engine.files,
+		baseline.files
+	);
+}
@@ -0,0 +1,501 @@
+use std::{collections::{BTreeMap, BTreeSet, HashMap}, convert::Infallible, fs, net::SocketAddr, path::PathBuf, sync::{Arc, Mutex}};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use hyper::{body::to_bytes, header::HeaderValue, service::{make_service_fn, service_fn}, Body, HeaderMap, Method, Request, Response, Server, StatusCode};
+use serde_json::{json, Value};
+use tempfile::TempDir;
+
+use crate::etag::{content_md5, md5_of};
+
+pub const BUCKET: &str = "io";
+const META_PREFIX: &str = "x-amz-meta-";
+
+struct StoredObject {
+	etag: String,
+	size: u64,
+	metadata: HashMap<String, String>,
+	modified: DateTime<Utc>
+}
+
+struct PendingUpload {
+	key: String,
+	metadata: HashMap<String, String>,
+	parts: BTreeMap<i32, Vec<u8>>
+}
+
+#[derive(Default)]
+struct MockState {
+	dir: PathBuf,
+	users: HashMap<String, String>,
+	jwt: String,
+	jwt_generation: u32,
+	sessions: usize,
+	requests: usize,
+	puts: usize,
+	fail_puts: usize,
+	objects: BTreeMap<String, StoredObject>,
+	folders: Vec<String>,
+	/// Meta of the root folders that are workspaces, keyed by slug
+	workspaces: HashMap<String, Value>,
+	uploads: HashMap<String, PendingUpload>,
+	next_upload: u64
+}
+
+/// An in-process stand-in for a Cells server: the few REST endpoints the app
+/// calls and the S3 gateway, with object content kept in a temp dir.
+pub struct MockCells {
+	pub endpoint: String,
+	state: Arc<Mutex<MockState>>,
+	_dir: TempDir
+}
+
+impl MockCells {
+	pub async fn start() -> Self {
+		let dir = tempfile::tempdir().unwrap();
+		let state = Arc::new(Mutex::new(MockState {
+			dir: dir.path().to_path_buf(),
+			jwt: "jwt-1".to_string(),
+			jwt_generation: 1,
+			..Default::default()
+		}));
+
+		let service_state = state.clone();
+		let make_service = make_service_fn(move |_| {
+			let state = service_state.clone();
+			async move {
+				Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req)))
+			}
+		});
+		let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+		let endpoint = format!("http://{}", server.local_addr());
+		tokio::spawn(server);
+
+		Self { endpoint, state, _dir: dir }
+	}
+
+	pub fn add_user(&self, name: &str, password: &str) {
+		self.state.lock().unwrap().users.insert(name.to_string(), password.to_string());
+	}
+
+	/// Invalidate the current JWT, requests using it get a 401 until the next login.
+	pub fn expire_session(&self) {
+		let mut state = self.state.lock().unwrap();
+		state.jwt_generation += 1;
+		state.jwt = format!("jwt-{}", state.jwt_generation);
+	}
+
+	/// Answer the next `count` object uploads with an internal error.
+	pub fn fail_next_puts(&self, count: usize) {
+		self.state.lock().unwrap().fail_puts = count;
+	}
+
+	pub fn sessions(&self) -> usize {
+		self.state.lock().unwrap().sessions
+	}
+
+	/// Requests of any kind received so far.
+	pub fn requests(&self) -> usize {
+		self.state.lock().unwrap().requests
+	}
+
+	/// Object uploads received, failed ones included.
+	pub fn puts(&self) -> usize {
+		self.state.lock().unwrap().puts
+	}
+
+	/// Make `slug` a workspace. Meta values are JSON encoded, like Cells does.
+	pub fn add_workspace(&self, slug: &str, label: &str, permissions: &str, syncable: bool) {
+		let mut state = self.state.lock().unwrap();
+		state.folders.push(slug.to_string());
+		state.workspaces.insert(slug.to_string(), json!({
+			"ws_label": json!(label).to_string(),
+			"ws_permissions": json!(permissions).to_string(),
+			"ws_syncable": json!(syncable).to_string()
+		}));
+	}
+
+	pub fn folders(&self) -> Vec<String> {
+		self.state.lock().unwrap().folders.clone()
+	}
+
+	pub fn object(&self, key: &str) -> Option<Vec<u8>> {
+		let state = self.state.lock().unwrap();
+		state.objects.get(key)?;
+		fs::read(state.content_path(key)).ok()
+	}
+
+	pub fn seed(&self, key: &str, content: &[u8]) {
+		self.state.lock().unwrap().store(key, content, HashMap::new());
+	}
+}
+
+impl MockState {
+	fn content_path(&self, key: &str) -> PathBuf {
+		self.dir.join(hex::encode(key))
+	}
+
+	fn store(&mut self, key: &str, content: &[u8], metadata: HashMap<String, String>) -> String {
+		fs::write(self.content_path(key), content).unwrap();
+		let etag = hex::encode(md5_of(content));
+		self.objects.insert(key.to_string(), StoredObject {
+			etag: etag.clone(),
+			size: content.len() as u64,
+			metadata,
+			modified: Utc::now()
+		});
+		etag
+	}
+
+	fn authorized(&self, headers: &HeaderMap) -> bool {
+		headers.get("authorization").and_then(|v| v.to_str().ok()) == Some(format!("Bearer {}", self.jwt).as_str())
+	}
+
+	fn session(&mut self, body: &[u8]) -> Response<Body> {
+		self.sessions += 1;
+		let request: Value = serde_json::from_slice(body).unwrap_or_default();
+		let login = request["AuthInfo"]["login"].as_str().unwrap_or("");
+		let password = request["AuthInfo"]["password"].as_str().unwrap_or("");
+
+		if self.users.get(login).map(|p| p == password).unwrap_or(false) {
+			json_response(StatusCode::OK, json!({
+				"JWT": self.jwt,
+				"ExpireTime": 600,
+				"Token": {
+					"AccessToken": self.jwt,
+					"IDToken": "id-token",
+					"ExpiresAt": ""
+				}
+			}))
+		} else {
+			json_response(StatusCode::UNAUTHORIZED, json!({ "Title": "Login failed" }))
+		}
+	}
+
+	fn user(&self, name: &str) -> Response<Body> {
+		match self.users.get(name) {
+			Some(_) => json_response(StatusCode::OK, json!({
+				"Uuid": format!("uuid-{}", name),
+				"Attributes": {
+					"displayName": name,
+					"email": format!("{}@example.com", name),
+					"profile": "standard"
+				}
+			})),
+			None => json_response(StatusCode::NOT_FOUND, json!({}))
+		}
+	}
+
+	fn node(&self, path: &str) -> Option<Value> {
+		if let Some(object) = self.objects.get(path) {
+			return Some(json!({
+				"Uuid": path,
+				"Path": path,
+				"Type": "LEAF",
+				"Etag": object.etag,
+				"Size": object.size.to_string(),
+				"MetaStore": { "name": name_of(path) }
+			}));
+		}
+		let folder = format!("{}/", path);
+		let exists = self.folders.iter().any(|f| f == path)
+			|| self.objects.keys().any(|k| k.starts_with(&folder));
+		let mut meta = self.workspaces.get(path).cloned().unwrap_or_else(|| json!({}));
+		meta["name"] = json!(name_of(path));
+		exists.then(|| json!({
+			"Uuid": path,
+			"Path": path,
+			"Type": "COLLECTION",
+			"MetaStore": meta
+		}))
+	}
+
+	fn bulk_get(&self, body: &[u8]) -> Response<Body> {
+		let request: Value = serde_json::from_slice(body).unwrap_or_default();
+		let mut nodes = vec![];
+		for path in request["NodePaths"].as_array().cloned().unwrap_or_default() {
+			let path = path.as_str().unwrap_or("").trim_matches('/').to_string();
+			match path.strip_suffix("*") {
+				Some(parent) => {
+					let children: BTreeSet<String> = self.objects
+						.keys()
+						.chain(self.folders.iter())
+						.filter_map(|k| k.strip_prefix(parent))
+						.filter(|rest| !rest.is_empty())
+						.map(|rest| format!("{}{}", parent, rest.split('/').next().unwrap()))
+						.collect();
+					nodes.extend(children.iter().filter_map(|c| self.node(c)));
+				},
+				None => nodes.extend(self.node(&path))
+			}
+		}
+
+		if nodes.is_empty() {
+			json_response(StatusCode::OK, json!({}))
+		} else {
+			json_response(StatusCode::OK, json!({ "Nodes": nodes }))
+		}
+	}
+
+	fn tree_create(&mut self, body: &[u8]) -> Response<Body> {
+		let request: Value = serde_json::from_slice(body).unwrap_or_default();
+		for node in request["Nodes"].as_array().cloned().unwrap_or_default() {
+			if let Some(path) = node["Path"].as_str() {
+				self.folders.push(path.trim_matches('/').to_string());
+			}
+		}
+		json_response(StatusCode::OK, json!({}))
+	}
+
+	fn list_objects(&self, query: &HashMap<String, String>) -> Response<Body> {
+		let prefix = query.get("prefix").cloned().unwrap_or_default();
+		let contents: String = self.objects
+			.iter()
+			.filter(|(k, _)| k.starts_with(&prefix))
+			.map(|(k, o)| format!(
+				"<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>\"{}\"</ETag><Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+				escape_xml(k), o.modified.to_rfc3339_opts(SecondsFormat::Millis, true), o.etag, o.size
+			))
+			.collect();
+		let count = self.objects.keys().filter(|k| k.starts_with(&prefix)).count();
+
+		xml_response(StatusCode::OK, format!(
+			"<ListBucketResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\"><Name>{}</Name><Prefix>{}</Prefix><KeyCount>{}</KeyCount><MaxKeys>1000</MaxKeys><IsTruncated>false</IsTruncated>{}</ListBucketResult>",
+			BUCKET, escape_xml(&prefix), count, contents
+		))
+	}
+
+	fn object_request(
+		&mut self, method: &Method, key: &str, query: &HashMap<String, String>, headers: &HeaderMap, body: &[u8]
+	) -> Response<Body> {
+		match *method {
+			Method::HEAD | Method::GET => {
+				let object = match self.objects.get(key) {
+					Some(o) => o,
+					None if *method == Method::HEAD => return empty_response(StatusCode::NOT_FOUND),
+					None => return s3_error(StatusCode::NOT_FOUND, "NoSuchKey")
+				};
+				let content = if *method == Method::GET {
+					fs::read(self.content_path(key)).unwrap()
+				} else {
+					vec![]
+				};
+
+				let mut res = Response::new(Body::from(content));
+				let res_headers = res.headers_mut();
+				res_headers.insert("content-length", object.size.into());
+				res_headers.insert("etag", header(&format!("\"{}\"", object.etag)));
+				res_headers.insert("last-modified", header(&object.modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string()));
+				for (k, v) in &object.metadata {
+					res_headers.insert(
+						hyper::header::HeaderName::from_bytes(format!("{}{}", META_PREFIX, k).as_bytes()).unwrap(),
+						header(v)
+					);
+				}
+				res
+			},
+			Method::PUT => {
+				if let (Some(number), Some(upload_id)) = (query.get("partNumber"), query.get("uploadId")) {
+					return match self.uploads.get_mut(upload_id) {
+						Some(upload) => {
+							upload.parts.insert(number.parse().unwrap_or(0), body.to_vec());
+							with_etag(empty_response(StatusCode::OK), &hex::encode(md5_of(body)))
+						},
+						None => s3_error(StatusCode::NOT_FOUND, "NoSuchUpload")
+					};
+				}
+
+				self.puts += 1;
+				if self.fail_puts > 0 {
+					self.fail_puts -= 1;
+					return s3_error(StatusCode::INTERNAL_SERVER_ERROR, "InternalError");
+				}
+
+				if let Some(source) = headers.get("x-amz-copy-source").and_then(|v| v.to_str().ok()) {
+					let source = decode(source.trim_start_matches('/'));
+					let source = source.strip_prefix(&format!("{}/", BUCKET)).unwrap_or(&source).to_string();
+					let (content, metadata) = match self.objects.get(&source) {
+						Some(o) => (fs::read(self.content_path(&source)).unwrap(), o.metadata.clone()),
+						None => return s3_error(StatusCode::NOT_FOUND, "NoSuchKey")
+					};
+					let etag = self.store(key, &content, metadata);
+					return xml_response(StatusCode::OK, format!("<CopyObjectResult><ETag>\"{}\"</ETag></CopyObjectResult>", etag));
+				}
+
+				if let Some(expected) = headers.get("content-md5").and_then(|v| v.to_str().ok()) {
+					if content_md5(&md5_of(body)) != expected {
+						return s3_error(StatusCode::BAD_REQUEST, "BadDigest");
+					}
+				}
+				let etag = self.store(key, body, metadata_of(headers));
+				with_etag(empty_response(StatusCode::OK), &etag)
+			},
+			Method::POST if query.contains_key("uploads") => {
+				self.next_upload += 1;
+				let upload_id = format!("upload-{}", self.next_upload);
+				self.uploads.insert(upload_id.clone(), PendingUpload {
+					key: key.to_string(),
+					metadata: metadata_of(headers),
+					parts: BTreeMap::new()
+				});
+				xml_response(StatusCode::OK, format!(
+					"<InitiateMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key><UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+					BUCKET, escape_xml(key), upload_id
+				))
+			},
+			Method::POST if query.contains_key("uploadId") => {
+				let upload = match self.uploads.remove(&query["uploadId"]) {
+					Some(u) if u.key == key => u,
+					_ => return s3_error(StatusCode::NOT_FOUND, "NoSuchUpload")
+				};
+				self.puts += 1;
+				let mut content = vec![];
+				let mut digests = vec![];
+				for part in upload.parts.values() {
+					content.extend_from_slice(part);
+					digests.extend(md5_of(part));
+				}
+				let count = upload.parts.len();
+				self.store(key, &content, upload.metadata);
+				// multipart ETags are the digest of the part digests
+				let etag = format!("{}-{}", hex::encode(md5_of(&digests)), count);
+				if let Some(object) = self.objects.get_mut(key) {
+					object.etag = etag.clone();
+				}
+				xml_response(StatusCode::OK, format!(
+					"<CompleteMultipartUploadResult><Bucket>{}</Bucket><Key>{}</Key><ETag>\"{}\"</ETag></CompleteMultipartUploadResult>",
+					BUCKET, escape_xml(key), etag
+				))
+			},
+			Method::DELETE => {
+				match query.get("uploadId") {
+					Some(upload_id) => {
+						self.uploads.remove(upload_id);
+					},
+					None => {
+						if self.objects.remove(key).is_some() {
+							let _ = fs::remove_file(self.content_path(key));
+						}
+					}
+				}
+				empty_response(StatusCode::NO_CONTENT)
+			},
+			_ => empty_response(StatusCode::METHOD_NOT_ALLOWED)
+		}
+	}
+}
+
+async fn handle(state: Arc<Mutex<MockState>>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+	let method = req.method().clone();
+	let path = decode(req.uri().path());
+	let query = parse_query(req.uri().query().unwrap_or(""));
+	let headers = req.headers().clone();
+	let body = to_bytes(req.into_body()).await.unwrap_or_default();
+
+	let mut state = state.lock().unwrap();
+	state.requests += 1;
+	let bucket_path = format!("/{}", BUCKET);
+	let res = match (&method, path.as_str()) {
+		(&Method::POST, "/a/frontend/session") => state.session(&body),
+		(_, p) if p.starts_with("/a/") && !state.authorized(&headers) => json_response(StatusCode::UNAUTHORIZED, json!({})),
+		(&Method::GET, p) if p.starts_with("/a/user/") => state.user(&p["/a/user/".len()..]),
+		(&Method::POST, "/a/meta/bulk/get") => state.bulk_get(&body),
+		(&Method::POST, "/a/tree/create") => state.tree_create(&body),
+		(&Method::GET, p) if p == bucket_path || p == format!("{}/", bucket_path) => state.list_objects(&query),
+		(_, p) if p.starts_with(&format!("{}/", bucket_path)) => {
+			let key = p[bucket_path.len() + 1..].to_string();
+			state.object_request(&method, &key, &query, &headers, &body)
+		},
+		_ => empty_response(StatusCode::NOT_FOUND)
+	};
+	Ok(res)
+}
+
+fn metadata_of(headers: &HeaderMap) -> HashMap<String, String> {
+	headers
+		.iter()
+		.filter_map(|(k, v)| {
+			let key = k.as_str().strip_prefix(META_PREFIX)?;
+			Some((key.to_string(), v.to_str().ok()?.to_string()))
+		})
+		.collect()
+}
+
+fn name_of(path: &str) -> &str {
+	path.rsplit('/').next().unwrap_or(path)
+}
+
+fn header(value: &str) -> HeaderValue {
+	HeaderValue::from_str(value).unwrap()
+}
+
+fn with_etag(mut res: Response<Body>, etag: &str) -> Response<Body> {
+	res.headers_mut().insert("etag", header(&format!("\"{}\"", etag)));
+	res
+}
+
+fn empty_response(status: StatusCode) -> Response<Body> {
+	let mut res = Response::new(Body::empty());
+	*res.status_mut() = status;
+	res
+}
+
+fn json_response(status: StatusCode, value: Value) -> Response<Body> {
+	let mut res = Response::new(Body::from(value.to_string()));
+	*res.status_mut() = status;
+	res.headers_mut().insert("content-type", header("application/json"));
+	res
+}
+
+fn xml_response(status: StatusCode, content: String) -> Response<Body> {
+	let mut res = Response::new(Body::from(format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>{}", content)));
+	*res.status_mut() = status;
+	res.headers_mut().insert("content-type", header("application/xml"));
+	res
+}
+
+fn s3_error(status: StatusCode, code: &str) -> Response<Body> {
+	xml_response(status, format!("<Error><Code>{}</Code><Message>{}</Message></Error>", code, code))
+}
+
+fn escape_xml(value: &str) -> String {
+	value
+		.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace('"', "&quot;")
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+	query
+		.split('&')
+		.filter(|p| !p.is_empty())
+		.map(|p| match p.split_once('=') {
+			Some((k, v)) => (decode(k), decode(v)),
+			None => (decode(p), String::new())
+		})
+		.collect()
+}
+
+fn decode(value: &str) -> String {
+	let bytes = value.as_bytes();
+	let mut decoded = Vec::with_capacity(bytes.len());
+	let mut i = 0;
+	while i < bytes.len() {
+		let escaped = if bytes[i] == b'%' && i + 2 < bytes.len() {
+			std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|h| u8::from_str_radix(h, 16).ok())
+		} else {
+			None
+		};
+		match escaped {
+			Some(b) => {
+				decoded.push(b);
+				i += 3;
+			},
+			None => {
+				decoded.push(bytes[i]);
+				i += 1;
+			}
+		}
+	}
+	String::from_utf8_lossy(&decoded).to_string()
+}
@@ -0,0 +1,618 @@
+//! Integration tests running the sync engine against `MockCells`.
+
+mod bench;
+mod mock_cells;
+
+use std::{any::Any, collections::HashMap, fs, path::Path, sync::{Mutex, Once}, time::Duration};
+
+use aws_sdk_s3::primitives::ByteStream;
+use keyring::credential::{Credential, CredentialApi, CredentialBuilderApi};
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use tempfile::TempDir;
+
+use crate::{
+	backend::{CellsBackend, RemoteBackend},
+	data::{set_tasks, CONFIG_DIR_ENV},
+	history::export_history,
+	logging::redact,
+	names::NameRules,
+	net::{connect, is_running, list, list_workspaces, login, retry_file, s3_client, set_encryption_passphrase, sync},
+	placeholder::{hydrate, PLACEHOLDER_SUFFIX},
+	structs::{ArchiveAction, EncryptionMode, HistoryQuery, InvalidNamePolicy, NameNormalization, SyncDirection, SyncTask, TaskData}
+};
+use mock_cells::MockCells;
+
+const USERNAME: &str = "admin";
+const PASSWORD: &str = "secret";
+
+lazy_static! {
+	static ref CONFIG_DIR: TempDir = tempfile::tempdir().unwrap();
+	static ref KEYS: Mutex<HashMap<String, Vec<u8>>> = Mutex::new(HashMap::new());
+	// the engine keeps the endpoint and session in globals, tests take turns
+	static ref SERIAL: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+
+static SETUP: Once = Once::new();
+
+/// Keeps credentials in memory instead of the system keychain.
+struct MemoryKey(String);
+
+impl CredentialApi for MemoryKey {
+	fn set_password(&self, password: &str) -> keyring::Result<()> {
+		self.set_secret(password.as_bytes())
+	}
+
+	fn set_secret(&self, secret: &[u8]) -> keyring::Result<()> {
+		KEYS.lock().unwrap().insert(self.0.clone(), secret.to_vec());
+		Ok(())
+	}
+
+	fn get_password(&self) -> keyring::Result<String> {
+		String::from_utf8(self.get_secret()?).map_err(|e| keyring::Error::BadEncoding(e.into_bytes()))
+	}
+
+	fn get_secret(&self) -> keyring::Result<Vec<u8>> {
+		KEYS.lock().unwrap().get(&self.0).cloned().ok_or(keyring::Error::NoEntry)
+	}
+
+	fn delete_credential(&self) -> keyring::Result<()> {
+		KEYS.lock().unwrap().remove(&self.0).map(|_| ()).ok_or(keyring::Error::NoEntry)
+	}
+
+	fn as_any(&self) -> &dyn Any {
+		self
+	}
+}
+
+struct MemoryKeys;
+
+impl CredentialBuilderApi for MemoryKeys {
+	fn build(&self, _target: Option<&str>, service: &str, user: &str) -> keyring::Result<Box<Credential>> {
+		Ok(Box::new(MemoryKey(format!("{}:{}", service, user))))
+	}
+
+	fn as_any(&self) -> &dyn Any {
+		self
+	}
+}
+
+fn setup() {
+	SETUP.call_once(|| {
+		std::env::set_var(CONFIG_DIR_ENV, CONFIG_DIR.path());
+		keyring::set_default_credential_builder(Box::new(MemoryKeys));
+		// files are uploaded as soon as they are written
+		let settings = json!({ "settleSeconds": 0, "uploadThreadNumber": 2 });
+		fs::write(CONFIG_DIR.path().join("settings.json"), settings.to_string()).unwrap();
+	});
+}
+
+async fn start_server() -> MockCells {
+	setup();
+	let server = MockCells::start().await;
+	server.add_user(USERNAME, PASSWORD);
+	server
+}
+
+async fn login_to(server: &MockCells) -> Value {
+	let res = login(server.endpoint.clone(), USERNAME.to_string(), PASSWORD.to_string()).await;
+	serde_json::from_str(&res).unwrap()
+}
+
+fn task(uuid: &str, local: &Path, remote: &str, direction: SyncDirection) -> TaskData {
+	serde_json::from_value(json!({
+		"uuid": uuid,
+		"localDir": local,
+		"ignores": [],
+		"remoteDir": { "Uuid": "", "Path": remote, "Type": "COLLECTION" },
+		"paused": false,
+		"repeatInterval": 1.0,
+		"repeatIntervalUnit": { "name": "Hour", "level": 3600.0 },
+		"direction": direction
+	})).unwrap()
+}
+
+async fn run_task(task: &TaskData) {
+	sync(task.clone(), vec![], Some("test".to_string())).await;
+	tokio::time::timeout(Duration::from_secs(60), async {
+		while is_running(&task.uuid) {
+			tokio::time::sleep(Duration::from_millis(50)).await;
+		}
+	}).await.expect("task did not finish");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn login_checks_credentials() {
+	let _serial = SERIAL.lock().await;
+	let server = start_server().await;
+
+	let res = login(server.endpoint.clone(), USERNAME.to_string(), "wrong".to_string()).await;
+	let res: Value = serde_json::from_str(&res).unwrap();
+	assert_eq!(res["success"], false);
+	assert_eq!(res["kind"], "Auth");
+
+	let res = login_to(&server).await;
+	assert_eq!(res["success"], true);
+	assert_eq!(res["data"]["JWT"], "jwt-1");
+
+	let res: Value = serde_json::from_str(&connect(server.endpoint.clone(), USERNAME.to_string()).await).unwrap();
+	assert_eq!(res["data"]["Uuid"], "uuid-admin");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn list_returns_direct_children() {
+	let _serial = SERIAL.lock().await;
+	let server = start_server().await;
+	server.seed("personal/a.txt", b"a");
+	server.seed("personal/docs/b.txt", b"b");
+	login_to(&server).await;
+
+	let res: Value = serde_json::from_str(&list("personal/".to_string()).await).unwrap();
+	let paths: Vec<&str> = res["data"]["Nodes"].as_array().unwrap().iter().map(|n| n["Path"].as_str().unwrap()).collect();
+	assert_eq!(paths, vec!["personal/a.txt", "personal/docs"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn expired_session_is_refreshed() {
+	let _serial = SERIAL.lock().await;
+	let server = start_server().await;
+	server.seed("personal/a.txt", b"a");
+	login_to(&server).await;
+	assert_eq!(server.sessions(), 1);
+
+	server.expire_session();
+	list("personal".to_string()).await;
+	assert_eq!(server.sessions(), 2);
+
+	let res: Value = serde_json::from_str(&list("personal".to_string()).await).unwrap();
+	assert_eq!(res["data"]["Nodes"][0]["Path"], "personal/a.txt");
+	assert_eq!(server.sessions(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unchanged_files_are_skipped_by_etag() {
+	let _serial = SERIAL.lock().await;
+	let server = start_server().await;
+	login_to(&server).await;
+
+	let local = tempfile::tempdir().unwrap();
+	fs::write(local.path().join("a.txt"), "first file").unwrap();
+	fs::create_dir(local.path().join("docs")).unwrap();
+	fs::write(local.path().join("docs").join("b.txt"), "second file").unwrap();
+	fs::create_dir(local.path().join("empty")).unwrap();
+	let task = task("skip-by-etag", local.path(), "personal/backup", SyncDirection::Upload);
+
+	run_task(&task).await;
+	assert_eq!(server.puts(), 2);
+	assert_eq!(server.object("personal/backup/a.txt").unwrap(), b"first file");
+	assert_eq!(server.object("personal/backup/docs/b.txt").unwrap(), b"second file");
+	assert_eq!(server.folders(), vec!["personal/backup/empty"]);
+
+	run_task(&task).await;
+	assert_eq!(server.puts(), 2);
+
+	fs::write(local.path().join("a.txt"), "first file, changed").unwrap();
+	run_task(&task).await;
+	assert_eq!(server.puts(), 3);
+	assert_eq!(server.object("personal/backup/a.txt").unwrap(), b"first file, changed");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn remote_files_are_downloaded() {
+	let _serial = SERIAL.lock().await;
+	let server = start_server().await;
+	server.seed("personal/shared/report.txt", b"remote content");
+	login_to(&server).await;
+
+	let local = tempfile::tempdir().unwrap();
+	let task = task("download", local.path(), "personal/shared", SyncDirection::Download);
+
+	run_task(&task).await;
+	assert_eq!(fs::read(local.path().join("report.txt")).unwrap(), b"remote content");
+	assert_eq!(server.puts(), 0);
+
+	// the run is written to the history right after the task stops running
+	let export = local.path().join("runs.csv");
+	let query = HistoryQuery { taskUuid: Some(task.uuid.clone()), ..Default::default() };
+	let csv = tokio::time::timeout(Duration::from_secs(5), async {
+		loop {
+			export_history(query.clone(), "runs".to_string(), "csv".to_string(), export.to_string_lossy().to_string()).await;
+			let csv = fs::read_to_string(&export).unwrap();
+			if csv.lines().count() > 1 {
+				return csv;
+			}
+			tokio::time::sleep(Duration::from_millis(50)).await;
+		}
+	}).await.expect("run was not recorded");
+	let mut lines = csv.lines().map(|l| l.split(',').collect::<Vec<_>>());
+	let header = lines.next().unwrap();
+	let row = lines.next().unwrap();
+	let column = header.iter().position(|c| *c == "downloaded").unwrap();
+	assert_eq!(row[column], "1");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn two_way_conflicts_keep_both_copies() {
+	let _serial = SERIAL.lock().await;
+	let server = start_server().await;
+	login_to(&server).await;
+
+	let local = tempfile::tempdir().unwrap();
+	let file = local.path().join("notes.txt");
+	fs::write(&file, "base").unwrap();
+	let task = task("two-way", local.path(), "personal/notes", SyncDirection::Both);
+	run_task(&task).await;
+	assert_eq!(server.object("personal/notes/notes.txt").unwrap(), b"base");
+
+	// only the remote changed
+	server.seed("personal/notes/notes.txt", b"remote edit");
+	run_task(&task).await;
+	assert_eq!(fs::read(&file).unwrap(), b"remote edit");
+
+	// both changed, the remote edit takes the place and the local one is kept next to it
+	fs::write(&file, "local edit").unwrap();
+	server.seed("personal/notes/notes.txt", b"second remote edit");
+	run_task(&task).await;
+	assert_eq!(fs::read(&file).unwrap(), b"second remote edit");
+	let copies: Vec<_> = fs::read_dir(local.path())
+		.unwrap()
+		.map(|e| e.unwrap().path())
+		.filter(|p| p.to_string_lossy().contains("(conflict "))
+		.collect();
+	assert_eq!(copies.len(), 1);
+	assert_eq!(fs::read(&copies[0]).unwrap(), b"local edit");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn failed_uploads_are_retried() {
+	let _serial = SERIAL.lock().await;
+	let server = start_server().await;
+	login_to(&server).await;
+
+	let local = tempfile::tempdir().unwrap();
+	fs::write(local.path().join("a.txt"), "retry me").unwrap();
+	let task = task("retry-run", local.path(), "personal/retry", SyncDirection::Upload);
+
+	// more failures than the S3 client retries on its own, the run has to queue the file again
+	server.fail_next_puts(5);
+	run_task(&task).await;
+	assert!(server.puts() > 5);
+	assert_eq!(server.object("personal/retry/a.txt").unwrap(), b"retry me");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn retrying_a_single_file() {
+	let _serial = SERIAL.lock().await;
+	let server = start_server().await;
+	login_to(&server).await;
+
+	let local = tempfile::tempdir().unwrap();
+	let from = local.path().join("single.txt");
+	fs::write(&from, "single").unwrap();
+	let sync_task = SyncTask {
+		from,
+		to: "personal/single.txt".to_string(),
+		size: 6,
+		direction: SyncDirection::Upload,
+		link: None
+	};
+
+	server.fail_next_puts(100);
+	assert!(!retry_file("retry-single".to_string(), sync_task.clone()).await);
+	assert!(server.object("personal/single.txt").is_none());
+
+	server.fail_next_puts(0);
+	assert!(retry_file("retry-single".to_string(), sync_task).await);
+	assert_eq!(server.object("personal/single.txt").unwrap(), b"single");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn multipart_uploads_are_assembled() {
+	let _serial = SERIAL.lock().await;
+	let server = start_server().await;
+	login_to(&server).await;
+
+	let client = s3_client();
+	let upload = client.create_multipart_upload().bucket("io").key("personal/big.bin").send().await.unwrap();
+	let upload_id = upload.upload_id().unwrap();
+
+	let mut parts = vec![];
+	for (number, content) in [(1, "first part, "), (2, "second part")] {
+		let part = client
+			.upload_part()
+			.bucket("io")
+			.key("personal/big.bin")
+			.upload_id(upload_id)
+			.part_number(number)
+			.body(content.as_bytes().to_vec().into())
+			.send()
+			.await
+			.unwrap();
+		parts.push(
+			aws_sdk_s3::types::CompletedPart::builder()
+				.part_number(number)
+				.set_e_tag(part.e_tag().map(|e| e.to_string()))
+				.build()
+		);
+	}
+	client
+		.complete_multipart_upload()
+		.bucket("io")
+		.key("personal/big.bin")
+		.upload_id(upload_id)
+		.multipart_upload(aws_sdk_s3::types::CompletedMultipartUpload::builder().set_parts(Some(parts)).build())
+		.send()
+		.await
+		.unwrap();
+
+	let stat = CellsBackend.stat("personal/big.bin").await.unwrap().unwrap();
+	assert_eq!(stat.size, 23);
+	assert!(stat.etag.ends_with("-2"));
+	assert_eq!(server.object("personal/big.bin").unwrap(), b"first part, second part");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn encrypted_tasks_round_trip() {
+	let _serial = SERIAL.lock().await;
+	let server = start_server().await;
+	login_to(&server).await;
+
+	let local = tempfile::tempdir().unwrap();
+	fs::write(local.path().join("salary.txt"), "confidential").unwrap();
+	let mut upload = task("encrypted", local.path(), "personal/hr", SyncDirection::Upload);
+	upload.encryption = EncryptionMode::ContentAndNames;
+	set_encryption_passphrase(upload.uuid.clone(), "correct horse".to_string());
+
+	run_task(&upload).await;
+	// the key file and the file itself
+	assert_eq!(server.puts(), 2);
+	assert!(server.object("personal/hr/salary.txt").is_none());
+	let stored: Vec<String> = CellsBackend.list("personal/hr/").await.unwrap()
+		.into_iter()
+		.map(|f| f.key)
+		.filter(|k| !k.ends_with(".cells-sync-key"))
+		.collect();
+	assert_eq!(stored.len(), 1);
+	let ciphertext = server.object(&stored[0]).unwrap();
+	assert!(!ciphertext.windows(12).any(|w| w == b"confidential"));
+
+	run_task(&upload).await;
+	assert_eq!(server.puts(), 2);
+
+	let restored = tempfile::tempdir().unwrap();
+	let mut download = task("encrypted-restore", restored.path(), "personal/hr", SyncDirection::Download);
+	download.encryption = EncryptionMode::ContentAndNames;
+	set_encryption_passphrase(download.uuid.clone(), "correct horse".to_string());
+	run_task(&download).await;
+	assert_eq!(fs::read(restored.path().join("salary.txt")).unwrap(), b"confidential");
+
+	let wrong = tempfile::tempdir().unwrap();
+	let mut download = task("encrypted-wrong", wrong.path(), "personal/hr", SyncDirection::Download);
+	download.encryption = EncryptionMode::ContentAndNames;
+	set_encryption_passphrase(download.uuid.clone(), "wrong horse".to_string());
+	run_task(&download).await;
+	assert_eq!(fs::read_dir(wrong.path()).unwrap().count(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn compressed_files_skip_and_restore() {
+	let _serial = SERIAL.lock().await;
+	let server = start_server().await;
+	login_to(&server).await;
+
+	let local = tempfile::tempdir().unwrap();
+	let log = "GET /index.html 200\n".repeat(1000);
+	fs::write(local.path().join("access.log"), &log).unwrap();
+	fs::write(local.path().join("photo.jpg"), "not a pattern").unwrap();
+	let mut upload = task("compressed", local.path(), "personal/logs", SyncDirection::Upload);
+	upload.compression.enabled = true;
+
+	run_task(&upload).await;
+	assert_eq!(server.puts(), 2);
+	let stored = server.object("personal/logs/access.log").unwrap();
+	assert!(stored.len() < log.len() / 10);
+	assert_eq!(server.object("personal/logs/photo.jpg").unwrap(), b"not a pattern");
+
+	// the original content hash is compared, not the one of the stored object
+	run_task(&upload).await;
+	assert_eq!(server.puts(), 2);
+
+	let restored = tempfile::tempdir().unwrap();
+	let download = task("compressed-restore", restored.path(), "personal/logs", SyncDirection::Download);
+	run_task(&download).await;
+	assert_eq!(fs::read_to_string(restored.path().join("access.log")).unwrap(), log);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn backups_keep_replaced_versions() {
+	let _serial = SERIAL.lock().await;
+	let server = start_server().await;
+	login_to(&server).await;
+
+	let local = tempfile::tempdir().unwrap();
+	let file = local.path().join("ledger.csv");
+	let mut backup = task("backup", local.path(), "personal/backup", SyncDirection::Upload);
+	backup.backup.enabled = true;
+	backup.backup.keepLast = 1;
+	backup.backup.keepDailyDays = 0;
+	backup.backup.keepMonthlyMonths = 0;
+
+	let now = std::time::SystemTime::now();
+	for (days_ago, content) in [(3, "v1"), (2, "v2"), (1, "v3")] {
+		fs::write(&file, content).unwrap();
+		let mtime = now - Duration::from_secs(days_ago * 24 * 3600);
+		filetime::set_file_mtime(&file, filetime::FileTime::from_system_time(mtime)).unwrap();
+		run_task(&backup).await;
+	}
+
+	assert_eq!(server.object("personal/backup/ledger.csv").unwrap(), b"v3");
+	let versions: Vec<String> = CellsBackend.list("personal/backup/.versions/").await.unwrap()
+		.into_iter()
+		.map(|f| f.key)
+		.filter(|k| !k.ends_with('/'))
+		.collect();
+	// v1 is past every retention rule
+	assert_eq!(versions.len(), 1);
+	assert!(versions[0].starts_with("personal/backup/.versions/ledger.csv/") && versions[0].ends_with(".csv"));
+	assert_eq!(server.object(&versions[0]).unwrap(), b"v2");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn backups_keep_versions_of_equal_mtimes() {
+	let _serial = SERIAL.lock().await;
+	let server = start_server().await;
+	login_to(&server).await;
+
+	let local = tempfile::tempdir().unwrap();
+	let file = local.path().join("ledger.csv");
+	let mut backup = task("backup", local.path(), "personal/backup", SyncDirection::Upload);
+	backup.backup.enabled = true;
+	backup.backup.keepLast = 5;
+
+	let mtime = filetime::FileTime::from_system_time(std::time::SystemTime::now() - Duration::from_secs(3600));
+	for content in ["v1", "v2", "v3"] {
+		fs::write(&file, content).unwrap();
+		filetime::set_file_mtime(&file, mtime).unwrap();
+		run_task(&backup).await;
+	}
+
+	let mut kept: Vec<Vec<u8>> = CellsBackend.list("personal/backup/.versions/").await.unwrap()
+		.into_iter()
+		.filter(|f| !f.key.ends_with('/'))
+		.map(|f| server.object(&f.key).unwrap())
+		.collect();
+	kept.sort();
+	assert_eq!(kept, vec![b"v1".to_vec(), b"v2".to_vec()]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn archived_files_leave_the_disk() {
+	let _serial = SERIAL.lock().await;
+	let server = start_server().await;
+	login_to(&server).await;
+
+	let local = tempfile::tempdir().unwrap();
+	let trash = tempfile::tempdir().unwrap();
+	fs::create_dir(local.path().join("card")).unwrap();
+	fs::write(local.path().join("card/IMG_0001.JPG"), "raw").unwrap();
+	let mut ingest = task("archive", local.path(), "personal/camera", SyncDirection::Upload);
+	ingest.archive.enabled = true;
+	ingest.archive.action = ArchiveAction::Trash;
+	ingest.archive.trashDir = trash.path().to_string_lossy().to_string();
+
+	run_task(&ingest).await;
+	assert_eq!(server.object("personal/camera/card/IMG_0001.JPG").unwrap(), b"raw");
+	assert!(!local.path().join("card/IMG_0001.JPG").exists());
+	assert_eq!(fs::read(trash.path().join("card/IMG_0001.JPG")).unwrap(), b"raw");
+
+	// too new for the minimum age, kept until a later run
+	fs::write(local.path().join("card/IMG_0002.JPG"), "raw").unwrap();
+	ingest.archive.minAgeHours = 1;
+	run_task(&ingest).await;
+	assert!(server.object("personal/camera/card/IMG_0002.JPG").is_some());
+	assert!(local.path().join("card/IMG_0002.JPG").exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unselected_folders_get_placeholders() {
+	let _serial = SERIAL.lock().await;
+	let server = start_server().await;
+	login_to(&server).await;
+
+	for key in ["personal/shared/keep/a.txt", "personal/shared/skip/b.txt"] {
+		CellsBackend.put(key, ByteStream::from(key.as_bytes().to_vec()), None, HashMap::new()).await.unwrap();
+	}
+
+	let local = tempfile::tempdir().unwrap();
+	let mut download = task("selective", local.path(), "personal/shared", SyncDirection::Download);
+	download.selectedFolders = vec!["keep".to_string()];
+	run_task(&download).await;
+
+	assert!(local.path().join("keep/a.txt").exists());
+	assert!(!local.path().join("skip/b.txt").exists());
+	let placeholder = local.path().join(format!("skip/b.txt{}", PLACEHOLDER_SUFFIX));
+	assert!(placeholder.exists());
+	set_tasks(vec![download.clone()]);
+
+	// only placeholders inside the folder of the task are hydrated
+	let outside = tempfile::tempdir().unwrap();
+	fs::copy(&placeholder, outside.path().join(format!("b.txt{}", PLACEHOLDER_SUFFIX))).unwrap();
+	let refused = vec![
+		outside.path().to_string_lossy().to_string(),
+		local.path().join("skip/../../").to_string_lossy().to_string(),
+		local.path().join("keep/a.txt").to_string_lossy().to_string()
+	];
+	let res: Value = serde_json::from_str(&hydrate(download.uuid.clone(), refused).await).unwrap();
+	assert_eq!(res["data"]["hydrated"], 0);
+	assert_eq!(res["data"]["failed"].as_array().unwrap().len(), 3);
+	assert!(placeholder.exists());
+
+	// nor do they fetch objects from outside the remote folder
+	let forged = local.path().join(format!("keep/c.txt{}", PLACEHOLDER_SUFFIX));
+	fs::write(&forged, json!({ "remotePath": "personal/private.txt", "size": 1 }).to_string()).unwrap();
+	let res: Value = serde_json::from_str(
+		&hydrate(download.uuid.clone(), vec![forged.to_string_lossy().to_string()]).await
+	).unwrap();
+	assert_eq!(res["data"]["hydrated"], 0);
+	assert!(!local.path().join("keep/c.txt").exists());
+
+	let res: Value = serde_json::from_str(
+		&hydrate(download.uuid.clone(), vec![local.path().join("skip").to_string_lossy().to_string()]).await
+	).unwrap();
+	assert_eq!(res["data"]["hydrated"], 1);
+	assert_eq!(fs::read(local.path().join("skip/b.txt")).unwrap(), b"personal/shared/skip/b.txt");
+	assert!(!placeholder.exists());
+
+	// a hydrated file is synced like the others from then on
+	run_task(&download).await;
+	assert!(local.path().join("skip/b.txt").exists());
+	assert!(!placeholder.exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn read_only_workspaces_refuse_uploads() {
+	let _serial = SERIAL.lock().await;
+	let server = start_server().await;
+	login_to(&server).await;
+	server.add_workspace("common", "Common Files", "r", true);
+
+	let res: Value = serde_json::from_str(&list_workspaces().await).unwrap();
+	let workspace = res["data"].as_array().unwrap().iter().find(|w| w["slug"] == "common").cloned().unwrap();
+	assert_eq!(workspace["label"], "Common Files");
+	assert_eq!(workspace["readable"], true);
+	assert_eq!(workspace["writable"], false);
+	assert_eq!(workspace["syncable"], true);
+
+	let local = tempfile::tempdir().unwrap();
+	fs::write(local.path().join("a.txt"), b"a").unwrap();
+	let upload = task("read-only", local.path(), "common/docs", SyncDirection::Upload);
+	let res: Value = serde_json::from_str(&sync(upload.clone(), vec![], None).await).unwrap();
+	assert_eq!(res["success"], false);
+	assert_eq!(res["kind"], "Permission");
+	assert!(!is_running(&upload.uuid));
+	assert_eq!(server.puts(), 0);
+}
+
+#[test]
+fn escaped_names_round_trip() {
+	let rules = NameRules::new(NameNormalization::None, InvalidNamePolicy::Escape);
+	// a valid name spelling out an escape does not collide with the escaped name
+	assert_ne!(rules.check("a%5C").unwrap(), rules.check("a\\").unwrap());
+	for name in ["a%5C", "a\\", "100% done.txt", "trailing ", "tab\there", "plain.txt"] {
+		let key = rules.check(name).unwrap();
+		assert_eq!(rules.local_name(&key), name);
+	}
+	assert_eq!(rules.local_path(Path::new("root"), "%2E%2E/../a%25b"), Path::new("root").join("%2E%2E").join("a%b"));
+	// escaping must not push a name past what the server accepts
+	assert!(rules.check(&"%".repeat(100)).is_err());
+}
+
+#[test]
+fn sensitive_values_are_redacted() {
+	let line = r#"Sync Task {"accessKey":"AKIA","secretKey":"s3cr3t","password": "hunter2"} Authorization: Bearer abc.def"#;
+	let redacted = redact(line);
+	for secret in ["s3cr3t", "hunter2", "abc.def"] {
+		assert!(!redacted.contains(secret), "{} leaked in {}", secret, redacted);
+	}
+	assert!(redacted.contains(r#""accessKey":"AKIA""#));
+}
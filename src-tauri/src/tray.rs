@@ -0,0 +1,281 @@
+use std::{path::Path, process::Command, sync::Mutex, time::{Duration, Instant}};
+
+use chrono::{Days, Local, NaiveTime};
+use lazy_static::lazy_static;
+use tauri::{AppHandle, CustomMenuItem, Icon, Manager, SystemTray, SystemTrayEvent, SystemTrayMenu, SystemTrayMenuItem, SystemTraySubmenu};
+use tracing::warn;
+
+use crate::{data::{get_task, get_tasks, update_task}, error::error_count, events::{app_handle, emit, TASK_UPDATED}, net::{is_running, pause, pause_all_until, paused_until, resume_all, running_count, shutdown, sync_all, sync_now}, progress::progress_summary, structs::TaskData};
+
+#[cfg(target_os="macos")]
+const ACCELERATOR_PREFIX: &str = "Command";
+
+#[cfg(not(target_os="macos"))]
+const ACCELERATOR_PREFIX: &str = "Ctrl";
+
+pub const TRAY_ID: &str = "main";
+
+const TASK_SYNC_PREFIX: &str = "task-sync:";
+const TASK_TOGGLE_PREFIX: &str = "task-toggle:";
+const TASK_OPEN_PREFIX: &str = "task-open:";
+
+const TOOLTIP_INTERVAL: Duration = Duration::from_secs(1);
+/// Menu rebuilds asked for by errors happen at most this often.
+const MENU_INTERVAL: Duration = Duration::from_secs(1);
+
+const IDLE_ICON: &[u8] = include_bytes!("../icons/tray.png");
+const SYNCING_ICON: &[u8] = include_bytes!("../icons/tray-syncing.png");
+const PAUSED_ICON: &[u8] = include_bytes!("../icons/tray-paused.png");
+const ERROR_ICON: &[u8] = include_bytes!("../icons/tray-error.png");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TrayState {
+	Idle,
+	Syncing,
+	Paused,
+	Error
+}
+
+lazy_static! {
+	static ref LAST_TOOLTIP: Mutex<Option<Instant>> = Mutex::new(None);
+	// last menu rebuild, and whether a delayed one is already scheduled
+	static ref LAST_MENU: Mutex<(Option<Instant>, bool)> = Mutex::new((None, false));
+}
+
+fn tray_state() -> TrayState {
+	let tasks = get_tasks();
+	if error_count() > 0 {
+		TrayState::Error
+	} else if running_count() > 0 {
+		TrayState::Syncing
+	} else if paused_until().is_some() || (!tasks.is_empty() && tasks.iter().all(|t| t.paused)) {
+		TrayState::Paused
+	} else {
+		TrayState::Idle
+	}
+}
+
+fn task_label(task: &TaskData) -> String {
+	let name = Path::new(&task.localDir)
+		.file_name()
+		.map(|n| n.to_string_lossy().to_string())
+		.unwrap_or_else(|| task.localDir.clone());
+
+	if task.paused {
+		format!("{} (paused)", name)
+	} else if is_running(&task.uuid) {
+		format!("{} (syncing)", name)
+	} else {
+		name
+	}
+}
+
+fn build_menu() -> SystemTrayMenu {
+	let tasks = get_tasks();
+	let global_pause = paused_until();
+	let mut menu = SystemTrayMenu::new();
+
+	for task in tasks.iter() {
+		let mut sync_item = CustomMenuItem::new(format!("{}{}", TASK_SYNC_PREFIX, task.uuid), "Sync now");
+		if task.paused || global_pause.is_some() {
+			sync_item = sync_item.disabled();
+		}
+		let toggle_title = if task.paused { "Resume" } else { "Pause" };
+
+		let task_menu = SystemTrayMenu::new()
+			.add_item(sync_item)
+			.add_item(CustomMenuItem::new(format!("{}{}", TASK_TOGGLE_PREFIX, task.uuid), toggle_title))
+			.add_item(CustomMenuItem::new(format!("{}{}", TASK_OPEN_PREFIX, task.uuid), "Open local folder"));
+		menu = menu.add_submenu(SystemTraySubmenu::new(task_label(task), task_menu));
+	}
+	if !tasks.is_empty() {
+		menu = menu.add_native_item(SystemTrayMenuItem::Separator);
+	}
+
+	match global_pause {
+		Some(until) => {
+			menu = menu.add_item(CustomMenuItem::new(
+				"resume-all".to_string(),
+				format!("Resume all (paused until {})", until.format("%a %H:%M"))
+			));
+		},
+		None => {
+			menu = menu
+				.add_item(CustomMenuItem::new("sync-all".to_string(), "Sync all now"))
+				.add_item(CustomMenuItem::new("pause-hour".to_string(), "Pause all for 1 hour"))
+				.add_item(CustomMenuItem::new("pause-tomorrow".to_string(), "Pause all until tomorrow"));
+		}
+	}
+
+	menu
+		.add_native_item(SystemTrayMenuItem::Separator)
+		.add_item(CustomMenuItem::new("show".to_string(), "Show Window").accelerator(format!("{}+S", ACCELERATOR_PREFIX)))
+		.add_native_item(SystemTrayMenuItem::Separator)
+		.add_item(CustomMenuItem::new("quit".to_string(), "Quit").accelerator(format!("{}+Q", ACCELERATOR_PREFIX)))
+}
+
+pub fn build_tray() -> SystemTray {
+	SystemTray::new().with_id(TRAY_ID).with_menu(build_menu())
+}
+
+fn tooltip_text(state: TrayState) -> String {
+	if let Some(summary) = progress_summary() {
+		return summary;
+	}
+
+	match state {
+		TrayState::Error => format!("Cells Synchronizer: {} sync error(s)", error_count()),
+		TrayState::Paused => match paused_until() {
+			Some(until) => format!("Cells Synchronizer: paused until {}", until.format("%a %H:%M")),
+			None => "Cells Synchronizer: all tasks paused".to_string()
+		},
+		_ => "Cells Synchronizer".to_string()
+	}
+}
+
+/// Update the tray tooltip, at most once per `TOOLTIP_INTERVAL` unless forced.
+pub fn refresh_tooltip(force: bool) {
+	let mut last = LAST_TOOLTIP.lock().unwrap();
+	let now = Instant::now();
+	if let Some(time) = *last {
+		if !force && now.duration_since(time) < TOOLTIP_INTERVAL {
+			return;
+		}
+	}
+	*last = Some(now);
+	drop(last);
+
+	if let Some(app_handle) = app_handle() {
+		if let Some(tray) = app_handle.tray_handle_by_id(TRAY_ID) {
+			let _ = tray.set_tooltip(&tooltip_text(tray_state()));
+		}
+	}
+}
+
+/// Rebuild the menu at most once per `MENU_INTERVAL`, for events that come
+/// in bursts such as errors. A skipped rebuild happens once the interval is
+/// over, so the last state is always shown.
+pub fn refresh_tray_throttled() {
+	let mut last = LAST_MENU.lock().unwrap();
+	let wait = match last.0 {
+		Some(time) => MENU_INTERVAL.saturating_sub(time.elapsed()),
+		None => Duration::ZERO
+	};
+	if wait.is_zero() {
+		drop(last);
+		refresh_tray();
+	} else if !last.1 {
+		last.1 = true;
+		drop(last);
+		std::thread::spawn(move || {
+			std::thread::sleep(wait);
+			LAST_MENU.lock().unwrap().1 = false;
+			refresh_tray();
+		});
+	}
+}
+
+/// Rebuild the menu and icon from the current task list and engine state.
+pub fn refresh_tray() {
+	LAST_MENU.lock().unwrap().0 = Some(Instant::now());
+	if let Some(app_handle) = app_handle() {
+		if let Some(tray) = app_handle.tray_handle_by_id(TRAY_ID) {
+			let state = tray_state();
+			let icon = match state {
+				TrayState::Idle => IDLE_ICON,
+				TrayState::Syncing => SYNCING_ICON,
+				TrayState::Paused => PAUSED_ICON,
+				TrayState::Error => ERROR_ICON
+			};
+
+			if let Err(e) = tray.set_menu(build_menu()) {
+				warn!("Failed to update tray menu: {}", e);
+			}
+			let _ = tray.set_icon(Icon::Raw(icon.to_vec()));
+			// badged icons would lose their colour as a template
+			#[cfg(target_os="macos")]
+			let _ = tray.set_icon_as_template(state == TrayState::Idle);
+		}
+	}
+	refresh_tooltip(true);
+}
+
+fn open_folder(path: &str) {
+	#[cfg(target_os="macos")]
+	let opener = "open";
+	#[cfg(target_os="windows")]
+	let opener = "explorer";
+	#[cfg(not(any(target_os="macos", target_os="windows")))]
+	let opener = "xdg-open";
+
+	if let Err(e) = Command::new(opener).arg(path).spawn() {
+		warn!("Failed to open {}: {}", path, e);
+	}
+}
+
+fn toggle_task(uuid: &str) {
+	if let Some(mut task) = get_task(uuid) {
+		task.paused = !task.paused;
+		if task.paused {
+			tokio::spawn(pause(task.uuid.clone()));
+		}
+		update_task(task.clone());
+		// the window owns the task list and schedules resumed tasks
+		emit(TASK_UPDATED, task);
+		refresh_tray();
+	}
+}
+
+fn tomorrow() -> chrono::DateTime<Local> {
+	let date = Local::now().date_naive() + Days::new(1);
+	date.and_time(NaiveTime::MIN)
+		.and_local_timezone(Local)
+		.earliest()
+		.unwrap_or_else(|| Local::now() + chrono::Duration::days(1))
+}
+
+fn show_window(app_handle: &AppHandle) {
+	let window = app_handle.get_window("main").unwrap();
+
+	#[cfg(target_os = "macos")]
+	tauri::AppHandle::show(&window.app_handle()).unwrap();
+
+	#[cfg(not(target_os = "macos"))]
+	window.show().unwrap();
+
+	window.set_focus().unwrap();
+}
+
+pub fn tray_event(app_handle: &AppHandle, event: SystemTrayEvent) {
+	match event {
+		SystemTrayEvent::MenuItemClick { id, .. } => match id.as_str() {
+			"quit" => {
+				tokio::spawn(async {
+					shutdown().await;
+					std::process::exit(0);
+				});
+			}
+			"show" => show_window(app_handle),
+			"sync-all" => sync_all("tray"),
+			"pause-hour" => pause_all_until(Local::now() + chrono::Duration::hours(1)),
+			"pause-tomorrow" => pause_all_until(tomorrow()),
+			"resume-all" => resume_all(),
+			id => {
+				if let Some(uuid) = id.strip_prefix(TASK_SYNC_PREFIX) {
+					if let Some(task) = get_task(uuid) {
+						sync_now(task, "tray");
+					}
+				} else if let Some(uuid) = id.strip_prefix(TASK_TOGGLE_PREFIX) {
+					toggle_task(uuid);
+				} else if let Some(uuid) = id.strip_prefix(TASK_OPEN_PREFIX) {
+					if let Some(task) = get_task(uuid) {
+						open_folder(&task.localDir);
+					}
+				}
+			}
+		},
+		#[cfg(target_os="windows")]
+		SystemTrayEvent::LeftClick { .. } => show_window(app_handle),
+		_ => {}
+	}
+}
@@ -1,5 +1,6 @@
 import {invoke} from "@tauri-apps/api";
 import toast from "react-hot-toast";
+import {SyncDirection, Task, Workspace} from "./interfaces.ts";
 
 export function randomNum(minNum: number, maxNum: number){
     switch (arguments.length) {
@@ -12,6 +13,25 @@ export function randomNum(minNum: number, maxNum: number){
     }
 }
 
+export function formatBytes(bytes: number) {
+    const units = ["B", "KB", "MB", "GB", "TB"];
+    let unit = 0;
+    while (bytes >= 1024 && unit < units.length - 1) {
+        bytes /= 1024;
+        unit++;
+    }
+    return `${bytes.toFixed(1)} ${units[unit]}`;
+}
+
+export function formatDuration(seconds: number) {
+    if (seconds >= 3600) {
+        return `${Math.floor(seconds / 3600)}h ${Math.floor(seconds % 3600 / 60)}m`;
+    } else if (seconds >= 60) {
+        return `${Math.floor(seconds / 60)}m ${seconds % 60}s`;
+    }
+    return `${seconds}s`;
+}
+
 export async function callBackend(name: string, args: any) {
     let flag = false;
     setTimeout(() => {
@@ -37,6 +57,40 @@ export async function callBackend(name: string, args: any) {
     }
 }
 
+/** Why the workspace of `path` cannot be synced in `direction`, if it cannot. */
+export async function workspaceProblem(path: string, direction: SyncDirection): Promise<string | undefined> {
+    const slug = path.replace(/^\/+/, "").split("/")[0];
+    let res;
+    try {
+        res = await callBackend("list_workspaces", {});
+    } catch {
+        return undefined;
+    }
+    const workspace: Workspace | undefined = res?.data.find((w: Workspace) => w.slug === slug);
+    if (workspace === undefined) {
+        return undefined;
+    }
+    if (!workspace.syncable) {
+        return `Workspace ${workspace.label} does not allow syncing.`;
+    }
+    if (direction !== "Download" && !workspace.writable) {
+        return `Workspace ${workspace.label} is read-only, files can only be downloaded from it.`;
+    }
+    if (direction !== "Upload" && !workspace.readable) {
+        return `Workspace ${workspace.label} is write-only, files can only be uploaded to it.`;
+    }
+    return undefined;
+}
+
+/** `task` as it is saved, with its S3 secret key moved to the keyring. */
+export function withoutSecrets(task: Task): Task {
+    if (task.backend?.type !== "S3" || task.backend.secretKey.length === 0) {
+        return task;
+    }
+    callBackend("set_s3_secret_key", {uuid: task.uuid, value: task.backend.secretKey}).then();
+    return {...task, backend: {...task.backend, secretKey: ""}};
+}
+
 export async function getUsername() {
     return await invoke("get_username") || "";
 }
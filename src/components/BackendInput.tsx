@@ -0,0 +1,83 @@
+import {Button, FormControl, FormLabel, Input, Option, Select, Stack} from "@mui/joy";
+import {open as selectLocal} from "@tauri-apps/api/dialog";
+import {BACKENDS, PAD} from "../constants.ts";
+import {BackendConfig} from "../interfaces.ts";
+
+export default function BackendInput({
+    backend,
+    setBackend
+} : {
+    backend: BackendConfig,
+    setBackend: (backend: BackendConfig) => void
+}) {
+    function handleSelectRoot() {
+        selectLocal({
+            multiple: false,
+            directory: true
+        }).then((dir) => {
+            if (dir) {
+                setBackend({type: "Local", root: dir.toString()});
+            }
+        });
+    }
+
+    function setField(field: string, value: string) {
+        let newBackend = JSON.parse(JSON.stringify(backend));
+        newBackend[field] = value;
+        setBackend(newBackend);
+    }
+
+    return (
+        <Stack spacing={PAD}>
+            <FormControl>
+                <FormLabel>
+                    Storage
+                </FormLabel>
+                <Select
+                    value={backend.type}
+                    onChange={(_, value) => {
+                        const b = BACKENDS.find((b) => b.value.type === value);
+                        if (b && b.value.type !== backend.type) {
+                            setBackend(b.value);
+                        }
+                    }}
+                >
+                    {
+                        BACKENDS.map((b, index) => (
+                            <Option key={index} value={b.value.type}>{b.name}</Option>
+                        ))
+                    }
+                </Select>
+            </FormControl>
+            {
+                backend.type === "S3" ? (
+                    ["endpoint", "region", "bucket", "accessKey", "secretKey"].map((field) => (
+                        <Input
+                            key={field}
+                            placeholder={field === "secretKey" ? "secretKey (kept in the system keychain, empty keeps the saved one)" : field}
+                            type={field === "secretKey" ? "password" : "text"}
+                            //@ts-ignore
+                            value={backend[field]}
+                            onChange={(e) => setField(field, e.target.value)}
+                        />
+                    ))
+                ) : backend.type === "Local" ? (
+                    <FormControl>
+                        {
+                            backend.root.length > 0 ? (
+                                <FormLabel>{backend.root}</FormLabel>
+                            ) : (
+                                <></>
+                            )
+                        }
+                        <Button onClick={() => handleSelectRoot()}>
+                            Select storage folder
+                        </Button>
+                    </FormControl>
+                ) : (
+                    <></>
+                )
+            }
+        </Stack>
+    );
+}
@@ -1,5 +1,5 @@
 import {randomNum} from "./Utils.ts";
-import {Settings, TimeUnit} from "./interfaces.ts";
+import {BackendConfig, BulkNode, InvalidNamePolicy, NameNormalization, Settings, SymlinkPolicy, SyncDirection, TaskPriority, TimeUnit, EncryptionMode, CompressionConfig, BackupConfig, ArchiveConfig, ArchiveAction} from "./interfaces.ts";
 
 export const ASPECT_RATIO = 1/0.618;
 export const SMALL_PART = 38.2;
@@ -65,5 +65,92 @@ export const DEFAULT_SETTINGS: Settings = {
     globalIgnores: [],
     continueWhenUsingBattery: true,
     notificationWhenFailed: false,
-    uploadThreadNumber: 8
+    uploadThreadNumber: 8,
+    smallFilesFirst: false,
+    verifyUploads: false,
+    settleSeconds: 5,
+    snapshotSizeLimit: 0,
+    preservePosixMetadata: false,
+    restoreOwner: false,
+    nameNormalization: "Nfc",
+    invalidNames: "Skip",
+    historyRetentionDays: 30,
+    logLevel: "info"
+}
+
+export const DIRECTIONS: {name: string, value: SyncDirection}[] = [
+    {name: "Upload local changes", value: "Upload"},
+    {name: "Download remote changes", value: "Download"},
+    {name: "Both ways, keep both copies on conflict", value: "Both"},
+];
+
+export const SYMLINK_POLICIES: {name: string, value: SymlinkPolicy}[] = [
+    {name: "Skip symlinks", value: "Skip"},
+    {name: "Follow symlinks", value: "Follow"},
+    {name: "Upload symlinks as links", value: "Descriptor"},
+];
+
+export const PRIORITIES: {name: string, value: TaskPriority}[] = [
+    {name: "Low", value: "Low"},
+    {name: "Normal", value: "Normal"},
+    {name: "High", value: "High"},
+];
+
+export const ENCRYPTION_MODES: {name: string, value: EncryptionMode}[] = [
+    {name: "No encryption", value: "Off"},
+    {name: "Encrypt file contents", value: "Content"},
+    {name: "Encrypt file contents and names", value: "ContentAndNames"},
+];
+
+export const DEFAULT_COMPRESSION: CompressionConfig = {
+    enabled: false,
+    patterns: ["*.txt", "*.log", "*.csv", "*.json", "*.xml", "*.sql"],
+};
+
+export const DEFAULT_BACKUP: BackupConfig = {
+    enabled: false,
+    keepLast: 10,
+    keepDailyDays: 30,
+    keepMonthlyMonths: 12,
+};
+
+export const DEFAULT_ARCHIVE: ArchiveConfig = {
+    enabled: false,
+    action: "Delete",
+    trashDir: "",
+    minAgeHours: 0,
+    evictBelowFreeGb: 0,
+};
+
+export const ARCHIVE_ACTIONS: {name: string, value: ArchiveAction}[] = [
+    {name: "Delete the local file", value: "Delete"},
+    {name: "Move it to a trash folder", value: "Trash"},
+];
+
+export const NAME_NORMALIZATIONS: {name: string, value: NameNormalization}[] = [
+    {name: "Keep as is", value: "None"},
+    {name: "Composed (NFC)", value: "Nfc"},
+    {name: "Decomposed (NFD)", value: "Nfd"},
+];
+
+export const INVALID_NAME_POLICIES: {name: string, value: InvalidNamePolicy}[] = [
+    {name: "Skip the file", value: "Skip"},
+    {name: "Escape the name", value: "Escape"},
+];
+
+export const BACKENDS: {name: string, value: BackendConfig}[] = [
+    {name: "Cells server", value: {type: "Cells"}},
+    {name: "S3 compatible storage", value: {type: "S3", endpoint: "", region: "", bucket: "", accessKey: "", secretKey: ""}},
+    {name: "Local or network folder", value: {type: "Local", root: ""}},
+];
+
+// tasks on other storages only have a path, no Cells node
+export function pathNode(path: string): BulkNode {
+    return {
+        Uuid: "",
+        Path: path.replace(/^\/+|\/+$/g, ""),
+        Type: "COLLECTION",
+        Etag: "",
+        MetaStore: {ws_label: "", ws_syncable: true, name: ""}
+    };
 }
@@ -16,6 +16,23 @@ export const DEFAULT_USER_DATA: UserData = {
     }
 }
 
+export type SyncDirection = "Upload" | "Download" | "Both";
+
+export type SymlinkPolicy = "Skip" | "Follow" | "Descriptor";
+
+export type TaskPriority = "Low" | "Normal" | "High";
+
+export type EncryptionMode = "Off" | "Content" | "ContentAndNames";
+
+export type BackendConfig =
+    { type: "Cells" } |
+    { type: "S3", endpoint: string, region: string, bucket: string, accessKey: string, secretKey: string } |
+    { type: "Local", root: string };
+
+export type NameNormalization = "None" | "Nfc" | "Nfd";
+
+export type InvalidNamePolicy = "Skip" | "Escape";
+
 export interface Task {
     uuid: string,
     localDir: string,
@@ -23,7 +40,44 @@ export interface Task {
     remoteDir: BulkNode,
     paused: boolean,
     repeatInterval: number,
-    repeatIntervalUnit: TimeUnit
+    repeatIntervalUnit: TimeUnit,
+    direction: SyncDirection,
+    symlinkPolicy: SymlinkPolicy,
+    backend: BackendConfig,
+    priority: TaskPriority,
+    maxTransfers: number,
+    encryption: EncryptionMode,
+    compression: CompressionConfig,
+    backup: BackupConfig,
+    archive: ArchiveConfig,
+    selectedFolders: string[]
+}
+
+export type ArchiveAction = "Delete" | "Trash";
+
+export interface ArchiveConfig {
+    enabled: boolean,
+    action: ArchiveAction,
+    trashDir: string,
+    minAgeHours: number,
+    evictBelowFreeGb: number
+}
+
+export interface BackupConfig {
+    enabled: boolean,
+    keepLast: number,
+    keepDailyDays: number,
+    keepMonthlyMonths: number
+}
+
+export interface CompressionConfig {
+    enabled: boolean,
+    patterns: string[]
+}
+
+export interface HydrateResult {
+    hydrated: number,
+    failed: string[]
 }
 
 export interface BulkNode {
@@ -37,19 +91,64 @@ export interface BulkNode {
 export interface BulkMetaStore {
     ws_label: string,
     ws_syncable: boolean,
+    ws_permissions?: string,
     name: string
 }
 
+export interface Workspace {
+    uuid: string,
+    slug: string,
+    label: string,
+    permissions: string,
+    readable: boolean,
+    writable: boolean,
+    syncable: boolean
+}
+
 export interface TimeUnit {
     name: string,
     level: number,
 }
 
+export interface TaskProgress {
+    total: number,
+    current: number,
+    totalBytes: number,
+    transferredBytes: number,
+    throughput: number,
+    eta: number | null
+}
+
+export interface TaskEvent {
+    uuid: string,
+    progress: TaskProgress
+}
+
+export interface FileEvent {
+    uuid: string,
+    path: string,
+    remotePath: string,
+    bytes: number,
+    totalBytes: number,
+    error: string | null,
+    progress: TaskProgress
+}
+
 export interface Settings {
     startWithLogin: boolean,
     showTrayIcon: boolean,
     globalIgnores: string[],
     continueWhenUsingBattery: boolean,
     notificationWhenFailed: boolean,
-    uploadThreadNumber: number
+    uploadThreadNumber: number,
+    smallFilesFirst: boolean,
+    verifyUploads: boolean,
+    settleSeconds: number,
+    snapshotSizeLimit: number,
+    preservePosixMetadata: boolean,
+    restoreOwner: boolean,
+    nameNormalization: NameNormalization,
+    invalidNames: InvalidNamePolicy,
+    historyRetentionDays: number,
+    logLevel: string
 }
@@ -1,21 +1,25 @@
 import {
+    Box,
     Button,
-    ButtonGroup, DialogContent,
+    ButtonGroup, Chip, DialogContent,
     DialogTitle, Divider,
     FormControl,
     FormLabel, Input,
     Modal,
     ModalDialog, Option, Select,
-    Stack
+    Stack, Switch
 } from "@mui/joy";
 import React, {useState} from "react";
-import {HOUR_UNIT, LARGE_PART, PAD2, UNITS} from "../constants.ts";
+import {callBackend, workspaceProblem} from "../Utils.ts";
+import {BACKENDS, DIRECTIONS, SYMLINK_POLICIES, PRIORITIES, ENCRYPTION_MODES, DEFAULT_COMPRESSION, DEFAULT_BACKUP, DEFAULT_ARCHIVE, ARCHIVE_ACTIONS, HOUR_UNIT, LARGE_PART, PAD2, UNITS, pathNode} from "../constants.ts";
 import {open as selectLocal} from "@tauri-apps/api/dialog";
 import RemoteSelectModal, {getName} from "./RemoteSelectModal.tsx";
-import {BulkNode, Task} from "../interfaces.ts";
+import FolderSelectModal from "./FolderSelectModal.tsx";
+import {ArchiveConfig, BackendConfig, BackupConfig, BulkNode, CompressionConfig, EncryptionMode, SymlinkPolicy, SyncDirection, Task, TaskPriority} from "../interfaces.ts";
 import toast from "react-hot-toast";
 import {v1 as uuid1} from "uuid";
 import IgnoresInput from "../components/IgnoresInput.tsx";
+import BackendInput from "../components/BackendInput.tsx";
 
 export default function CreateTaskModal({
     open,
@@ -30,11 +34,23 @@ export default function CreateTaskModal({
     const [remoteNode, setRemoteNode] =
         useState<BulkNode | undefined>(undefined);
     const [ignores, setIgnores] = useState<string[]>([]);
+    const [direction, setDirection] = useState<SyncDirection>("Upload");
+    const [symlinkPolicy, setSymlinkPolicy] = useState<SymlinkPolicy>("Skip");
+    const [priority, setPriority] = useState<TaskPriority>("Normal");
+    const [maxTransfers, setMaxTransfers] = useState<number>(0);
+    const [encryption, setEncryption] = useState<EncryptionMode>("Off");
+    const [passphrase, setPassphrase] = useState("");
+    const [compression, setCompression] = useState<CompressionConfig>(DEFAULT_COMPRESSION);
+    const [backup, setBackup] = useState<BackupConfig>(DEFAULT_BACKUP);
+    const [archive, setArchive] = useState<ArchiveConfig>(DEFAULT_ARCHIVE);
+    const [selectedFolders, setSelectedFolders] = useState<string[]>([]);
+    const [backend, setBackend] = useState<BackendConfig>(BACKENDS[0].value);
 
     const [interval, setInterval] = useState(1);
     const [intervalUnit, setIntervalUnit] = useState(HOUR_UNIT);
 
     const [remoteModalOpen, setRemoteModalOpen] = useState(false);
+    const [folderModalOpen, setFolderModalOpen] = useState(false);
 
     function handleSelectLocal() {
         selectLocal({
@@ -47,11 +63,22 @@ export default function CreateTaskModal({
         });
     }
 
+    function handleSelectTrash() {
+        selectLocal({
+            multiple: false,
+            directory: true
+        }).then((dir) => {
+            if (dir) {
+                setArchive({...archive, trashDir: dir.toString()});
+            }
+        });
+    }
+
     function handleSelectRemote() {
         setRemoteModalOpen(true);
     }
 
-    function handleCreate() {
+    async function handleCreate() {
         if (localPath.length === 0) {
             toast.error("Local directory should not be empty.");
             return;
@@ -60,10 +87,39 @@ export default function CreateTaskModal({
             toast.error("Remote directory should not be empty.");
             return;
         }
+        if (backend.type === "S3" && (backend.endpoint.length === 0 || backend.bucket.length === 0)) {
+            toast.error("Endpoint and bucket should not be empty.");
+            return;
+        }
+        if (backend.type === "Local" && backend.root.length === 0) {
+            toast.error("Storage folder should not be empty.");
+            return;
+        }
+        if (encryption !== "Off" && passphrase.length === 0) {
+            toast.error("Encryption passphrase should not be empty.");
+            return;
+        }
+        if (direction === "Upload" && archive.enabled && archive.action === "Trash") {
+            if (archive.trashDir.length === 0) {
+                toast.error("Trash folder should not be empty.");
+                return;
+            }
+            if (archive.trashDir.startsWith(localPath)) {
+                toast.error("Trash folder should not be inside the local directory.");
+                return;
+            }
+        }
         if (interval.toString().length === 0) {
             toast.error("Repeat interval should not be empty.");
             return;
         }
+        if (backend.type === "Cells") {
+            const problem = await workspaceProblem(remoteNode.Path, direction);
+            if (problem !== undefined) {
+                toast.error(problem);
+                return;
+            }
+        }
 
         let newTask: Task = {
             uuid: uuid1().toString(),
@@ -72,9 +128,22 @@ export default function CreateTaskModal({
             ignores: ignores,
             paused: true,
             repeatInterval: interval,
-            repeatIntervalUnit: intervalUnit
+            repeatIntervalUnit: intervalUnit,
+            direction: direction,
+            symlinkPolicy: symlinkPolicy,
+            backend: backend,
+            priority: priority,
+            maxTransfers: maxTransfers,
+            encryption: encryption,
+            compression: compression,
+            backup: backup,
+            archive: archive,
+            selectedFolders: selectedFolders
         };
 
+        if (encryption !== "Off") {
+            callBackend("set_encryption_passphrase", {uuid: newTask.uuid, value: passphrase}).then();
+        }
         createTask(newTask);
         setOpen(false);
     }
@@ -109,29 +178,320 @@ export default function CreateTaskModal({
                                 </Button>
                             </ButtonGroup>
                         </FormControl>
+                        <BackendInput backend={backend} setBackend={(b) => {
+                            setBackend(b);
+                            setRemoteNode(undefined);
+                        }}/>
+                        {
+                            backend.type === "Cells" ? (
+                                <FormControl>
+                                    {
+                                        remoteNode !== undefined ? (
+                                            <FormLabel>
+                                                {getName(remoteNode)} ({remoteNode.Path})
+                                            </FormLabel>
+                                        ) : (
+                                            <></>
+                                        )
+                                    }
+                                    <ButtonGroup sx={{width: '100%'}}>
+                                        <Button sx={{width: '100%'}} onClick={() => handleSelectRemote()}>
+                                            Select remote directory
+                                        </Button>
+                                    </ButtonGroup>
+                                    <RemoteSelectModal
+                                        open={remoteModalOpen}
+                                        setOpen={setRemoteModalOpen}
+                                        onSelect={(node) => {
+                                            setRemoteNode(node);
+                                            setSelectedFolders([]);
+                                        }}
+                                    />
+                                </FormControl>
+                            ) : (
+                                <FormControl>
+                                    <FormLabel>
+                                        Remote directory
+                                    </FormLabel>
+                                    <Input
+                                        value={remoteNode?.Path || ""}
+                                        onChange={(e) => setRemoteNode(
+                                            e.target.value.length > 0 ? pathNode(e.target.value) : undefined
+                                        )}
+                                    />
+                                </FormControl>
+                            )
+                        }
                         <FormControl>
-                            {
-                                remoteNode !== undefined ? (
+                            <FormLabel>
+                                Direction
+                            </FormLabel>
+                            <Select
+                                value={direction}
+                                onChange={(_, value) => setDirection(value || direction)}
+                            >
+                                {
+                                    DIRECTIONS.map((d, index) => (
+                                        <Option key={index} value={d.value}>{d.name}</Option>
+                                    ))
+                                }
+                            </Select>
+                        </FormControl>
+                        <FormControl>
+                            <FormLabel>
+                                Symlinks
+                            </FormLabel>
+                            <Select
+                                value={symlinkPolicy}
+                                onChange={(_, value) => setSymlinkPolicy(value || symlinkPolicy)}
+                            >
+                                {
+                                    SYMLINK_POLICIES.map((p, index) => (
+                                        <Option key={index} value={p.value}>{p.name}</Option>
+                                    ))
+                                }
+                            </Select>
+                        </FormControl>
+                        <FormControl>
+                            <FormLabel>
+                                Priority
+                            </FormLabel>
+                            <Select
+                                value={priority}
+                                onChange={(_, value) => setPriority(value || priority)}
+                            >
+                                {
+                                    PRIORITIES.map((p, index) => (
+                                        <Option key={index} value={p.value}>{p.name}</Option>
+                                    ))
+                                }
+                            </Select>
+                        </FormControl>
+                        <FormControl>
+                            <FormLabel>
+                                Parallel Transfers
+                            </FormLabel>
+                            <Input
+                                value={maxTransfers}
+                                type='number'
+                                placeholder="0 uses every upload thread"
+                                onChange={(e) => setMaxTransfers(Math.max(0, parseInt(e.target.value) || 0))}
+                            />
+                        </FormControl>
+                        <FormControl>
+                            <FormLabel>
+                                Encryption
+                            </FormLabel>
+                            <Select
+                                value={encryption}
+                                onChange={(_, value) => setEncryption(value || encryption)}
+                            >
+                                {
+                                    ENCRYPTION_MODES.map((m, index) => (
+                                        <Option key={index} value={m.value}>{m.name}</Option>
+                                    ))
+                                }
+                            </Select>
+                        </FormControl>
+                        {
+                            encryption !== "Off" ? (
+                                <FormControl>
                                     <FormLabel>
-                                        {getName(remoteNode)} ({remoteNode.Path})
+                                        Passphrase (cannot be changed later)
                                     </FormLabel>
-                                ) : (
-                                    <></>
-                                )
-                            }
-                            <ButtonGroup sx={{width: '100%'}}>
-                                <Button sx={{width: '100%'}} onClick={() => handleSelectRemote()}>
-                                    Select remote directory
-                                </Button>
-                            </ButtonGroup>
-                            <RemoteSelectModal
-                                open={remoteModalOpen}
-                                setOpen={setRemoteModalOpen}
-                                onSelect={(node) => {
-                                    setRemoteNode(node);
-                                }}
+                                    <Input
+                                        type="password"
+                                        value={passphrase}
+                                        onChange={(e) => setPassphrase(e.target.value)}
+                                    />
+                                </FormControl>
+                            ) : (
+                                <></>
+                            )
+                        }
+                        <FormControl orientation="horizontal" sx={{justifyContent: 'space-between'}}>
+                            <FormLabel>
+                                Compress matching files
+                            </FormLabel>
+                            <Switch
+                                checked={compression.enabled}
+                                onChange={(e) => setCompression({...compression, enabled: e.target.checked})}
+                            />
+                        </FormControl>
+                        {
+                            compression.enabled ? (
+                                <FormControl>
+                                    <FormLabel>
+                                        Compressed File Patterns
+                                    </FormLabel>
+                                    <IgnoresInput
+                                        ignores={compression.patterns}
+                                        setIgnores={(patterns) => setCompression({...compression, patterns: patterns})}
+                                    />
+                                </FormControl>
+                            ) : (
+                                <></>
+                            )
+                        }
+                        <FormControl orientation="horizontal" sx={{justifyContent: 'space-between'}}>
+                            <FormLabel>
+                                Keep replaced files as versions
+                            </FormLabel>
+                            <Switch
+                                checked={backup.enabled}
+                                onChange={(e) => setBackup({...backup, enabled: e.target.checked})}
                             />
                         </FormControl>
+                        {
+                            backup.enabled ? (
+                                <React.Fragment>
+                                    <FormControl>
+                                        <FormLabel>
+                                            Keep Last Versions
+                                        </FormLabel>
+                                        <Input
+                                            value={backup.keepLast}
+                                            type='number'
+                                            onChange={(e) => setBackup({...backup, keepLast: Math.max(0, parseInt(e.target.value) || 0)})}
+                                        />
+                                    </FormControl>
+                                    <FormControl>
+                                        <FormLabel>
+                                            Keep Daily Versions For (days)
+                                        </FormLabel>
+                                        <Input
+                                            value={backup.keepDailyDays}
+                                            type='number'
+                                            onChange={(e) => setBackup({...backup, keepDailyDays: Math.max(0, parseInt(e.target.value) || 0)})}
+                                        />
+                                    </FormControl>
+                                    <FormControl>
+                                        <FormLabel>
+                                            Keep Monthly Versions For (months)
+                                        </FormLabel>
+                                        <Input
+                                            value={backup.keepMonthlyMonths}
+                                            type='number'
+                                            onChange={(e) => setBackup({...backup, keepMonthlyMonths: Math.max(0, parseInt(e.target.value) || 0)})}
+                                        />
+                                    </FormControl>
+                                </React.Fragment>
+                            ) : (
+                                <></>
+                            )
+                        }
+                        {
+                            direction === "Upload" ? (
+                                <FormControl orientation="horizontal" sx={{justifyContent: 'space-between'}}>
+                                    <FormLabel>
+                                        Archive uploaded files
+                                    </FormLabel>
+                                    <Switch
+                                        checked={archive.enabled}
+                                        onChange={(e) => setArchive({...archive, enabled: e.target.checked})}
+                                    />
+                                </FormControl>
+                            ) : (
+                                <></>
+                            )
+                        }
+                        {
+                            direction === "Upload" && archive.enabled ? (
+                                <React.Fragment>
+                                    <FormControl>
+                                        <FormLabel>
+                                            Once Uploaded
+                                        </FormLabel>
+                                        <Select
+                                            value={archive.action}
+                                            onChange={(_, value) => setArchive({...archive, action: value || archive.action})}
+                                        >
+                                            {
+                                                ARCHIVE_ACTIONS.map((a, index) => (
+                                                    <Option key={index} value={a.value}>{a.name}</Option>
+                                                ))
+                                            }
+                                        </Select>
+                                    </FormControl>
+                                    {
+                                        archive.action === "Trash" ? (
+                                            <FormControl>
+                                                {
+                                                    archive.trashDir.length > 0 ? (
+                                                        <FormLabel>{archive.trashDir}</FormLabel>
+                                                    ) : (
+                                                        <></>
+                                                    )
+                                                }
+                                                <ButtonGroup sx={{width: '100%'}}>
+                                                    <Button sx={{width: '100%'}} onClick={() => handleSelectTrash()}>
+                                                        Select trash folder
+                                                    </Button>
+                                                </ButtonGroup>
+                                            </FormControl>
+                                        ) : (
+                                            <></>
+                                        )
+                                    }
+                                    <FormControl>
+                                        <FormLabel>
+                                            Minimum Age (hours)
+                                        </FormLabel>
+                                        <Input
+                                            value={archive.minAgeHours}
+                                            type='number'
+                                            placeholder="0 archives files right away"
+                                            onChange={(e) => setArchive({...archive, minAgeHours: Math.max(0, parseInt(e.target.value) || 0)})}
+                                        />
+                                    </FormControl>
+                                    <FormControl>
+                                        <FormLabel>
+                                            Only Below Free Space (GB)
+                                        </FormLabel>
+                                        <Input
+                                            value={archive.evictBelowFreeGb}
+                                            type='number'
+                                            placeholder="0 always archives files"
+                                            onChange={(e) => setArchive({...archive, evictBelowFreeGb: Math.max(0, parseInt(e.target.value) || 0)})}
+                                        />
+                                    </FormControl>
+                                </React.Fragment>
+                            ) : (
+                                <></>
+                            )
+                        }
+                        {
+                            direction !== "Upload" && backend.type === "Cells" && remoteNode !== undefined ? (
+                                <FormControl>
+                                    <FormLabel>
+                                        Downloaded Folders
+                                    </FormLabel>
+                                    <Box sx={{display: 'flex', flexFlow: 'row wrap', alignItems: 'center', gap: PAD2}}>
+                                        {
+                                            selectedFolders.length === 0 ? (
+                                                <Chip>Everything</Chip>
+                                            ) : (
+                                                selectedFolders.map((folder, index) => (
+                                                    <Chip key={index}>{folder}</Chip>
+                                                ))
+                                            )
+                                        }
+                                        <Button variant="soft" color="neutral" size="sm" onClick={() => setFolderModalOpen(true)}>
+                                            Choose folders
+                                        </Button>
+                                    </Box>
+                                    <FolderSelectModal
+                                        open={folderModalOpen}
+                                        setOpen={setFolderModalOpen}
+                                        root={remoteNode}
+                                        selected={selectedFolders}
+                                        setSelected={setSelectedFolders}
+                                    />
+                                </FormControl>
+                            ) : (
+                                <></>
+                            )
+                        }
                         <Divider/>
                         <FormControl>
                             <FormLabel>
@@ -0,0 +1,166 @@
+import {
+    Box, Button, ButtonGroup, Checkbox, CircularProgress,
+    DialogContent, DialogTitle,
+    List, ListItem, ListItemButton,
+    Modal,
+    ModalDialog,
+    Typography
+} from "@mui/joy";
+import {useEffect, useState} from "react";
+import {BulkNode} from "../interfaces.ts";
+import {callBackend} from "../Utils.ts";
+import {PAD, SMALL_PART} from "../constants.ts";
+import toast from "react-hot-toast";
+import {getName} from "./RemoteSelectModal.tsx";
+
+/** Path of `node` below `root`, as kept in `selectedFolders`. */
+function relativePath(root: BulkNode, node: BulkNode): string {
+    const rootPath = root.Path.replace(/^\/+|\/+$/g, "");
+    const path = node.Path.replace(/^\/+|\/+$/g, "");
+    return path.startsWith(rootPath + "/") ? path.substring(rootPath.length + 1) : path;
+}
+
+export default function FolderSelectModal({
+    open,
+    setOpen,
+    root,
+    selected,
+    setSelected
+} : {
+    open: boolean,
+    setOpen: (open: boolean) => void,
+    root: BulkNode,
+    selected: string[],
+    setSelected: (selected: string[]) => void
+}) {
+    const [parentNodes, setParentNodes] = useState<BulkNode[]>([]);
+    const [nodes, setNodes] = useState<BulkNode[]>([]);
+    const [loading, setLoading] = useState(false);
+    const [localSelected, setLocalSelected] = useState<string[]>(selected);
+
+    function load(node: BulkNode, parents: BulkNode[]) {
+        setLoading(true);
+        callBackend("list", {
+            p: node.Path
+        }).then((res) => {
+            setParentNodes(parents);
+            setNodes(res.data.Nodes);
+            setLoading(false);
+        }).catch(() => {
+            toast.error("Failed to load list.");
+            setLoading(false);
+        });
+    }
+
+    useEffect(() => {
+        if (open) {
+            setLocalSelected(selected);
+            load(root, []);
+        }
+    }, [open]);
+
+    function handleToggle(path: string, checked: boolean) {
+        if (checked) {
+            setLocalSelected([...localSelected, path]);
+        } else {
+            setLocalSelected(localSelected.filter((p) => p !== path));
+        }
+    }
+
+    function handleBackParent() {
+        const parents = [...parentNodes];
+        parents.pop();
+        const parent = parents.pop();
+        if (parent) {
+            load(parent, [...parents, parent]);
+        } else {
+            load(root, []);
+        }
+    }
+
+    function handleConfirm() {
+        setSelected(localSelected);
+        setOpen(false);
+    }
+
+    return (
+        <Modal
+            open={open}
+            onClose={() => setOpen(false)}
+        >
+            <ModalDialog sx={{width: `${SMALL_PART}%`}}>
+                <DialogContent sx={{gap: 2}}>
+                    <DialogTitle>
+                        <Box sx={{
+                            width: '100%', pl: PAD, pr: PAD,
+                            display: "flex", flexDirection: 'row', alignItems: "center", justifyContent: "space-between"
+                        }}>
+                            {
+                                loading ? "Loading" :
+                                parentNodes.length === 0
+                                ? getName(root) : getName(parentNodes[parentNodes.length-1])
+                            }
+                            <Button
+                                variant="outlined"
+                                size="sm"
+                                color="neutral"
+                                disabled={loading || parentNodes.length === 0}
+                                onClick={() => handleBackParent()}
+                            >
+                                Back
+                            </Button>
+                        </Box>
+                    </DialogTitle>
+                    <Box sx={{
+                        width: '100%',
+                        display: 'flex', flexDirection: 'row', alignItems: "center", justifyContent: "center"
+                    }}>
+                        {
+                            loading ? <CircularProgress size="sm"/> : <></>
+                        }
+                    </Box>
+                    <List>
+                        {nodes.filter((node) => node.Type === "COLLECTION").map((node) => {
+                            const path = relativePath(root, node);
+                            return (
+                                <ListItem
+                                    key={node.Uuid}
+                                    startAction={
+                                        <Checkbox
+                                            checked={localSelected.indexOf(path) !== -1}
+                                            disabled={loading}
+                                            onChange={(e) => handleToggle(path, e.target.checked)}
+                                        />
+                                    }
+                                >
+                                    <ListItemButton
+                                        disabled={loading}
+                                        onDoubleClick={() => load(node, [...parentNodes, node])}
+                                    >
+                                        {getName(node)}
+                                    </ListItemButton>
+                                </ListItem>
+                            );
+                        })}
+                    </List>
+                    <ButtonGroup
+                        variant="soft"
+                        buttonFlex={`0 1 100%`}
+                        sx={{ width: '100%', justifyContent: 'center' }}
+                    >
+                        <Button onClick={() => setOpen(false)}>
+                            Cancel
+                        </Button>
+                        <Button color="primary" onClick={() => handleConfirm()}>
+                            Confirm
+                        </Button>
+                    </ButtonGroup>
+                    <Typography level="body-sm" sx={{width: '100%', textAlign: 'center'}}>
+                        Tick the folders to download. Double click to see children.
+                        Nothing ticked downloads everything.
+                    </Typography>
+                </DialogContent>
+            </ModalDialog>
+        </Modal>
+    );
+}
@@ -13,9 +13,12 @@ import {
 } from "@mui/joy";
 import SettingsIcon from "@mui/icons-material/Settings";
 import {useState} from "react";
-import {DEFAULT_SETTINGS, PAD, PAD2} from "../constants.ts";
+import {DEFAULT_SETTINGS, INVALID_NAME_POLICIES, NAME_NORMALIZATIONS, PAD, PAD2} from "../constants.ts";
 import {Settings} from "../interfaces.ts";
 import IgnoresInput from "../components/IgnoresInput.tsx";
+import {save as selectSavePath} from "@tauri-apps/api/dialog";
+import {callBackend} from "../Utils.ts";
+import toast from "react-hot-toast";
 
 const generalControls = [
     {
@@ -38,6 +41,26 @@ const generalControls = [
         property: 'notificationWhenFailed',
         restart: false
     },
+    {
+        label: 'Transfer small files first',
+        property: 'smallFilesFirst',
+        restart: false
+    },
+    {
+        label: 'Verify files after upload',
+        property: 'verifyUploads',
+        restart: false
+    },
+    {
+        label: 'Keep file mode and owner',
+        property: 'preservePosixMetadata',
+        restart: false
+    },
+    {
+        label: 'Restore file owner (needs root)',
+        property: 'restoreOwner',
+        restart: false
+    },
 ];
 
 export default function SettingDrawerWithIconButton({
@@ -60,6 +83,21 @@ export default function SettingDrawerWithIconButton({
         setOpen(false);
     }
 
+    function handleExportDiagnostics() {
+        selectSavePath({
+            defaultPath: "diagnostics.zip",
+            filters: [{name: "Zip", extensions: ["zip"]}]
+        }).then((path) => {
+            if (path) {
+                callBackend("export_diagnostics", {path: path}).then((res) => {
+                    if (res) {
+                        toast.success("Diagnostics exported.");
+                    }
+                });
+            }
+        });
+    }
+
     function setGlobalIgnores(ignores: string[]) {
         let newSettings = JSON.parse(JSON.stringify(localSettings));
         newSettings.globalIgnores = ignores;
@@ -157,6 +195,92 @@ export default function SettingDrawerWithIconButton({
                                     }}
                                 />
                             </FormControl>
+                            <FormControl orientation="horizontal">
+                                <Box sx={{flex: 1, textAlign: 'center'}}>
+                                    <FormLabel>
+                                        Settle Time
+                                    </FormLabel>
+                                    <FormHelperText>
+                                        Seconds a file must stay unchanged before it is uploaded
+                                    </FormHelperText>
+                                </Box>
+                                <Autocomplete
+                                    value={localSettings.settleSeconds}
+                                    options={[0, 2, 5, 10, 30, 60]}
+                                    getOptionLabel={(v) => v.toString()}
+                                    onChange={(_e, v) => {
+                                        if (v !== null) {
+                                            let newSettings: Settings = JSON.parse(JSON.stringify(localSettings));
+                                            newSettings.settleSeconds = v;
+                                            setLocalSettings(newSettings);
+                                        }
+                                    }}
+                                />
+                            </FormControl>
+                            <FormControl orientation="horizontal">
+                                <Box sx={{flex: 1, textAlign: 'center'}}>
+                                    <FormLabel>
+                                        Copy Before Upload (MB)
+                                    </FormLabel>
+                                    <FormHelperText>
+                                        Smaller files are copied first so writes can not corrupt the upload, 0 disables it
+                                    </FormHelperText>
+                                </Box>
+                                <Autocomplete
+                                    value={localSettings.snapshotSizeLimit}
+                                    options={[0, 1, 10, 50, 100, 500]}
+                                    getOptionLabel={(v) => v.toString()}
+                                    onChange={(_e, v) => {
+                                        if (v !== null) {
+                                            let newSettings: Settings = JSON.parse(JSON.stringify(localSettings));
+                                            newSettings.snapshotSizeLimit = v;
+                                            setLocalSettings(newSettings);
+                                        }
+                                    }}
+                                />
+                            </FormControl>
+                            <FormControl orientation="horizontal">
+                                <Box sx={{flex: 1, textAlign: 'center'}}>
+                                    <FormLabel>
+                                        Unicode Names
+                                    </FormLabel>
+                                    <FormHelperText>
+                                        Form remote names are written in, so the same name is not uploaded twice
+                                    </FormHelperText>
+                                </Box>
+                                <Autocomplete
+                                    value={NAME_NORMALIZATIONS.find((n) => n.value === localSettings.nameNormalization) ?? NAME_NORMALIZATIONS[1]}
+                                    options={NAME_NORMALIZATIONS}
+                                    getOptionLabel={(n) => n.name}
+                                    disableClearable
+                                    onChange={(_e, v) => {
+                                        let newSettings: Settings = JSON.parse(JSON.stringify(localSettings));
+                                        newSettings.nameNormalization = v.value;
+                                        setLocalSettings(newSettings);
+                                    }}
+                                />
+                            </FormControl>
+                            <FormControl orientation="horizontal">
+                                <Box sx={{flex: 1, textAlign: 'center'}}>
+                                    <FormLabel>
+                                        Invalid Names
+                                    </FormLabel>
+                                    <FormHelperText>
+                                        Files whose name the server does not accept
+                                    </FormHelperText>
+                                </Box>
+                                <Autocomplete
+                                    value={INVALID_NAME_POLICIES.find((p) => p.value === localSettings.invalidNames) ?? INVALID_NAME_POLICIES[0]}
+                                    options={INVALID_NAME_POLICIES}
+                                    getOptionLabel={(p) => p.name}
+                                    disableClearable
+                                    onChange={(_e, v) => {
+                                        let newSettings: Settings = JSON.parse(JSON.stringify(localSettings));
+                                        newSettings.invalidNames = v.value;
+                                        setLocalSettings(newSettings);
+                                    }}
+                                />
+                            </FormControl>
                         </Box>
 
                         <Divider/>
@@ -167,6 +291,38 @@ export default function SettingDrawerWithIconButton({
                             </Typography>
                             <IgnoresInput ignores={localSettings.globalIgnores} setIgnores={setGlobalIgnores}/>
                         </Box>
+
+                        <Divider/>
+
+                        <Box sx={{display: 'flex', flexDirection: 'column', gap: PAD }}>
+                            <Typography level="title-md" sx={{ fontWeight: 'bold' }}>
+                                Diagnostics
+                            </Typography>
+                            <FormControl orientation="horizontal">
+                                <Box sx={{flex: 1, textAlign: 'center'}}>
+                                    <FormLabel>
+                                        Log Level
+                                    </FormLabel>
+                                    <FormHelperText>
+                                        Credentials are never written to the log files
+                                    </FormHelperText>
+                                </Box>
+                                <Autocomplete
+                                    value={localSettings.logLevel}
+                                    options={["error", "warn", "info", "debug", "trace"]}
+                                    onChange={(_e, v) => {
+                                        if (v) {
+                                            let newSettings: Settings = JSON.parse(JSON.stringify(localSettings));
+                                            newSettings.logLevel = v;
+                                            setLocalSettings(newSettings);
+                                        }
+                                    }}
+                                />
+                            </FormControl>
+                            <Button variant="outlined" onClick={() => handleExportDiagnostics()}>
+                                Export Diagnostics Bundle
+                            </Button>
+                        </Box>
                     </DialogContent>
 
                     <Divider sx={{ mt: 'auto' }} />
@@ -9,7 +9,7 @@ import {
     Typography
 } from "@mui/joy";
 import {LinearProgress} from "@mui/material";
-import {Settings, Task} from "../interfaces.ts";
+import {FileEvent, HydrateResult, Settings, Task, TaskEvent, TaskProgress} from "../interfaces.ts";
 import {useEffect, useState} from "react";
 import {
     getValueFromStorage,
@@ -25,11 +25,15 @@ import {getName} from "../modals/RemoteSelectModal.tsx";
 import DeleteOutlineIcon from '@mui/icons-material/DeleteOutline';
 import PauseIcon from '@mui/icons-material/Pause';
 import PlayArrowIcon from '@mui/icons-material/PlayArrow';
+import CloudDownloadIcon from '@mui/icons-material/CloudDownload';
 import EditTaskModalWithButton from "../modals/EditTaskModal.tsx";
-import {callBackend, handleLogout} from "../Utils.ts";
+import {callBackend, formatBytes, formatDuration, handleLogout, withoutSecrets} from "../Utils.ts";
 import React from "react";
 import SettingDrawerWithIconButton from "../modals/SettingDrawer.tsx";
 import "./TaskPage.css";
+import {emit, listen} from "@tauri-apps/api/event";
+import {open as selectLocal} from "@tauri-apps/api/dialog";
+import toast from "react-hot-toast";
 
 export default function TaskPage({
     settings,
@@ -45,18 +49,60 @@ export default function TaskPage({
     const [taskModalOpen, setTaskModalOpen] = useState(false);
 
     const [progresses, setProgresses] = useState<Map<string, number>>(new Map());
+    const [rates, setRates] = useState<Map<string, string>>(new Map());
 
     useEffect(() => {
+        // tasks saved before secret keys went to the keyring are saved again without them
+        setTasks(tasks);
         tasks.map((t) => {
             if (!t.paused) {
-                sync(t);
+                sync(t, "startup");
             }
         });
     }, []);
 
+    useEffect(() => {
+        const unlisteners = [
+            listen<TaskEvent>("task-started", (e) => {
+                setProgress(e.payload.uuid, e.payload.progress);
+            }),
+            listen<FileEvent>("file-progress", (e) => {
+                setProgress(e.payload.uuid, e.payload.progress);
+            }),
+            listen<FileEvent>("file-done", (e) => {
+                setProgress(e.payload.uuid, e.payload.progress);
+            }),
+            listen<FileEvent>("file-failed", (e) => {
+                console.log(`Failed ${e.payload.path}: ${e.payload.error}`);
+            }),
+            listen<Task>("task-updated", (e) => {
+                let newTask = e.payload;
+                let ts: Task[] = JSON.parse(getValueFromStorage(TASKS_STORAGE_KEY, "[]"));
+                let newTasks = ts.map((t) => t.uuid === newTask.uuid ? newTask : t);
+                setTasks(newTasks);
+                if (!newTask.paused) {
+                    sync(newTask, "tray");
+                }
+            }),
+            listen<TaskEvent>("task-finished", (e) => {
+                setProgresses((old) => {
+                    let newProgresses = new Map(old);
+                    newProgresses.delete(e.payload.uuid);
+                    return newProgresses;
+                });
+            }),
+        ];
+
+        return () => {
+            unlisteners.map((u) => u.then((f) => f()));
+        };
+    }, []);
+
     function setTasks(newTasks: Task[]) {
+        newTasks = newTasks.map(withoutSecrets);
         localStorage.setItem(TASKS_STORAGE_KEY, JSON.stringify(newTasks));
         _setTasks(newTasks);
+        emit("update-tasks", newTasks).then().catch();
     }
 
     function handleNewTask() {
@@ -73,16 +119,16 @@ export default function TaskPage({
             paused: false,
         };
         saveTask(newTask);
-        sync(newTask);
+        sync(newTask, "manual");
     }
 
-    function sync(task: Task) {
+    function sync(task: Task, trigger: string) {
         console.log("sync", task.uuid);
         callBackend("sync", {
             task: task,
             ignores: settings.globalIgnores,
+            trigger: trigger,
         }).then(() => {
-            getProgress(task.uuid);
             let ts: Task[] = JSON.parse(getValueFromStorage(TASKS_STORAGE_KEY, JSON.stringify(tasks)));
             let t = ts.find(ts => ts.uuid === task.uuid);
             if (t && !t.paused) {
@@ -91,7 +137,7 @@ export default function TaskPage({
                     let t = ts.find(ts => ts.uuid === task.uuid);
                     if (t && !t.paused) {
                         console.log(`Repeat ${t.uuid}`);
-                        sync(t);
+                        sync(t, "schedule");
                     }
                 }, t.repeatInterval * t.repeatIntervalUnit.level * 1000);
             }
@@ -112,6 +158,28 @@ export default function TaskPage({
         });
     }
 
+    function handleHydrate(task: Task) {
+        selectLocal({
+            multiple: true,
+            defaultPath: task.localDir,
+            filters: [{name: "Placeholders", extensions: ["cellsplaceholder"]}]
+        }).then((paths) => {
+            if (!paths) return;
+            callBackend("hydrate", {
+                uuid: task.uuid,
+                paths: Array.isArray(paths) ? paths : [paths],
+            }).then((res) => {
+                let result: HydrateResult = res.data;
+                if (result.failed.length > 0) {
+                    toast.error(`Failed to download ${result.failed.length} file(s).`);
+                }
+                if (result.hydrated > 0) {
+                    toast.success(`Downloaded ${result.hydrated} file(s).`);
+                }
+            }).catch();
+        });
+    }
+
     function saveTask(newTask: Task) {
         let index = -1;
         for (let i = 0; i < tasks.length; i++) {
@@ -138,33 +206,30 @@ export default function TaskPage({
         setTasks(newTasks);
     }
 
-    function getProgress(uuid: string, task?: Task) {
-        let ts: Task[] = JSON.parse(getValueFromStorage(TASKS_STORAGE_KEY, JSON.stringify(tasks)));
-        task = task || ts.find(t => t.uuid === uuid);
-        if (task && !task.paused) {
-            callBackend('progress', {
-                uuid: task.uuid
-            }).then((res) => {
-                let percent = 0;
-                if (res) {
-                    percent = res.data.current / res.data.total * 100;
-                    percent = parseFloat(percent.toFixed(2));
-                }
-
-                let newProgresses = new Map(progresses);
-                newProgresses.set(task.uuid, percent);
-                setProgresses(newProgresses);
+    function setProgress(uuid: string, progress: TaskProgress) {
+        let percent = 0;
+        if (progress.totalBytes > 0) {
+            percent = progress.transferredBytes / progress.totalBytes * 100;
+        } else if (progress.total > 0) {
+            percent = progress.current / progress.total * 100;
+        }
+        percent = parseFloat(percent.toFixed(2));
 
-                setTimeout(() => {
-                    getProgress(uuid);
-                }, 500);
-            }).catch((err) => {
-                console.log(err);
-                setTimeout(() => {
-                    getProgress(uuid);
-                }, 500);
-            });
+        let rate = `${progress.current}/${progress.total} · ${formatBytes(progress.throughput)}/s`;
+        if (progress.eta !== null) {
+            rate += ` · ${formatDuration(progress.eta)} left`;
         }
+
+        setProgresses((old) => {
+            let newProgresses = new Map(old);
+            newProgresses.set(uuid, percent);
+            return newProgresses;
+        });
+        setRates((old) => {
+            let newRates = new Map(old);
+            newRates.set(uuid, rate);
+            return newRates;
+        });
     }
 
     return (
@@ -289,6 +354,15 @@ export default function TaskPage({
                                                 task={task}
                                                 saveTask={saveTask}
                                             />
+                                            {
+                                                task.direction !== undefined && task.direction !== "Upload" ? (
+                                                    <IconButton onClick={() => handleHydrate(task)}>
+                                                        <CloudDownloadIcon/>
+                                                    </IconButton>
+                                                ) : (
+                                                    <></>
+                                                )
+                                            }
                                             <IconButton onClick={() => handleDelete(task)}>
                                                 <DeleteOutlineIcon/>
                                             </IconButton>
@@ -314,6 +388,9 @@ export default function TaskPage({
                                                 <Typography level="body-sm">
                                                     {progresses.get(task.uuid) || 0}%
                                                 </Typography>
+                                                <Typography level="body-sm">
+                                                    {rates.get(task.uuid) || ""}
+                                                </Typography>
                                             </Box>
                                         </td>
                                     </tr>